@@ -0,0 +1,42 @@
+// Donut - DNS over HTTPS server
+//
+// Copyright 2019 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Example of serving Donut's DNS-over-HTTPS filters alongside other routes in a Warp server.
+
+use donut::HandlerContext;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use warp::Filter;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let upstream = ([127, 0, 0, 1], 53).into();
+    let context = Arc::new(HandlerContext::from_upstream(upstream, Duration::from_millis(1000)).await?);
+
+    let hello = warp::path("hello").map(|| "Hello, world!");
+    let routes = donut::json_get(context.clone(), false)
+        .or(donut::text_get(context.clone()))
+        .or(donut::wire_get(context.clone()))
+        .or(donut::wire_post(context))
+        .or(donut::fallback())
+        .or(hello);
+
+    warp::serve(routes).run(([127, 0, 0, 1], 3000)).await;
+    Ok(())
+}
@@ -18,71 +18,40 @@
 
 use clap::Parser;
 use std::env;
-use std::fmt::Write;
 use std::io::{self, Read};
+use std::str;
 use trust_dns_client::op::Message;
-use trust_dns_client::rr::Record;
 
 /// Donut DNS binary to text util
 ///
 /// Convert binary DNS responses on STDIN to a dig-like text format
 #[derive(Debug, Parser)]
 #[clap(name = "donut", version = clap::crate_version!())]
-struct Bin2DnsApplication;
-
-fn format_question(buf: &mut String, mes: &Message) {
-    let _ = writeln!(buf, ";; QUESTION SECTION:");
-    for q in mes.queries() {
-        let _ = writeln!(
-            buf,
-            "; {}\t\t\t{}\t{}",
-            q.name().to_utf8(),
-            q.query_class(),
-            q.query_type()
-        );
-    }
-}
-
-fn format_authority(buf: &mut String, mes: &Message) {
-    let _ = writeln!(buf, ";; AUTHORITY SECTION:");
-    format_records(buf, mes.name_servers());
-}
-
-fn format_answer(buf: &mut String, mes: &Message) {
-    let _ = writeln!(buf, ";; ANSWER SECTION:");
-    format_records(buf, mes.answers());
-}
-
-fn format_records(buf: &mut String, records: &[Record]) {
-    for r in records {
-        let _ = writeln!(
-            buf,
-            "{}\t\t{}\t{}\t{}\t{}",
-            r.name().to_utf8(),
-            r.ttl(),
-            r.dns_class(),
-            r.record_type(),
-            donut::response::record_to_data(r),
-        );
-    }
+struct Bin2DnsApplication {
+    /// Input is hex text (instead of raw binary)
+    #[clap(long = "hex", short = 'x')]
+    hex: bool,
 }
 
-fn format_message(mes: &Message) -> String {
-    let mut buf = String::new();
-    format_question(&mut buf, mes);
-    let _ = writeln!(buf);
-
-    if mes.answer_count() > 0 {
-        format_answer(&mut buf, mes);
-    } else {
-        format_authority(&mut buf, mes)
+fn decode_hex(input: &[u8]) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = input.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits: {}", digits.len()));
     }
 
-    buf
+    digits
+        .chunks(2)
+        .map(|pair| {
+            str::from_utf8(pair)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or_else(|| format!("invalid hex digits: {}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let _opts = Bin2DnsApplication::parse();
+    let opts = Bin2DnsApplication::parse();
 
     let mut buf = Vec::new();
     let mut stdin = io::stdin();
@@ -92,9 +61,19 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         return Ok(());
     }
 
+    if opts.hex {
+        buf = match decode_hex(&buf) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("decoding error: {}", e);
+                return Ok(());
+            }
+        };
+    }
+
     match Message::from_vec(&buf) {
         Ok(v) => {
-            println!("{}", format_message(&v));
+            println!("{}", donut::response::format_message(&v));
         }
         Err(e) => {
             eprintln!("decoding error: {}", e);
@@ -103,3 +82,48 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::decode_hex;
+    use std::str::FromStr;
+    use trust_dns_client::op::{Message, Query};
+    use trust_dns_client::rr::{Name, RecordType};
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(Ok(vec![]), decode_hex(b""));
+        assert_eq!(Ok(vec![0x00, 0x01, 0x0A, 0xFF]), decode_hex(b"00010aff"));
+        assert_eq!(Ok(vec![0x00, 0x01, 0x0A, 0xFF]), decode_hex(b"00 01\n0A FF\n"));
+    }
+
+    #[test]
+    fn test_decode_hex_odd_length() {
+        assert!(decode_hex(b"0001a").is_err());
+        assert!(decode_hex(b"0 001 a").is_err());
+    }
+
+    #[test]
+    fn test_decode_hex_invalid_digits() {
+        assert!(decode_hex(b"0g").is_err());
+        assert!(decode_hex(b"+1").is_err());
+        assert!(decode_hex("é".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_decode_hex_round_trip() {
+        let mut message = Message::new();
+        message.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::AAAA,
+        ));
+        let bytes = message.to_vec().unwrap();
+
+        // Same format as the output of `dns2bin --hex`
+        let hex: String = bytes.iter().map(|v| format!("{:02x}", v)).collect();
+        let decoded = decode_hex(hex.as_bytes()).unwrap();
+
+        assert_eq!(bytes, decoded);
+        assert_eq!(message.queries(), Message::from_vec(&decoded).unwrap().queries());
+    }
+}
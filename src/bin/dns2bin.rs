@@ -33,6 +33,10 @@ struct Dns2BinApplication {
     #[clap(long = "raw", short = 'r')]
     raw: bool,
 
+    /// Output hex text (instead of base64 text)
+    #[clap(long = "hex", short = 'x', conflicts_with = "raw")]
+    hex: bool,
+
     /// Record type to lookup
     #[clap(long = "type", short = 't', default_value_t = RecordType::A)]
     type_: RecordType,
@@ -41,6 +45,10 @@ struct Dns2BinApplication {
     name: Name,
 }
 
+fn encode_hex(input: &[u8]) -> String {
+    input.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let opts = Dns2BinApplication::parse();
 
@@ -48,10 +56,12 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .add_query(Query::query(opts.name.clone(), opts.type_))
         .to_bytes()
         .map(|b| {
-            if !opts.raw {
-                base64::encode_config(&b, base64::URL_SAFE_NO_PAD).into_bytes()
-            } else {
+            if opts.raw {
                 b
+            } else if opts.hex {
+                encode_hex(&b).into_bytes()
+            } else {
+                base64::encode_config(&b, base64::URL_SAFE_NO_PAD).into_bytes()
             }
         })?;
 
@@ -60,3 +70,14 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::encode_hex;
+
+    #[test]
+    fn test_encode_hex() {
+        assert_eq!("", encode_hex(&[]));
+        assert_eq!("00010aff", encode_hex(&[0x00, 0x01, 0x0A, 0xFF]));
+    }
+}
@@ -17,26 +17,37 @@
 //
 
 use clap::Parser;
-use donut::http::HandlerContext;
-use donut::request::{RequestParserJsonGet, RequestParserWireGet, RequestParserWirePost};
-use donut::resolve::UdpResolver;
-use donut::response::{ResponseEncoderJson, ResponseEncoderWire};
+use donut::cache::ResponseCache;
+use donut::http::{Chaos, DenyAction, HandlerContext, ResponseHeader, ServerMetadata};
+use donut::listen::{ReloadableCert, TlsCipherSuites, TlsMinVersion};
+use donut::metrics::{LatencyBuckets, Metrics};
+use donut::request::{
+    IpLiteralPolicy, RequestParserJsonGet, RequestParserWireGet, RequestParserWirePost, RequestValidator,
+};
+use donut::resolve::{
+    AaaaMap, AddressPrefix, Delegations, Maintenance, MaintenanceResponse, MultiQuestionPolicy, NatMapping, Resolver,
+    ResolverOptions, SelfPtr, ServFailPolicy, SourceAddrs, StaticRecord, StaticResolver, TlsUpstream, TypeTimeout,
+    UpstreamResolver, UpstreamStrategy,
+};
+use donut::response::{ResponseEncoderJson, ResponseEncoderText, ResponseEncoderWire};
 use donut::types::DonutResult;
 use std::error::Error;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::process;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::UdpSocket;
+use tokio::net::TcpListener;
 use tokio::signal::unix::{self, SignalKind};
 use tracing::Level;
-use trust_dns_client::client::AsyncClient;
-use trust_dns_client::udp::UdpClientStream;
+use trust_dns_client::rr::Name;
+use warp::http::header::HeaderName;
 use warp::Filter;
 
-const DEFAULT_UPSTREAM_UDP: ([u8; 4], u16) = ([127, 0, 0, 1], 53);
+const DEFAULT_UPSTREAM_UDP: &str = "127.0.0.1:53";
 const DEFAULT_UPSTREAM_TIMEOUT_MS: u64 = 1000;
+const MAX_UPSTREAM_TIMEOUT_MS: u64 = 60_000;
 const DEFAULT_LOG_LEVEL: Level = Level::INFO;
 const DEFAULT_BIND_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3000);
 
@@ -46,14 +57,309 @@ const DEFAULT_BIND_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3000);
 #[derive(Debug, Parser)]
 #[clap(name = "donut", version = clap::crate_version!())]
 struct DonutApplication {
-    /// Send DNS queries to this upstream DNS server (via DNS over UDP)
-    #[clap(long, default_value_t = DEFAULT_UPSTREAM_UDP.into())]
-    upstream_udp: SocketAddr,
+    /// Send DNS queries to this upstream DNS server (via DNS over UDP). May be specified
+    /// multiple times, see --upstream-strategy for how servers are picked for each query.
+    #[clap(long, default_value = DEFAULT_UPSTREAM_UDP)]
+    upstream_udp: Vec<SocketAddr>,
 
-    /// Timeout for upstream DNS server in milliseconds.
-    #[clap(long, default_value_t = DEFAULT_UPSTREAM_TIMEOUT_MS)]
+    /// Send DNS queries to this upstream DNS server (via DNS over TLS) instead of using UDP.
+    /// The certificate of the server must be valid for the name given by --upstream-tls-name.
+    #[clap(long, requires = "upstream-tls-name", conflicts_with = "upstream-static")]
+    upstream_tls: Option<SocketAddr>,
+
+    /// Name to verify the certificate of the upstream DNS over TLS server against, also sent
+    /// to the server via SNI.
+    #[clap(long, requires = "upstream-tls")]
+    upstream_tls_name: Option<String>,
+
+    /// Verify the certificate of the upstream DNS over TLS server using only the CA certificates
+    /// in this file (PEM format) instead of the root certificates of the system.
+    #[clap(long, requires = "upstream-tls")]
+    upstream_tls_ca: Option<std::path::PathBuf>,
+
+    /// How to pick which upstream DNS server to send each query to when there are several:
+    /// 'failover' uses the one that most recently succeeded, trying the others in order when it
+    /// fails. 'round-robin' uses each healthy one in turn.
+    #[clap(long, default_value_t = UpstreamStrategy::Failover)]
+    upstream_strategy: UpstreamStrategy,
+
+    /// Send queries to IPv4 upstream DNS servers from this local address instead of letting the
+    /// operating system pick one.
+    #[clap(long)]
+    upstream_bind4: Option<Ipv4Addr>,
+
+    /// Send queries to IPv6 upstream DNS servers from this local address instead of letting the
+    /// operating system pick one.
+    #[clap(long)]
+    upstream_bind6: Option<Ipv6Addr>,
+
+    /// Start even if the upstream DNS servers can't be connected to, retrying in the background.
+    /// Queries sent to a server that hasn't been connected to yet fail with a 503 response.
+    #[clap(long)]
+    lazy_upstream: bool,
+
+    /// Answer A and AAAA queries from a static table instead of an upstream DNS server, in the
+    /// form '<name>=<ip>'. Queries for any other name get an NXDOMAIN response. May be specified
+    /// multiple times, meant for testing without a DNS server.
+    #[clap(long)]
+    upstream_static: Vec<StaticRecord>,
+
+    /// Timeout for upstream DNS server in milliseconds. Must be greater than zero, values
+    /// above 60000 are capped.
+    #[clap(long, default_value_t = DEFAULT_UPSTREAM_TIMEOUT_MS, parse(try_from_str = parse_timeout))]
     upstream_timeout: u64,
 
+    /// Timeout for connecting to the upstream DNS server over TCP or TLS (including the TLS
+    /// handshake) in milliseconds, separate from --upstream-timeout. Defaults to the value of
+    /// --upstream-timeout, values above 60000 are capped.
+    #[clap(long, parse(try_from_str = parse_timeout))]
+    upstream_connect_timeout: Option<u64>,
+
+    /// How to respond when the upstream DNS server returns SERVFAIL. Allowed values are 'propagate',
+    /// 'nxdomain', or an IP address to answer A or AAAA queries with. Queries for the other address
+    /// family or other types get the SERVFAIL response.
+    #[clap(long, default_value_t = ServFailPolicy::Propagate)]
+    servfail_response: ServFailPolicy,
+
+    /// Start in maintenance mode, answering every query with --maintenance-response instead of
+    /// resolving it. Maintenance mode can be turned on or off at runtime with --maintenance-endpoint.
+    #[clap(long)]
+    maintenance_mode: bool,
+
+    /// How to respond to queries in maintenance mode. Allowed values are 'servfail', 'nxdomain', or
+    /// an IP address to answer A or AAAA queries with (using --synthetic-ttl).
+    #[clap(long, default_value_t = MaintenanceResponse::ServFail)]
+    maintenance_response: MaintenanceResponse,
+
+    /// Enable the 'POST /maintenance' endpoint to turn maintenance mode on or off at runtime, either
+    /// with an 'enabled=true' or 'enabled=false' parameter or toggling it without one.
+    #[clap(long)]
+    maintenance_endpoint: bool,
+
+    /// How to handle JSON and text requests where the name is an IP address (e.g. 'name=1.2.3.4')
+    /// instead of a domain name. Allowed values are 'reject' (respond with a 400), 'ptr' (look up
+    /// the PTR record of the address instead for A, AAAA, or PTR queries and respond with a 400
+    /// for other types), or 'forward' (send the name upstream unchanged).
+    #[clap(long, default_value_t = IpLiteralPolicy::Reject)]
+    ip_literal: IpLiteralPolicy,
+
+    /// How to handle queries with more than one question, which most DNS servers refuse. Allowed
+    /// values are 'reject' (respond with a 400), 'split' (send each question as a separate query
+    /// and merge the answers), or 'forward' (send the query as-is).
+    #[clap(long, default_value_t = MultiQuestionPolicy::Forward)]
+    multi_question: MultiQuestionPolicy,
+
+    /// Replace CNAME chains that end in A or AAAA records with only the address records, using
+    /// the queried name.
+    #[clap(long)]
+    flatten_cname: bool,
+
+    /// Log a warning for queries that take longer than this many milliseconds to resolve.
+    #[clap(long)]
+    slow_query_threshold: Option<u64>,
+
+    /// Answer PTR queries for an address with the given name instead of forwarding them to the
+    /// upstream DNS server, in the form '<ip>=<name>'. May be specified multiple times.
+    #[clap(long)]
+    self_ptr: Vec<SelfPtr>,
+
+    /// Return a rotating window of at most this many A or AAAA records for larger answer sets.
+    #[clap(long)]
+    answer_subset: Option<NonZeroUsize>,
+
+    /// Sort SRV records by priority, ordering records with the same priority by a weighted random
+    /// shuffle (RFC 2782), for clients that don't do this themselves.
+    #[clap(long)]
+    sort_srv: bool,
+
+    /// TTL in seconds for records in responses synthesized by Donut instead of the upstream server.
+    #[clap(long, default_value_t = donut::response::DEFAULT_SYNTHETIC_TTL)]
+    synthetic_ttl: u32,
+
+    /// Answer NS queries for the root and TLDs using records from this file (in zone file
+    /// format, such as the IANA root hints file) instead of forwarding them upstream.
+    #[clap(long)]
+    preload_delegations: Option<std::path::PathBuf>,
+
+    /// Answer AAAA queries for names in this file when the upstream server has no AAAA records
+    /// for them. Each line of the file is a name and an IPv6 address separated by whitespace,
+    /// names starting with '*.' match any subdomain.
+    #[clap(long)]
+    aaaa_from_a_map: Option<std::path::PathBuf>,
+
+    /// Rewrite A and AAAA answers in one network to the same host in another network of the
+    /// same size, in the form '<from-cidr>=<to-cidr>'. May be specified multiple times.
+    #[clap(long)]
+    nat_map: Vec<NatMapping>,
+
+    /// Cache at most this many responses from the upstream server in memory. Responses are
+    /// not cached if this isn't set.
+    #[clap(long)]
+    cache_size: Option<NonZeroUsize>,
+
+    /// Keep responses in the cache for this many seconds after they expire and use them to answer
+    /// queries when the upstream DNS server can't be reached (RFC 8767). Records in these stale
+    /// responses have a TTL of 30 seconds.
+    #[clap(long, requires = "cache-size")]
+    serve_stale_ttl: Option<u64>,
+
+    /// Move A and AAAA answers in this network (in CIDR notation) to the front of responses.
+    /// May be specified multiple times, earlier networks are preferred over later ones.
+    #[clap(long)]
+    prefer_address: Vec<AddressPrefix>,
+
+    /// Save the contents of the response cache to this file on shutdown and load it on startup.
+    #[clap(long, requires = "cache-size")]
+    cache_persist: Option<std::path::PathBuf>,
+
+    /// Reject queries for names with more than this many labels.
+    #[clap(long, default_value_t = donut::request::DEFAULT_MAX_LABELS)]
+    max_labels: u8,
+
+    /// Remove any EDNS Client Subnet option from queries before sending them upstream, including
+    /// ones sent by clients in wire format requests, so that client networks are never revealed.
+    #[clap(long)]
+    strip_ecs: bool,
+
+    /// Reject wire format requests with trailing data after the DNS message.
+    #[clap(long)]
+    strict_parse: bool,
+
+    /// Allow JSON clients to request the base64 encoded wire format response via the 'raw' parameter.
+    #[clap(long)]
+    allow_raw: bool,
+
+    /// Pad JSON responses to a multiple of this many bytes to make traffic analysis harder.
+    #[clap(long)]
+    pad_json: Option<NonZeroUsize>,
+
+    /// Pad all wire format responses that use EDNS to a multiple of --pad-block-size bytes
+    /// (RFC 8467), not just responses to queries that include padding.
+    #[clap(long)]
+    pad_responses: bool,
+
+    /// Block size in bytes to pad wire format responses to a multiple of, when padded.
+    #[clap(long, default_value_t = NonZeroUsize::new(donut::response::DEFAULT_PAD_BLOCK).unwrap())]
+    pad_block_size: NonZeroUsize,
+
+    /// Include this comment in all JSON responses.
+    #[clap(long)]
+    json_comment: Option<String>,
+
+    /// Wrap JSON responses in an envelope with metadata about how the query was resolved, in
+    /// the form '{"meta":{"upstream":...,"cache":...,"latency_ms":...},"response":{...}}'.
+    /// Requests can opt out with the 'envelope=false' (or 'envelope=0') parameter.
+    #[clap(long)]
+    json_envelope: bool,
+
+    /// Respond to JSON requests made with 'Accept: application/json' using the same content type
+    /// instead of 'application/dns-json'.
+    #[clap(long)]
+    json_match_accept: bool,
+
+    /// Timeout for upstream DNS server in milliseconds for queries of a particular type, in
+    /// the form '<type>=<ms>'. May be specified multiple times.
+    #[clap(long)]
+    type_timeout: Vec<TypeTimeout>,
+
+    /// Add this header to all HTTP responses, in the form '<name>=<value>'. May be specified
+    /// multiple times.
+    #[clap(long)]
+    response_header: Vec<ResponseHeader>,
+
+    /// Include the value of this request header as the tenant in logs for each query.
+    #[clap(long)]
+    tenant_header: Option<HeaderName>,
+
+    /// Answer AAAA queries with no records and remove AAAA records from other responses so
+    /// that clients on IPv4-only networks don't attempt IPv6 connections.
+    #[clap(long, conflicts_with = "aaaa-from-a-map")]
+    no_aaaa: bool,
+
+    /// Retry queries with this domain appended to the name when the upstream server returns
+    /// NXDOMAIN for a name with fewer dots than --ndots. Responses to retried queries include
+    /// a CNAME record from the original name to the name with the domain appended.
+    #[clap(long)]
+    search_domain: Option<Name>,
+
+    /// Minimum number of dots in a name for it to not be retried with --search-domain.
+    #[clap(long, default_value_t = donut::resolve::DEFAULT_NDOTS)]
+    ndots: u8,
+
+    /// Upper bounds of the buckets of the query latency histogram, in seconds, as a comma
+    /// separated list in increasing order.
+    #[clap(long, default_value_t = LatencyBuckets::default())]
+    latency_buckets: LatencyBuckets,
+
+    /// Reuse the result of checking upstream DNS servers for the /health endpoint for this many
+    /// milliseconds.
+    #[clap(long, default_value_t = 5000)]
+    health_check_interval: u64,
+
+    /// Randomize the case of the names in queries sent to upstream DNS servers over UDP and
+    /// reject responses that don't use exactly the same case (0x20 encoding), making spoofed
+    /// responses harder to forge. Upstream servers must preserve the case of query names.
+    #[clap(long)]
+    qname_randomize: bool,
+
+    /// Don't retry queries over TCP when the response from the upstream server is truncated
+    /// or larger than the payload size advertised in the query.
+    #[clap(long)]
+    no_tcp_fallback: bool,
+
+    /// Don't log every query resolved. Errors and slow queries are still logged.
+    #[clap(long)]
+    no_query_log: bool,
+
+    /// Write dnstap messages for each query forwarded upstream to this Unix socket.
+    #[cfg(feature = "dnstap")]
+    #[clap(long)]
+    dnstap_socket: Option<std::path::PathBuf>,
+
+    /// Maximum number of HTTP connections to have open at once. Additional connections wait
+    /// until an open connection is closed.
+    #[clap(long)]
+    max_connections: Option<NonZeroUsize>,
+
+    /// Only allow requests from clients with an IP address in this network, in the form
+    /// '<ip>/<prefix>'. Requests from other clients get a 403 response. May be specified multiple
+    /// times, all clients are allowed if not specified.
+    #[clap(long)]
+    allow_from: Vec<AddressPrefix>,
+
+    /// Maximum number of queries from a single client IP address to handle at once. Additional
+    /// queries from the client are rejected with a 429 response.
+    #[clap(long)]
+    max_concurrent_per_client: Option<NonZeroUsize>,
+
+    /// How to respond to requests denied by --allow-from or --max-concurrent-per-client. Allowed
+    /// values are 'reject' (respond with a 403 or 429) or 'drop' (close the connection without
+    /// responding).
+    #[clap(long, default_value_t = DenyAction::Reject)]
+    deny_action: DenyAction,
+
+    /// Enable the 'GET /trace' endpoint, resolving a query given with the same parameters as JSON
+    /// requests and responding with the result of each step (parsing, cache, upstream, etc.). Also
+    /// enables the 'GET /cache' endpoint, listing cached responses with their remaining TTL, and the
+    /// 'POST /cache/flush' endpoint, removing every cached response or only those for the 'name'
+    /// parameter.
+    #[clap(long)]
+    admin: bool,
+
+    /// Allow --inject-delay and --inject-error-rate to be used. These are only meant for testing
+    /// how clients handle a slow or unreliable server, never in production.
+    #[clap(long, hide = true)]
+    enable_chaos: bool,
+
+    /// Delay each query by this many milliseconds before resolving it. Requires --enable-chaos.
+    #[clap(long, hide = true, requires = "enable-chaos")]
+    inject_delay: Option<u64>,
+
+    /// Fraction of queries, between 0.0 and 1.0, to fail with a 503 response instead of resolving
+    /// them. Requires --enable-chaos.
+    #[clap(long, hide = true, requires = "enable-chaos", parse(try_from_str = parse_error_rate))]
+    inject_error_rate: Option<f64>,
+
     /// Logging verbosity. Allowed values are 'trace', 'debug', 'info', 'warn', and 'error' (case insensitive).
     #[clap(long, default_value_t = DEFAULT_LOG_LEVEL)]
     log_level: Level,
@@ -61,35 +367,242 @@ struct DonutApplication {
     /// Address to bind to.
     #[clap(long, default_value_t = DEFAULT_BIND_ADDR.into())]
     bind: SocketAddr,
-}
 
-async fn new_udp_dns_client(addr: SocketAddr, timeout: Duration) -> DonutResult<AsyncClient> {
-    let conn = UdpClientStream::<UdpSocket>::with_timeout(addr, timeout);
-    let (client, bg) = AsyncClient::connect(conn).await?;
-    // Trust DNS clients are really just handles for talking to a future running in the background
-    // that actually does all the network activity and DNS lookups. Start the background future here
-    // on whatever Tokio executor has been set up when `main()` was run.
-    tokio::spawn(bg);
-    Ok(client)
+    /// Serve HTTPS using the certificate chain in this file (PEM format) instead of plain HTTP.
+    /// The certificate and key are loaded again from their files on SIGHUP.
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<std::path::PathBuf>,
+
+    /// Private key for the certificate given by --tls-cert (PEM format, PKCS8 or RSA).
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<std::path::PathBuf>,
+
+    /// Oldest TLS version clients may connect with when serving HTTPS. Allowed values are '1.2'
+    /// or '1.3'.
+    #[clap(long, default_value_t = TlsMinVersion::V1_2)]
+    tls_min_version: TlsMinVersion,
+
+    /// Comma separated list of cipher suites clients may connect with when serving HTTPS, using
+    /// the names from rustls (e.g. 'TLS13_AES_256_GCM_SHA384'). Suites that can't be used with
+    /// --tls-min-version are ignored, it's an error if none of them can be used.
+    #[clap(long, default_value_t = TlsCipherSuites::default())]
+    tls_cipher_suites: TlsCipherSuites,
 }
 
-async fn new_handler_context(addr: SocketAddr, timeout: Duration) -> DonutResult<HandlerContext> {
-    let client = new_udp_dns_client(addr, timeout).await?;
-    let resolver = UdpResolver::new(client);
-    let json_parser = RequestParserJsonGet::default();
-    let get_parser = RequestParserWireGet::default();
-    let post_parser = RequestParserWirePost::default();
-    let json_encoder = ResponseEncoderJson::default();
-    let wire_encoder = ResponseEncoderWire::default();
+async fn new_handler_context(opts: &DonutApplication) -> DonutResult<HandlerContext> {
+    let timeout = Duration::from_millis(opts.upstream_timeout.min(MAX_UPSTREAM_TIMEOUT_MS));
+    let connect_timeout = opts
+        .upstream_connect_timeout
+        .map(|t| Duration::from_millis(t.min(MAX_UPSTREAM_TIMEOUT_MS)))
+        .unwrap_or(timeout);
+    let delegations = match &opts.preload_delegations {
+        Some(path) => {
+            let delegations = Delegations::from_file(path)?;
+            tracing::info!(message = "loaded delegations", path = ?path, num_records = delegations.len());
+            delegations
+        }
+        None => Delegations::default(),
+    };
+    let aaaa_map = match &opts.aaaa_from_a_map {
+        Some(path) => {
+            let aaaa_map = AaaaMap::from_file(path)?;
+            tracing::info!(message = "loaded AAAA map", path = ?path, num_mappings = aaaa_map.len());
+            aaaa_map
+        }
+        None => AaaaMap::default(),
+    };
+
+    let options = ResolverOptions {
+        servfail: opts.servfail_response,
+        flatten_cname: opts.flatten_cname,
+        slow_query_threshold: opts.slow_query_threshold.map(Duration::from_millis),
+        self_ptr: opts.self_ptr.clone(),
+        answer_subset: opts.answer_subset,
+        sort_srv: opts.sort_srv,
+        timeout: Some(timeout),
+        connect_timeout: Some(connect_timeout),
+        type_timeouts: opts.type_timeout.clone(),
+        disable_query_log: opts.no_query_log,
+        synthetic_ttl: opts.synthetic_ttl,
+        delegations,
+        aaaa_map,
+        nat_map: opts.nat_map.clone(),
+        prefer_address: opts.prefer_address.clone(),
+        upstream_strategy: opts.upstream_strategy,
+        tcp_fallback: !opts.no_tcp_fallback,
+        search_domain: opts.search_domain.clone(),
+        ndots: opts.ndots,
+        no_aaaa: opts.no_aaaa,
+        multi_question: opts.multi_question,
+        qname_randomize: opts.qname_randomize,
+        #[cfg(feature = "dnstap")]
+        dnstap: opts.dnstap_socket.as_ref().map(donut::dnstap::DnstapLogger::new),
+    };
+
+    // The upstream client enforces its own timeout so make sure that it's long enough
+    // for any of the per-type timeouts. The resolver enforces the shorter timeouts.
+    let client_timeout = opts
+        .type_timeout
+        .iter()
+        .map(|t| t.timeout())
+        .fold(timeout, Duration::max);
+
+    let source = SourceAddrs {
+        v4: opts.upstream_bind4,
+        v6: opts.upstream_bind6,
+    };
+
+    let resolver: Resolver = if let (Some(addr), Some(name)) = (opts.upstream_tls, &opts.upstream_tls_name) {
+        let tls = TlsUpstream::new(name, opts.upstream_tls_ca.as_deref())?.with_connect_timeout(connect_timeout);
+        let client = if opts.lazy_upstream {
+            donut::resolve::new_lazy_tls_client(addr, tls, source, client_timeout).await?
+        } else {
+            donut::resolve::new_tls_client(addr, tls, source, client_timeout).await?
+        };
+        tracing::info!(
+            message = "using upstream server",
+            transport = "tls",
+            address = %addr,
+            name = %name,
+            timeout_ms = timeout.as_millis() as u64,
+            connect_timeout_ms = connect_timeout.as_millis() as u64,
+        );
+        UpstreamResolver::new(client, addr, options).into()
+    } else if opts.upstream_static.is_empty() {
+        let mut clients = Vec::with_capacity(opts.upstream_udp.len());
+        for &addr in opts.upstream_udp.iter() {
+            let client = if opts.lazy_upstream {
+                donut::resolve::new_lazy_udp_client(addr, source, client_timeout).await?
+            } else {
+                donut::resolve::new_udp_client(addr, source, client_timeout).await?
+            };
+            clients.push((client, addr));
+            tracing::info!(
+                message = "using upstream server",
+                transport = "udp",
+                address = %addr,
+                timeout_ms = timeout.as_millis() as u64,
+            );
+        }
+
+        let mut clients = clients.into_iter();
+        let (client, addr) = clients.next().expect("upstream servers has a default value");
+        clients
+            .fold(UpstreamResolver::new(client, addr, options), |r, (c, a)| {
+                r.with_upstream(c, a)
+            })
+            .into()
+    } else {
+        tracing::info!(
+            message = "using upstream server",
+            transport = "static",
+            num_records = opts.upstream_static.len(),
+        );
+        StaticResolver::new(opts.upstream_static.clone(), opts.synthetic_ttl).into()
+    };
 
-    Ok(HandlerContext::new(
+    let validator = RequestValidator::new(opts.max_labels).with_strip_ecs(opts.strip_ecs);
+    let json_parser = RequestParserJsonGet::new(validator.clone()).with_ip_literal(opts.ip_literal);
+    let get_parser = RequestParserWireGet::new(validator.clone(), opts.strict_parse);
+    let post_parser = RequestParserWirePost::new(validator, opts.strict_parse);
+    let json_encoder = ResponseEncoderJson::new(opts.allow_raw, opts.pad_json, opts.json_comment.clone());
+    let wire_encoder = ResponseEncoderWire::new()
+        .with_pad_block(opts.pad_block_size)
+        .with_pad_all(opts.pad_responses);
+
+    let text_encoder = ResponseEncoderText::new();
+    donut::response::self_test(&json_encoder, &wire_encoder, &text_encoder).await?;
+
+    let mut context = HandlerContext::new(
         json_parser,
         get_parser,
         post_parser,
         resolver,
         json_encoder,
         wire_encoder,
-    ))
+        text_encoder,
+    )
+    .with_metrics(Metrics::new(opts.latency_buckets.clone()))
+    .with_health_interval(Duration::from_millis(opts.health_check_interval));
+
+    if let Some(size) = opts.cache_size {
+        let cache = ResponseCache::new(size).with_stale_ttl(Duration::from_secs(opts.serve_stale_ttl.unwrap_or(0)));
+        if let Some(path) = opts.cache_persist.as_ref().filter(|p| p.exists()) {
+            // Any problems with the saved cache just mean starting with an empty one
+            match cache.load(path) {
+                Ok(n) => tracing::info!(message = "loaded response cache", path = ?path, num_entries = n),
+                Err(e) => tracing::warn!(message = "unable to load response cache", path = ?path, error = %e),
+            }
+        }
+
+        context = context.with_cache(cache);
+    }
+
+    if opts.maintenance_mode || opts.maintenance_endpoint {
+        context = context.with_maintenance(Maintenance::new(
+            opts.maintenance_response,
+            opts.synthetic_ttl,
+            opts.maintenance_mode,
+        ));
+    }
+
+    if opts.enable_chaos && (opts.inject_delay.is_some() || opts.inject_error_rate.is_some()) {
+        tracing::warn!(
+            message = "injecting latency or errors into queries, do not use in production",
+            delay_ms = opts.inject_delay.unwrap_or(0),
+            error_rate = opts.inject_error_rate.unwrap_or(0.0),
+        );
+
+        context = context.with_chaos(Chaos::new(
+            opts.inject_delay.map(Duration::from_millis),
+            opts.inject_error_rate.unwrap_or(0.0),
+        ));
+    }
+
+    if opts.json_envelope {
+        context = context.with_json_envelope(true);
+    }
+
+    if let Some(max) = opts.max_concurrent_per_client {
+        context = context
+            .with_max_concurrent_per_client(max)
+            .with_deny_action(opts.deny_action);
+    }
+
+    if let Some(header) = &opts.tenant_header {
+        context = context.with_tenant_header(header.clone());
+    }
+
+    Ok(context)
+}
+
+/// Filter that rejects every request unless `enabled` is set, for routes that must be opted into
+fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+fn parse_error_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        Ok(_) => Err("error rate must be between 0.0 and 1.0".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(0) => Err("timeout must be greater than zero".to_owned()),
+        Ok(v) => Ok(v),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 #[tokio::main]
@@ -103,29 +616,102 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     )
     .expect("Failed to set tracing subscriber");
 
-    let timeout = Duration::from_millis(opts.upstream_timeout);
-    let context = Arc::new(new_handler_context(opts.upstream_udp, timeout).await.unwrap());
+    if let Some(t) = opts.upstream_connect_timeout.filter(|t| *t > MAX_UPSTREAM_TIMEOUT_MS) {
+        tracing::warn!(
+            message = "upstream connect timeout too large, capping",
+            timeout_ms = t,
+            max_timeout_ms = MAX_UPSTREAM_TIMEOUT_MS,
+        );
+    }
+
+    if opts.upstream_timeout > MAX_UPSTREAM_TIMEOUT_MS {
+        tracing::warn!(
+            message = "upstream timeout too large, capping",
+            timeout_ms = opts.upstream_timeout,
+            max_timeout_ms = MAX_UPSTREAM_TIMEOUT_MS,
+        );
+    }
+
+    let context = Arc::new(new_handler_context(&opts).await.unwrap_or_else(|e| {
+        tracing::error!(message = "error creating handler context", error = %e);
+        process::exit(1)
+    }));
 
-    let handler = donut::http::json_get(context.clone())
+    // The maintenance endpoint lets anyone that can reach it stop queries from being resolved
+    // and the admin endpoints bypass limits on queries or empty the cache so they're only routed to
+    // when enabled.
+    let maintenance = enabled(opts.maintenance_endpoint).and(donut::http::maintenance(context.clone()));
+    let trace = enabled(opts.admin).and(donut::http::trace(context.clone()));
+    let cache_list = enabled(opts.admin).and(donut::http::cache_list(context.clone()));
+    let cache_flush = enabled(opts.admin).and(donut::http::cache_flush(context.clone()));
+
+    let handler = donut::http::access_control(opts.allow_from.clone(), opts.deny_action)
+        .or(donut::http::json_get(context.clone(), opts.json_match_accept))
+        .or(donut::http::text_get(context.clone()))
         .or(donut::http::wire_get(context.clone()))
         .or(donut::http::wire_post(context.clone()))
-        .or(donut::http::fallback());
-
-    let (sock, server) = warp::serve(handler)
-        .try_bind_with_graceful_shutdown(opts.bind, async {
-            // Wait for either SIGTERM or SIGINT to shutdown
-            tokio::select! {
-                _ = sigterm() => {}
-                _ = sigint() => {}
-            }
-        })
-        .unwrap_or_else(|e| {
-            tracing::error!(message = "error binding to address", address = %opts.bind, error = %e);
-            process::exit(1)
-        });
+        .or(donut::http::metadata(ServerMetadata::new(opts.max_labels)))
+        .or(donut::http::metrics(context.clone()))
+        .or(donut::http::health(context.clone()))
+        .or(maintenance)
+        .or(trace)
+        .or(cache_list)
+        .or(cache_flush)
+        .or(donut::http::fallback())
+        .with(warp::reply::with::headers(ResponseHeader::to_map(
+            &opts.response_header,
+        )));
 
-    tracing::info!(message = "server started", address = %sock);
-    server.await;
+    let acceptor = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert_file), Some(key_file)) => {
+            let acceptor = ReloadableCert::load(cert_file, key_file)
+                .and_then(|cert| {
+                    let acceptor = donut::listen::tls_acceptor(&cert, opts.tls_min_version, &opts.tls_cipher_suites)?;
+                    tokio::spawn(reload_on_sighup(cert));
+                    Ok(acceptor)
+                })
+                .unwrap_or_else(|e| {
+                    tracing::error!(message = "error configuring TLS", cert = ?cert_file, key = ?key_file, error = %e);
+                    process::exit(1)
+                });
+
+            Some(acceptor)
+        }
+        _ => None,
+    };
+
+    let listener = TcpListener::bind(opts.bind).await.unwrap_or_else(|e| {
+        tracing::error!(message = "error binding to address", address = %opts.bind, error = %e);
+        process::exit(1)
+    });
+    let sock = listener.local_addr()?;
+    let incoming = donut::listen::incoming(listener, opts.max_connections);
+    let shutdown = async {
+        // Wait for either SIGTERM or SIGINT to shutdown
+        tokio::select! {
+            _ = sigterm() => {}
+            _ = sigint() => {}
+        }
+    };
+
+    tracing::info!(message = "server started", address = %sock, tls = acceptor.is_some());
+    let res = match acceptor {
+        Some(acceptor) => {
+            donut::listen::serve(handler, donut::listen::incoming_tls(incoming, acceptor), shutdown).await
+        }
+        None => donut::listen::serve(handler, incoming, shutdown).await,
+    };
+
+    if let Err(e) = res {
+        tracing::error!(message = "error serving requests", error = %e);
+    }
+
+    if let (Some(cache), Some(path)) = (context.cache(), &opts.cache_persist) {
+        match cache.save(path) {
+            Ok(n) => tracing::info!(message = "saved response cache", path = ?path, num_entries = n),
+            Err(e) => tracing::error!(message = "unable to save response cache", path = ?path, error = %e),
+        }
+    }
 
     tracing::info!("server shutdown");
     Ok(())
@@ -142,3 +728,45 @@ async fn sigint() -> io::Result<()> {
     unix::signal(SignalKind::interrupt())?.recv().await;
     Ok(())
 }
+
+/// Reload the TLS certificate and key from their files each time a SIGHUP signal is received
+async fn reload_on_sighup(cert: ReloadableCert) {
+    let mut signal = match unix::signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!(message = "unable to listen for SIGHUP, TLS certificate can't be reloaded", error = %e);
+            return;
+        }
+    };
+
+    while signal.recv().await.is_some() {
+        match cert.reload() {
+            Ok(()) => tracing::info!(message = "reloaded TLS certificate", cert = ?cert),
+            Err(e) => tracing::error!(message = "error reloading TLS certificate", cert = ?cert, error = %e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_timeout, DonutApplication};
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(Ok(1), parse_timeout("1"));
+        assert_eq!(Ok(5000), parse_timeout("5000"));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("fast").is_err());
+    }
+
+    #[test]
+    fn test_upstream_timeouts_zero() {
+        assert!(DonutApplication::try_parse_from(["donut", "--upstream-timeout", "0"]).is_err());
+        assert!(DonutApplication::try_parse_from(["donut", "--upstream-connect-timeout", "0"]).is_err());
+
+        let opts = DonutApplication::try_parse_from(["donut", "--upstream-timeout", "250"]).unwrap();
+        assert_eq!(250, opts.upstream_timeout);
+    }
+}
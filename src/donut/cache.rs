@@ -0,0 +1,747 @@
+// Donut - DNS over HTTPS server
+//
+// Copyright 2019 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! In-memory cache of responses from the resolver.
+
+use crate::response::ResponseMetadata;
+use crate::types::{DonutError, DonutResult, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use trust_dns_client::op::{DnsResponse, Edns, Message, ResponseCode};
+use trust_dns_client::proto::rr::rdata::opt::EdnsCode;
+use trust_dns_client::proto::serialize::binary::BinEncodable;
+use trust_dns_client::proto::xfer::DnsRequest;
+use trust_dns_client::rr::{DNSClass, Name, RData, Record, RecordType};
+
+/// TTL of records in stale responses, as recommended by RFC 8767
+pub const STALE_ANSWER_TTL: u32 = 30;
+
+/// Key for cached responses, based on the single query of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    /// Lowercase, fully qualified version of the query name
+    name: String,
+    kind: RecordType,
+    class: DNSClass,
+    /// Responses to queries with checking disabled may contain records that failed DNSSEC
+    /// validation, make sure they're never used to answer queries with checking enabled.
+    checking_disabled: bool,
+    /// Responses to queries with the DO bit set include DNSSEC records that other queries
+    /// didn't ask for (and vice versa) so keep them separate.
+    dnssec_ok: bool,
+}
+
+impl CacheKey {
+    /// Create a key for the request if it has a single query, the only kind we cache
+    ///
+    /// Requests with a client subnet option aren't cached since the response is only meant for
+    /// clients in that network.
+    fn from_request(req: &DnsRequest) -> Option<Self> {
+        if req.edns().and_then(|e| e.option(EdnsCode::Subnet)).is_some() {
+            return None;
+        }
+
+        Self::from_message(req, req.checking_disabled(), req.edns().is_some_and(Edns::dnssec_ok))
+    }
+
+    fn from_message(message: &Message, checking_disabled: bool, dnssec_ok: bool) -> Option<Self> {
+        match message.queries() {
+            [q] => Some(CacheKey {
+                name: Self::key_name(q.name()),
+                kind: q.query_type(),
+                class: q.query_class(),
+                checking_disabled,
+                dnssec_ok,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Lowercase, fully qualified version of a name so that names that only differ by case or
+    /// a trailing dot have the same key
+    fn key_name(name: &Name) -> String {
+        let mut name = name.to_lowercase();
+        name.set_fqdn(true);
+        name.to_ascii()
+    }
+}
+
+/// Version of the format used to save the cache to disk, incremented for incompatible changes
+const PERSIST_VERSION: u32 = 1;
+
+/// Cache contents saved to disk, see `ResponseCache::save` and `ResponseCache::load`
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedCache {
+    version: u32,
+    /// Unix timestamp in seconds of when the cache was saved
+    saved_at: u64,
+    /// Entries from least to most recently used
+    entries: Vec<PersistedEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedEntry {
+    checking_disabled: bool,
+    #[serde(default)]
+    dnssec_ok: bool,
+    /// Seconds until the entry expires as of when the cache was saved
+    expires_in: u64,
+    /// Base64 encoded wire format response with TTLs as of when the cache was saved
+    response: String,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: DnsResponse,
+    inserted: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+/// Entries in the cache along with the order they were last used in
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl CacheState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(e) = self.entries.remove(key) {
+            self.recency.remove(&e.last_used);
+        }
+    }
+}
+
+/// Name, type, and remaining TTL in seconds of a cached response, see `ResponseCache::list`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheListing {
+    pub name: String,
+    pub kind: RecordType,
+    pub ttl: u64,
+}
+
+/// Bounded, least recently used cache of responses from the resolver.
+///
+/// Only successful responses to requests with a single query that have at least one answer
+/// and negative (NXDOMAIN or NODATA) responses with an SOA record are cached. Entries expire
+/// based on the lowest TTL of any record, limited by the minimum field of the SOA record for
+/// negative responses (RFC 2308), and responses with a TTL of zero are never cached. TTLs of cached
+/// responses are decremented by the time they've spent in the cache when returned.
+///
+/// Expired entries can be kept for a while longer to answer queries when the resolver fails,
+/// see `with_stale_ttl`.
+#[derive(Debug)]
+pub struct ResponseCache {
+    capacity: usize,
+    stale_ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        ResponseCache {
+            capacity: capacity.get(),
+            stale_ttl: Duration::ZERO,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Keep entries for this long after they expire so that they can be returned by `get_stale`
+    /// when the resolver fails (RFC 8767)
+    pub fn with_stale_ttl(mut self, ttl: Duration) -> Self {
+        self.stale_ttl = ttl;
+        self
+    }
+
+    /// Get a cached response for the request, if there is an unexpired one
+    pub fn get(&self, req: &DnsRequest) -> Option<DnsResponse> {
+        let key = CacheKey::from_request(req)?;
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let expires = state.entries.get(&key)?.expires;
+        if expires <= now {
+            if expires + self.stale_ttl <= now {
+                state.remove(&key);
+            }
+
+            return None;
+        }
+
+        let tick = state.next_tick();
+        let entry = state.entries.get_mut(&key)?;
+        let previous = entry.last_used;
+        entry.last_used = tick;
+
+        let elapsed = now.duration_since(entry.inserted);
+        let response = adjust_response(req, &entry.response, elapsed);
+
+        state.recency.remove(&previous);
+        state.recency.insert(tick, key);
+        Some(response)
+    }
+
+    /// Get an expired response for the request, if it expired less than the stale TTL ago.
+    ///
+    /// All records in the response have a TTL of `STALE_ANSWER_TTL` so that clients retry soon.
+    pub fn get_stale(&self, req: &DnsRequest) -> Option<DnsResponse> {
+        let key = CacheKey::from_request(req)?;
+        let now = Instant::now();
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let entry = state.entries.get(&key)?;
+        if entry.expires > now || entry.expires + self.stale_ttl <= now {
+            return None;
+        }
+
+        let mut response = adjust_response(req, &entry.response, now.duration_since(entry.inserted));
+        let set_ttl = |records: &mut Vec<Record>| {
+            for r in records.iter_mut() {
+                r.set_ttl(STALE_ANSWER_TTL);
+            }
+        };
+
+        set_ttl(response.answers_mut());
+        set_ttl(response.name_servers_mut());
+        set_ttl(response.additionals_mut());
+        Some(response)
+    }
+
+    /// Store the response to the request if it can be cached, evicting the least recently
+    /// used entry if the cache is full
+    pub fn insert(&self, req: &DnsRequest, res: &DnsResponse) {
+        let key = match CacheKey::from_request(req) {
+            Some(k) => k,
+            None => return,
+        };
+
+        let code = res.response_code();
+        if (code != ResponseCode::NoError && code != ResponseCode::NXDomain) || res.truncated() {
+            return;
+        }
+
+        let meta = ResponseMetadata::from(res);
+        // Responses with no answers can only be cached if there's an SOA record (RFC 2308)
+        if res.answers().is_empty() && meta.negative_ttl().is_none() {
+            return;
+        }
+
+        let ttl = match meta.min_ttl() {
+            Some(ttl) if ttl > 0 => ttl,
+            // A TTL of zero means the response must not be cached
+            _ => return,
+        };
+
+        let mut res = res.clone();
+        if meta.negative_ttl().is_some() {
+            cap_soa_ttl(&mut res);
+        }
+
+        let now = Instant::now();
+        self.insert_entry(key, res, now, now + Duration::from_secs(u64::from(ttl)));
+    }
+
+    fn insert_entry(&self, key: CacheKey, response: DnsResponse, inserted: Instant, expires: Instant) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.remove(&key);
+
+        while state.entries.len() >= self.capacity {
+            match state.recency.pop_first() {
+                Some((_, oldest)) => {
+                    state.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        let tick = state.next_tick();
+        state.recency.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                response,
+                inserted,
+                expires,
+                last_used: tick,
+            },
+        );
+    }
+
+    /// Write all unexpired entries to a file so they can be loaded after a restart
+    ///
+    /// The file is written to a temporary path first and renamed so that a partially
+    /// written file is never loaded.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> DonutResult<usize> {
+        let now = Instant::now();
+        let persisted = {
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let entries: Vec<PersistedEntry> = state
+                .recency
+                .values()
+                .filter_map(|k| state.entries.get(k).map(|e| (k, e)))
+                .filter(|(_, e)| e.expires > now)
+                .filter_map(|(k, e)| {
+                    let elapsed = u32::try_from(now.duration_since(e.inserted).as_secs()).unwrap_or(u32::MAX);
+                    let mut response = e.response.clone();
+                    decrement_ttls(&mut response, elapsed);
+
+                    Some(PersistedEntry {
+                        checking_disabled: k.checking_disabled,
+                        dnssec_ok: k.dnssec_ok,
+                        expires_in: e.expires.duration_since(now).as_secs(),
+                        response: base64::encode(&response.to_bytes().ok()?),
+                    })
+                })
+                .collect();
+
+            PersistedCache {
+                version: PERSIST_VERSION,
+                saved_at: unix_now(),
+                entries,
+            }
+        };
+
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let bytes = serde_json::to_vec(&persisted)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to serialize cache", e)))?;
+        fs::write(&tmp, bytes)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to write cache file", e)))?;
+
+        Ok(persisted.entries.len())
+    }
+
+    /// Add unexpired entries from a file written by `save` to the cache
+    ///
+    /// Entries that can't be decoded are skipped, files from incompatible versions are
+    /// rejected entirely.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> DonutResult<usize> {
+        let bytes =
+            fs::read(path).map_err(|e| DonutError::from((ErrorKind::Internal, "unable to read cache file", e)))?;
+        let persisted: PersistedCache = serde_json::from_slice(&bytes)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to parse cache file", e)))?;
+
+        if persisted.version != PERSIST_VERSION {
+            return Err(DonutError::from((
+                ErrorKind::Internal,
+                "unsupported cache file version",
+            )));
+        }
+
+        let age = unix_now().saturating_sub(persisted.saved_at);
+        let now = Instant::now();
+        let mut loaded = 0;
+
+        for e in persisted.entries {
+            if e.expires_in <= age {
+                continue;
+            }
+
+            let mut response = match base64::decode(&e.response)
+                .ok()
+                .and_then(|b| Message::from_vec(&b).ok())
+            {
+                Some(m) => DnsResponse::from(m),
+                None => continue,
+            };
+
+            let key = match CacheKey::from_message(&response, e.checking_disabled, e.dnssec_ok) {
+                Some(k) => k,
+                None => continue,
+            };
+
+            decrement_ttls(&mut response, u32::try_from(age).unwrap_or(u32::MAX));
+            self.insert_entry(key, response, now, now + Duration::from_secs(e.expires_in - age));
+            loaded += 1;
+        }
+
+        Ok(loaded)
+    }
+
+    /// Remove all entries for `name` (of any type) or every entry if `name` isn't set, returning
+    /// the number of entries removed
+    pub fn clear(&self, name: Option<&Name>) -> usize {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let name = match name {
+            Some(n) => CacheKey::key_name(n),
+            None => {
+                let removed = state.entries.len();
+                state.entries.clear();
+                state.recency.clear();
+                return removed;
+            }
+        };
+
+        let keys: Vec<CacheKey> = state.entries.keys().filter(|k| k.name == name).cloned().collect();
+        for k in keys.iter() {
+            state.remove(k);
+        }
+
+        keys.len()
+    }
+
+    /// Describe up to `limit` entries in the cache, most recently used first. Entries that have
+    /// expired but haven't been removed yet (to be used as stale answers) have a TTL of zero.
+    pub fn list(&self, limit: usize) -> Vec<CacheListing> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        state
+            .recency
+            .values()
+            .rev()
+            .filter_map(|k| state.entries.get(k).map(|e| (k, e)))
+            .take(limit)
+            .map(|(k, e)| {
+                // Round up so that entries inserted a moment ago have their full TTL
+                let remaining = e.expires.saturating_duration_since(now);
+                CacheListing {
+                    name: k.name.clone(),
+                    kind: k.kind,
+                    ttl: remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0),
+                }
+            })
+            .collect()
+    }
+
+    /// Number of entries in the cache, including any that have expired but not been removed
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Copy a cached response for a new request, decrementing TTLs by the time spent in the cache
+fn adjust_response(req: &DnsRequest, cached: &DnsResponse, elapsed: Duration) -> DnsResponse {
+    let mut res = cached.clone();
+    let elapsed = u32::try_from(elapsed.as_secs()).unwrap_or(u32::MAX);
+
+    // Use the ID and question from the new request since clients expect them to match what
+    // they sent, including the case of the query name.
+    res.set_id(req.id());
+    res.take_queries();
+    res.add_queries(req.queries().to_vec());
+
+    decrement_ttls(&mut res, elapsed);
+    res
+}
+
+/// Limit the TTL of SOA records in the authority section of a negative response to their
+/// minimum field, so that the TTL of cached copies is the remaining negative TTL (RFC 2308)
+fn cap_soa_ttl(res: &mut DnsResponse) {
+    for r in res.name_servers_mut().iter_mut() {
+        if let RData::SOA(soa) = r.rdata() {
+            let ttl = r.ttl().min(soa.minimum());
+            r.set_ttl(ttl);
+        }
+    }
+}
+
+/// Decrement the TTL of every record in the response, stopping at zero
+fn decrement_ttls(res: &mut DnsResponse, elapsed: u32) {
+    let decrement = |records: &mut Vec<Record>| {
+        for r in records.iter_mut() {
+            let ttl = r.ttl().saturating_sub(elapsed);
+            r.set_ttl(ttl);
+        }
+    };
+
+    decrement(res.answers_mut());
+    decrement(res.name_servers_mut());
+    decrement(res.additionals_mut());
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheListing, ResponseCache, PERSIST_VERSION};
+    use crate::response::synthesize_response;
+    use std::fs;
+    use std::net::Ipv4Addr;
+    use std::num::NonZeroUsize;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::PoisonError;
+    use std::time::Duration;
+    use trust_dns_client::op::{DnsResponse, Message, Query, ResponseCode};
+    use trust_dns_client::proto::xfer::{DnsRequest, DnsRequestOptions};
+    use trust_dns_client::rr::rdata::SOA;
+    use trust_dns_client::rr::{Name, RData, Record, RecordType};
+
+    fn request(name: &str) -> DnsRequest {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        DnsRequest::new(msg, DnsRequestOptions::default())
+    }
+
+    fn response(req: &DnsRequest, ttl: u32) -> DnsResponse {
+        let name = req.queries()[0].name().clone();
+        let answer = Record::from_rdata(name, ttl, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        synthesize_response(req, ResponseCode::NoError, vec![answer])
+    }
+
+    /// NXDOMAIN response with an SOA record in the authority section if `soa` is set
+    fn nxdomain(req: &DnsRequest, soa: bool) -> DnsResponse {
+        let mut res = synthesize_response(req, ResponseCode::NXDomain, Vec::new());
+        if soa {
+            let zone = Name::from_str("example.com.").unwrap();
+            let rdata = SOA::new(
+                Name::from_str("ns1.example.com.").unwrap(),
+                Name::from_str("hostmaster.example.com.").unwrap(),
+                1,
+                3600,
+                600,
+                86400,
+                300,
+            );
+            res.add_name_server(Record::from_rdata(zone, 3600, RData::SOA(rdata)));
+        }
+
+        res
+    }
+
+    fn cache(capacity: usize) -> ResponseCache {
+        ResponseCache::new(NonZeroUsize::new(capacity).unwrap())
+    }
+
+    /// Make every entry in the cache look like it was inserted `secs` seconds earlier
+    fn backdate(cache: &ResponseCache, secs: u64) {
+        let elapsed = Duration::from_secs(secs);
+        let mut state = cache.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for e in state.entries.values_mut() {
+            e.inserted = e.inserted.checked_sub(elapsed).unwrap();
+            e.expires = e.expires.checked_sub(elapsed).unwrap();
+        }
+    }
+
+    /// Path of a file in the temporary directory unique to this process and test
+    fn temp_path(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("donut-cache-{}-{}.json", std::process::id(), test))
+    }
+
+    #[test]
+    fn test_insert_zero_ttl_not_cached() {
+        let cache = cache(4);
+        let req = request("www.example.com.");
+        cache.insert(&req, &response(&req, 0));
+
+        assert!(cache.is_empty());
+        assert!(cache.get(&req).is_none());
+    }
+
+    #[test]
+    fn test_get_decrements_ttl() {
+        let cache = cache(4);
+        let req = request("www.example.com.");
+        cache.insert(&req, &response(&req, 300));
+        backdate(&cache, 10);
+
+        let res = cache.get(&req).unwrap();
+        assert_eq!(290, res.answers()[0].ttl());
+    }
+
+    #[test]
+    fn test_get_expired() {
+        let cache = cache(4);
+        let req = request("www.example.com.");
+        cache.insert(&req, &response(&req, 300));
+        backdate(&cache, 300);
+
+        assert!(cache.get(&req).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_insert_evicts_least_recently_used() {
+        let cache = cache(2);
+        let (a, b, c) = (
+            request("a.example.com."),
+            request("b.example.com."),
+            request("c.example.com."),
+        );
+
+        cache.insert(&a, &response(&a, 300));
+        cache.insert(&b, &response(&b, 300));
+        // Use the oldest entry so that the other one is evicted instead
+        assert!(cache.get(&a).is_some());
+        cache.insert(&c, &response(&c, 300));
+
+        assert_eq!(2, cache.len());
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some());
+    }
+
+    #[test]
+    fn test_insert_negative_with_soa() {
+        let cache = cache(4);
+        let req = request("missing.example.com.");
+        cache.insert(&req, &nxdomain(&req, true));
+
+        let res = cache.get(&req).unwrap();
+        assert_eq!(ResponseCode::NXDomain, res.response_code());
+        // The SOA TTL is limited to its minimum field so that's how long the response is cached
+        assert_eq!(300, res.name_servers()[0].ttl());
+
+        backdate(&cache, 300);
+        assert!(cache.get(&req).is_none());
+    }
+
+    #[test]
+    fn test_insert_negative_without_soa() {
+        let cache = cache(4);
+        let req = request("missing.example.com.");
+        cache.insert(&req, &nxdomain(&req, false));
+
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_save_load_decrements_ttl() {
+        let path = temp_path("save-load");
+        let saved = cache(4);
+        let req = request("www.example.com.");
+        saved.insert(&req, &response(&req, 300));
+        backdate(&saved, 10);
+
+        assert_eq!(1, saved.save(&path).unwrap());
+
+        let loaded = cache(4);
+        let count = loaded.load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(1, count.unwrap());
+        let res = loaded.get(&req).unwrap();
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 1)), res.answers()[0].rdata());
+        // The save time is only stored to the second so a second may have passed since saving
+        let ttl = res.answers()[0].ttl();
+        assert!((289..=290).contains(&ttl), "ttl: {}", ttl);
+    }
+
+    #[test]
+    fn test_load_corrupt_file() {
+        let path = temp_path("corrupt");
+        fs::write(&path, b"{\"version\": 1, \"entries\": [").unwrap();
+
+        let cache = cache(4);
+        let res = cache.load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(res.is_err());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_load_wrong_version() {
+        let path = temp_path("version");
+        let saved = cache(4);
+        let req = request("www.example.com.");
+        saved.insert(&req, &response(&req, 300));
+        saved.save(&path).unwrap();
+
+        let mut contents: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        contents["version"] = serde_json::Value::from(PERSIST_VERSION + 1);
+        fs::write(&path, serde_json::to_vec(&contents).unwrap()).unwrap();
+
+        let cache = cache(4);
+        let res = cache.load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(res.is_err());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_key_ignores_case_and_trailing_dot() {
+        let cache = cache(4);
+        let names = ["example.com", "example.com.", "EXAMPLE.COM"];
+
+        let first = request(names[0]);
+        cache.insert(&first, &response(&first, 300));
+        for n in names {
+            let req = request(n);
+            assert!(cache.get(&req).is_some(), "name: {}", n);
+
+            cache.insert(&req, &response(&req, 300));
+            assert_eq!(1, cache.len(), "name: {}", n);
+        }
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = cache(4);
+        for n in ["www.example.com.", "mail.example.com.", "www.example.net."] {
+            let req = request(n);
+            cache.insert(&req, &response(&req, 300));
+        }
+
+        assert_eq!(1, cache.clear(Some(&Name::from_str("WWW.example.com").unwrap())));
+        assert!(cache.get(&request("www.example.com.")).is_none());
+        assert!(cache.get(&request("mail.example.com.")).is_some());
+        assert_eq!(0, cache.clear(Some(&Name::from_str("www.example.com.").unwrap())));
+
+        assert_eq!(2, cache.clear(None));
+        assert!(cache.is_empty());
+        assert!(cache.get(&request("www.example.net.")).is_none());
+    }
+
+    #[test]
+    fn test_list() {
+        let cache = cache(4);
+        for n in ["www.example.com.", "mail.example.com."] {
+            let req = request(n);
+            cache.insert(&req, &response(&req, 300));
+        }
+        backdate(&cache, 10);
+
+        let expected = |name: &str| CacheListing {
+            name: name.to_owned(),
+            kind: RecordType::A,
+            ttl: 290,
+        };
+
+        let listing = cache.list(10);
+        assert_eq!(
+            vec![expected("mail.example.com."), expected("www.example.com.")],
+            listing
+        );
+        assert_eq!(vec![expected("mail.example.com.")], cache.list(1));
+    }
+}
@@ -0,0 +1,322 @@
+// Donut - DNS over HTTPS server
+//
+// Copyright 2019 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Emit dnstap messages for each query forwarded upstream over a Frame Streams Unix socket.
+//!
+//! See <https://dnstap.info/> for the dnstap format and <https://github.com/farsightsec/fstrm>
+//! for the Frame Streams protocol. Only the small subset of protobuf encoding needed to write
+//! dnstap messages is implemented here.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const QUEUE_SIZE: usize = 1024;
+
+const FSTRM_CONTROL_ACCEPT: u32 = 0x01;
+const FSTRM_CONTROL_START: u32 = 0x02;
+const FSTRM_CONTROL_READY: u32 = 0x04;
+const FSTRM_FIELD_CONTENT_TYPE: u32 = 0x01;
+
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+const MESSAGE_TYPE_FORWARDER_QUERY: u64 = 7;
+const MESSAGE_TYPE_FORWARDER_RESPONSE: u64 = 8;
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+
+/// Transport used to send a query upstream, values are from the `SocketProtocol` enum of dnstap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketProtocol {
+    Udp = 1,
+    Tcp = 2,
+    Dot = 3,
+}
+
+/// Handle for sending dnstap messages to a background task that writes them to a socket.
+///
+/// Messages are dropped instead of blocking resolution if the socket can't be written
+/// to or the background task can't keep up.
+#[derive(Debug, Clone)]
+pub struct DnstapLogger {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl DnstapLogger {
+    /// Create a new logger writing to the Unix socket at `path`, spawning the task that
+    /// writes to it on the current Tokio runtime.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write_frames(path.as_ref().to_path_buf(), rx));
+        DnstapLogger { tx }
+    }
+
+    /// Log a query forwarded to `upstream` using `protocol` and its response, both in wire format.
+    pub fn log(
+        &self,
+        upstream: SocketAddr,
+        protocol: SocketProtocol,
+        query: &[u8],
+        query_time: SystemTime,
+        response: &[u8],
+    ) {
+        let response_time = SystemTime::now();
+        for frame in [
+            encode_dnstap(
+                MESSAGE_TYPE_FORWARDER_QUERY,
+                upstream,
+                protocol,
+                query_time,
+                query,
+                None,
+            ),
+            encode_dnstap(
+                MESSAGE_TYPE_FORWARDER_RESPONSE,
+                upstream,
+                protocol,
+                query_time,
+                query,
+                Some((response_time, response)),
+            ),
+        ] {
+            if self.tx.try_send(frame).is_err() {
+                tracing::debug!(message = "dropped dnstap message, queue full or closed");
+            }
+        }
+    }
+}
+
+async fn write_frames(path: PathBuf, mut rx: mpsc::Receiver<Vec<u8>>) {
+    let mut stream: Option<UnixStream> = None;
+
+    while let Some(frame) = rx.recv().await {
+        if stream.is_none() {
+            match connect(&path).await {
+                Ok(s) => stream = Some(s),
+                Err(e) => {
+                    tracing::warn!(message = "unable to connect to dnstap socket", path = ?path, error = %e);
+                    continue;
+                }
+            }
+        }
+
+        if let Some(s) = stream.as_mut() {
+            let mut buf = Vec::with_capacity(frame.len() + 4);
+            buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&frame);
+
+            if let Err(e) = s.write_all(&buf).await {
+                tracing::warn!(message = "unable to write to dnstap socket", path = ?path, error = %e);
+                stream = None;
+            }
+        }
+    }
+}
+
+/// Connect to a Frame Streams receiver and perform the bidirectional handshake
+async fn connect(path: &Path) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream.write_all(&control_frame(FSTRM_CONTROL_READY)).await?;
+
+    let escape = stream.read_u32().await?;
+    let len = stream.read_u32().await?;
+    let mut control = vec![0; len as usize];
+    stream.read_exact(&mut control).await?;
+
+    if escape != 0 || control.len() < 4 || control[0..4] != FSTRM_CONTROL_ACCEPT.to_be_bytes() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected ACCEPT control frame",
+        ));
+    }
+
+    stream.write_all(&control_frame(FSTRM_CONTROL_START)).await?;
+    Ok(stream)
+}
+
+/// Build a Frame Streams control frame of the given type including our content type
+fn control_frame(kind: u32) -> Vec<u8> {
+    let mut control = Vec::new();
+    control.extend_from_slice(&kind.to_be_bytes());
+    control.extend_from_slice(&FSTRM_FIELD_CONTENT_TYPE.to_be_bytes());
+    control.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+    control.extend_from_slice(CONTENT_TYPE);
+
+    let mut frame = Vec::new();
+    // A length of zero is the escape sequence indicating a control frame follows
+    frame.extend_from_slice(&0u32.to_be_bytes());
+    frame.extend_from_slice(&(control.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&control);
+    frame
+}
+
+/// Encode a `Dnstap` protobuf containing a single `Message`
+fn encode_dnstap(
+    kind: u64,
+    upstream: SocketAddr,
+    protocol: SocketProtocol,
+    query_time: SystemTime,
+    query: &[u8],
+    response: Option<(SystemTime, &[u8])>,
+) -> Vec<u8> {
+    let mut message = ProtoWriter::default();
+    message.varint(1, kind);
+
+    match upstream.ip() {
+        IpAddr::V4(ip) => {
+            message.varint(2, SOCKET_FAMILY_INET);
+            message.varint(3, protocol as u64);
+            message.bytes(5, &ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.varint(2, SOCKET_FAMILY_INET6);
+            message.varint(3, protocol as u64);
+            message.bytes(5, &ip.octets());
+        }
+    }
+
+    message.varint(7, upstream.port() as u64);
+    let (secs, nanos) = timestamp(query_time);
+    message.varint(8, secs);
+    message.fixed32(9, nanos);
+    message.bytes(10, query);
+
+    if let Some((time, bytes)) = response {
+        let (secs, nanos) = timestamp(time);
+        message.varint(12, secs);
+        message.fixed32(13, nanos);
+        message.bytes(14, bytes);
+    }
+
+    let mut dnstap = ProtoWriter::default();
+    dnstap.bytes(1, b"donut");
+    dnstap.bytes(2, env!("CARGO_PKG_VERSION").as_bytes());
+    dnstap.bytes(14, &message.buf);
+    dnstap.varint(15, DNSTAP_TYPE_MESSAGE);
+    dnstap.buf
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since.as_secs(), since.subsec_nanos())
+}
+
+/// Minimal protobuf encoder supporting only the field types used by dnstap
+#[derive(Debug, Default)]
+struct ProtoWriter {
+    buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    const WIRE_VARINT: u64 = 0;
+    const WIRE_LENGTH_DELIMITED: u64 = 2;
+    const WIRE_FIXED32: u64 = 5;
+
+    fn varint(&mut self, field: u64, v: u64) {
+        self.raw_varint(field << 3 | Self::WIRE_VARINT);
+        self.raw_varint(v);
+    }
+
+    fn fixed32(&mut self, field: u64, v: u32) {
+        self.raw_varint(field << 3 | Self::WIRE_FIXED32);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, v: &[u8]) {
+        self.raw_varint(field << 3 | Self::WIRE_LENGTH_DELIMITED);
+        self.raw_varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    fn raw_varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+
+        self.buf.push(v as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        control_frame, DnstapLogger, SocketProtocol, FSTRM_CONTROL_ACCEPT, FSTRM_CONTROL_READY, FSTRM_CONTROL_START,
+    };
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Read a control frame, returning its type
+    async fn read_control(stream: &mut UnixStream) -> u32 {
+        assert_eq!(0, stream.read_u32().await.unwrap());
+        let len = stream.read_u32().await.unwrap();
+        let mut control = vec![0; len as usize];
+        stream.read_exact(&mut control).await.unwrap();
+        u32::from_be_bytes([control[0], control[1], control[2], control[3]])
+    }
+
+    async fn read_data(stream: &mut UnixStream) -> Vec<u8> {
+        let len = stream.read_u32().await.unwrap();
+        let mut frame = vec![0; len as usize];
+        stream.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[tokio::test]
+    async fn test_log_query_and_response_frames() {
+        let path = std::env::temp_dir().join(format!("donut-dnstap-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let upstream = SocketAddr::from(([127, 0, 0, 1], 853));
+        let query = b"example query bytes";
+        let response = b"example response bytes";
+        let logger = DnstapLogger::new(&path);
+        logger.log(upstream, SocketProtocol::Dot, query, SystemTime::now(), response);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(FSTRM_CONTROL_READY, read_control(&mut stream).await);
+        stream.write_all(&control_frame(FSTRM_CONTROL_ACCEPT)).await.unwrap();
+        assert_eq!(FSTRM_CONTROL_START, read_control(&mut stream).await);
+
+        let query_frame = read_data(&mut stream).await;
+        let response_frame = read_data(&mut stream).await;
+        fs::remove_file(&path).unwrap();
+
+        // Socket protocol is field 3 of the message, a varint
+        let protocol = [3 << 3, SocketProtocol::Dot as u8];
+        assert!(contains(&query_frame, &protocol));
+        assert!(contains(&query_frame, query));
+        assert!(!contains(&query_frame, response));
+
+        assert!(contains(&response_frame, &protocol));
+        assert!(contains(&response_frame, query));
+        assert!(contains(&response_frame, response));
+    }
+}
@@ -16,30 +16,152 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
+use crate::cache::ResponseCache;
+use crate::listen::{ClientAddr, ClientLimiter, ClientPermit, DropConnection};
+use crate::metrics::Metrics;
 use crate::request::{RequestParserJsonGet, RequestParserWireGet, RequestParserWirePost};
-use crate::resolve::UdpResolver;
-use crate::response::{ResponseEncoderJson, ResponseEncoderWire, ResponseMetadata};
-use crate::types::{DonutError, ErrorKind};
-use bytes::Bytes;
-use futures_util::TryFutureExt;
-use serde::{Deserialize, Serialize};
+use crate::resolve::{AddressPrefix, Maintenance, Resolver, ResolverOptions, SourceAddrs, UpstreamResolver};
+use crate::response::{ResponseEncoderJson, ResponseEncoderText, ResponseEncoderWire, ResponseMetadata};
+use crate::types::{DonutError, DonutResult, ErrorKind};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{Stream, StreamExt, TryFutureExt};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
 use std::sync::Arc;
-use tracing::{span, Instrument, Level};
-use warp::http::header::ACCEPT;
-use warp::http::{HeaderValue, StatusCode};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{span, Instrument, Level, Span};
+use trust_dns_client::op::DnsResponse;
+use trust_dns_client::proto::rr::rdata::opt::EdnsCode;
+use trust_dns_client::proto::xfer::DnsRequest;
+use trust_dns_client::rr::Name;
+use warp::http::header::{HeaderName, ACCEPT};
+use warp::http::{HeaderMap, HeaderValue, StatusCode};
 use warp::{Filter, Rejection, Reply};
 
 const WIRE_MESSAGE_FORMAT: &str = "application/dns-message";
 const JSON_MESSAGE_FORMAT: &str = "application/dns-json";
+const JSON_ALIAS_FORMAT: &str = "application/json";
+const TEXT_MESSAGE_FORMAT: &str = "text/dns";
+const PROMETHEUS_TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+const QUERY_PATH: &str = "/dns-query";
+const MAX_TENANT_LENGTH: usize = 64;
+const MAX_CACHE_LISTING: usize = 1000;
 
+/// Default amount of time to reuse the result of a health check for
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Artificial latency and errors added before resolving queries.
+///
+/// This is for testing how clients handle a slow or unreliable server and isn't meant to
+/// be used in production.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Chaos {
+    delay: Option<Duration>,
+    error_rate: f64,
+}
+
+impl Chaos {
+    /// Delay each query by `delay` (if set) and fail a fraction of them, between 0.0 and 1.0,
+    /// with `ErrorKind::Unavailable` based on `error_rate`. Failures are injected before the
+    /// cache is used so they're never answered with a stale response.
+    pub fn new(delay: Option<Duration>, error_rate: f64) -> Self {
+        Chaos {
+            delay,
+            error_rate: error_rate.clamp(0.0, 1.0),
+        }
+    }
+
+    async fn inject(&self) -> DonutResult<()> {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        if self.error_rate > 0.0 && rand::random::<f64>() < self.error_rate {
+            return Err(DonutError::from((ErrorKind::Unavailable, "injected error")));
+        }
+
+        Ok(())
+    }
+}
+
+/// How to respond to requests denied by rate limits or access control.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DenyAction {
+    /// Respond with an error status code (403 or 429)
+    #[default]
+    Reject,
+    /// Close the connection without responding. This only works when serving
+    /// requests with `listen::serve`, otherwise it's the same as `Reject`.
+    Drop,
+}
+
+impl DenyAction {
+    /// Mark the response to a denied request to be dropped instead of sent, if configured
+    fn apply(&self, mut res: warp::reply::Response) -> warp::reply::Response {
+        if *self == DenyAction::Drop {
+            res.extensions_mut().insert(DropConnection);
+        }
+
+        res
+    }
+}
+
+impl FromStr for DenyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(DenyAction::Reject),
+            "drop" => Ok(DenyAction::Drop),
+            _ => Err(format!("expected 'reject' or 'drop', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for DenyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenyAction::Reject => write!(f, "reject"),
+            DenyAction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Result of the most recent health check and when it was performed
+#[derive(Debug)]
+struct HealthState {
+    checked: Instant,
+    healthy: bool,
+}
+
+/// Parsers, resolver, and encoders shared by all DNS-over-HTTPS request handlers.
+///
+/// A context is meant to be created once, wrapped in an `Arc`, and passed to each of
+/// the filter functions in this module (`json_get`, `text_get`, `wire_get`, `wire_post`). See
+/// `examples/embedded.rs` for combining these filters with other routes.
 #[derive(Debug)]
 pub struct HandlerContext {
     json_parser: RequestParserJsonGet,
     get_parser: RequestParserWireGet,
     post_parser: RequestParserWirePost,
-    resolver: UdpResolver,
+    resolver: Resolver,
     json_encoder: ResponseEncoderJson,
     wire_encoder: ResponseEncoderWire,
+    text_encoder: ResponseEncoderText,
+    tenant_header: Option<HeaderName>,
+    cache: Option<ResponseCache>,
+    metrics: Metrics,
+    health_interval: Duration,
+    health: Mutex<Option<HealthState>>,
+    client_limiter: Option<ClientLimiter>,
+    maintenance: Maintenance,
+    chaos: Option<Chaos>,
+    deny_action: DenyAction,
+    json_envelope: bool,
 }
 
 impl HandlerContext {
@@ -47,18 +169,500 @@ impl HandlerContext {
         json_parser: RequestParserJsonGet,
         get_parser: RequestParserWireGet,
         post_parser: RequestParserWirePost,
-        resolver: UdpResolver,
+        resolver: impl Into<Resolver>,
         json_encoder: ResponseEncoderJson,
         wire_encoder: ResponseEncoderWire,
+        text_encoder: ResponseEncoderText,
     ) -> Self {
         HandlerContext {
             json_parser,
             get_parser,
             post_parser,
-            resolver,
+            resolver: resolver.into(),
             json_encoder,
             wire_encoder,
+            text_encoder,
+            tenant_header: None,
+            cache: None,
+            metrics: Metrics::default(),
+            health_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            health: Mutex::new(None),
+            client_limiter: None,
+            maintenance: Maintenance::default(),
+            chaos: None,
+            deny_action: DenyAction::default(),
+            json_envelope: false,
+        }
+    }
+
+    /// Create a context using the default parsers and encoders that forwards queries to an
+    /// upstream server over UDP.
+    ///
+    /// This is the simplest way to embed Donut. The upstream client is created the same way as
+    /// `resolve::new_udp_client` so this must be called from within a Tokio runtime. Use `new`
+    /// for anything else (TLS upstreams, resolver options, non-default parsers or encoders).
+    pub async fn from_upstream(addr: SocketAddr, timeout: Duration) -> DonutResult<Self> {
+        let client = crate::resolve::new_udp_client(addr, SourceAddrs::default(), timeout).await?;
+        let options = ResolverOptions {
+            timeout: Some(timeout),
+            ..ResolverOptions::default()
+        };
+
+        Ok(HandlerContext::new(
+            RequestParserJsonGet::default(),
+            RequestParserWireGet::default(),
+            RequestParserWirePost::default(),
+            UpstreamResolver::new(client, addr, options),
+            ResponseEncoderJson::default(),
+            ResponseEncoderWire::new(),
+            ResponseEncoderText::new(),
+        ))
+    }
+
+    /// Reject queries from clients that already have this many queries being handled with
+    /// `429 Too Many Requests`. Clients are identified by the IP address of their connection
+    /// which is only known when serving requests with `listen::serve`.
+    pub fn with_max_concurrent_per_client(mut self, max: NonZeroUsize) -> Self {
+        self.client_limiter = Some(ClientLimiter::new(max));
+        self
+    }
+
+    /// Count a query towards the limit of its client, if there is a limit and the client is known
+    fn client_permit(&self, client: Option<ClientAddr>) -> DonutResult<Option<ClientPermit>> {
+        match (&self.client_limiter, client) {
+            (Some(limiter), Some(ClientAddr(addr))) => limiter.acquire(addr.ip()).map(Some).ok_or_else(|| {
+                DonutError::from((ErrorKind::TooManyRequests, "too many concurrent queries from client"))
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    /// Answer every request with the response of this maintenance mode while it's on
+    pub fn with_maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
+
+    /// Maintenance mode used to answer requests, off unless turned on
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.maintenance
+    }
+
+    /// Respond to requests over the `with_max_concurrent_per_client` limit according to `action`
+    pub fn with_deny_action(mut self, action: DenyAction) -> Self {
+        self.deny_action = action;
+        self
+    }
+
+    /// Add latency or errors to requests before resolving them, for testing clients only
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Wrap JSON responses in an envelope with metadata about how they were resolved unless
+    /// requests opt out with `envelope=false`
+    pub fn with_json_envelope(mut self, enabled: bool) -> Self {
+        self.json_envelope = enabled;
+        self
+    }
+
+    /// Answer requests from this cache when possible, adding responses from the resolver to it
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Cache used to answer requests, if enabled
+    pub fn cache(&self) -> Option<&ResponseCache> {
+        self.cache.as_ref()
+    }
+
+    /// Record metrics about requests here instead of using the default settings
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Metrics about requests handled using this context
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Reuse the result of health checks for this amount of time instead of checking every time
+    pub fn with_health_interval(mut self, interval: Duration) -> Self {
+        self.health_interval = interval;
+        self
+    }
+
+    /// Check if the resolver is working, reusing the result of a recent check if there is one
+    async fn check_health(&self) -> bool {
+        // Hold the lock while checking so that concurrent requests share a single check
+        let mut state = self.health.lock().await;
+        if let Some(s) = state.as_ref().filter(|s| s.checked.elapsed() < self.health_interval) {
+            return s.healthy;
+        }
+
+        let healthy = match self.resolver.check_health().await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!(message = "health check failed", error = %e);
+                false
+            }
+        };
+
+        *state = Some(HealthState {
+            checked: Instant::now(),
+            healthy,
+        });
+        healthy
+    }
+
+    /// Resolve a request using the cache if enabled or the resolver otherwise
+    async fn resolve(&self, req: DnsRequest) -> DonutResult<DnsResponse> {
+        self.resolve_with_meta(req).await.map(|(_, res)| res)
+    }
+
+    /// Resolve a request the same way as `resolve`, also returning how it was resolved
+    async fn resolve_with_meta(&self, req: DnsRequest) -> DonutResult<(ResolutionMeta, DnsResponse)> {
+        let start = Instant::now();
+        let mut meta = ResolutionMeta {
+            upstream: None,
+            cache: if self.cache.is_some() { "miss" } else { "disabled" },
+            latency_ms: 0,
+        };
+
+        if let Some(res) = self.maintenance.answer(&req) {
+            tracing::debug!(message = "answered query in maintenance mode", id = req.id());
+            self.metrics.record_response(res.response_code());
+            meta.latency_ms = start.elapsed().as_millis() as u64;
+            return Ok((meta, res));
+        }
+
+        // Injected errors happen before the cache is consulted, unlike real errors from the
+        // resolver, so that clients being tested see them even for cached or stale answers.
+        if let Some(chaos) = &self.chaos {
+            chaos
+                .inject()
+                .await
+                .inspect_err(|e| self.metrics.record_error(e.kind()))?;
+        }
+
+        if let Some(res) = self.cache.as_ref().and_then(|c| c.get(&req)) {
+            tracing::debug!(message = "answered query from cache", id = req.id());
+            self.metrics.record_response(res.response_code());
+            meta.cache = "hit";
+            meta.latency_ms = start.elapsed().as_millis() as u64;
+            return Ok((meta, self.resolver.reorder(res)));
+        }
+
+        // Cache responses before reordering them so that answers from the cache are still
+        // rotated or shuffled for every request instead of being frozen until they expire.
+        let resolve_start = Instant::now();
+        let res = self.resolver.resolve_cacheable(req.clone()).await;
+        self.metrics.observe_latency(resolve_start.elapsed());
+
+        let (upstream, res) = match res {
+            Ok(r) => r,
+            Err(e) => {
+                self.metrics.record_error(e.kind());
+                return match self.stale_answer(&req, &e) {
+                    Some(res) => {
+                        meta.cache = "stale";
+                        meta.latency_ms = start.elapsed().as_millis() as u64;
+                        Ok((meta, res))
+                    }
+                    None => Err(e),
+                };
+            }
+        };
+
+        self.metrics.record_response(res.response_code());
+        if let Some(cache) = &self.cache {
+            cache.insert(&req, &res);
+        }
+
+        meta.upstream = upstream.map(|a| a.to_string());
+        meta.latency_ms = start.elapsed().as_millis() as u64;
+        Ok((meta, self.resolver.reorder(res)))
+    }
+
+    /// Find an expired response to answer a request with when the resolver fails, if it failed
+    /// because of the upstream server (not the request) and the cache has one (RFC 8767)
+    fn stale_answer(&self, req: &DnsRequest, err: &DonutError) -> Option<DnsResponse> {
+        if !matches!(
+            err.kind(),
+            ErrorKind::Timeout | ErrorKind::Unavailable | ErrorKind::Internal
+        ) {
+            return None;
+        }
+
+        let res = self.cache.as_ref()?.get_stale(req)?;
+        tracing::warn!(message = "answered query with stale response from cache", id = req.id(), error = %err);
+        self.metrics.record_response(res.response_code());
+        Some(self.resolver.reorder(res))
+    }
+
+    /// Encode a response as JSON, wrapping it in an envelope with `meta` if `envelope` is set
+    async fn encode_json(
+        &self,
+        res: DnsResponse,
+        meta: ResolutionMeta,
+        raw: bool,
+        envelope: bool,
+    ) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
+        if !envelope {
+            return self.json_encoder.encode(res, raw).await;
         }
+
+        const PREFIX: &[u8] = b"{\"meta\":";
+        const SEPARATOR: &[u8] = b",\"response\":";
+        const SUFFIX: &[u8] = b"}";
+
+        let meta = serde_json::to_vec(&meta)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to serialize to response", Box::new(e))))?;
+
+        // Padding (if enabled) has to account for the envelope so that it's the size of the whole
+        // body that's a multiple of the block size, not just the response inside it.
+        let overhead = PREFIX.len() + meta.len() + SEPARATOR.len() + SUFFIX.len();
+        let (res_meta, body) = self.json_encoder.encode_enclosed(res, raw, overhead).await?;
+
+        // Splice the already encoded response into the envelope instead of decoding and
+        // encoding it again.
+        let mut wrapped = Vec::with_capacity(overhead + body.len());
+        wrapped.extend_from_slice(PREFIX);
+        wrapped.extend_from_slice(&meta);
+        wrapped.extend_from_slice(SEPARATOR);
+        wrapped.extend_from_slice(&body);
+        wrapped.extend_from_slice(SUFFIX);
+
+        Ok((res_meta, wrapped))
+    }
+
+    /// Resolve a query the same way as JSON requests, recording the result of each step.
+    ///
+    /// Unlike other requests, nothing is added to the cache and metrics aren't recorded.
+    async fn trace(&self, q: JsonQuery) -> ResolutionTrace {
+        let mut trace = ResolutionTrace::default();
+        let start = Instant::now();
+
+        let req = match self
+            .json_parser
+            .parse(
+                q.name,
+                q.kind,
+                q.checking_disabled.unwrap_or(false),
+                q.dnssec_ok.unwrap_or(false),
+                q.client_subnet.as_deref(),
+            )
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                trace.parse_error = Some(e.to_string());
+                return trace;
+            }
+        };
+
+        trace.questions = req
+            .queries()
+            .iter()
+            .map(|q| format!("{} {} {}", q.name(), q.query_class(), q.query_type()))
+            .collect();
+
+        if let Some(res) = self.maintenance.answer(&req) {
+            trace.maintenance = true;
+            trace.set_response(&res, start);
+            return trace;
+        }
+
+        if let Some(cache) = &self.cache {
+            if let Some(res) = cache.get(&req) {
+                trace.cache = "hit";
+                trace.set_response(&self.resolver.reorder(res), start);
+                return trace;
+            }
+
+            trace.cache = "miss";
+        }
+
+        match self.resolver.resolve_with_upstream(req).await {
+            Ok((upstream, res)) => {
+                trace.upstream = upstream.map(|a| a.to_string());
+                trace.set_response(&res, start);
+            }
+            Err(e) => {
+                trace.error = Some(e.to_string());
+                trace.latency_ms = Some(start.elapsed().as_millis() as u64);
+            }
+        }
+
+        trace
+    }
+
+    /// Include the value of this request header as the `tenant` of the span each request
+    /// is handled in (and hence each event logged while resolving it).
+    pub fn with_tenant_header(mut self, name: HeaderName) -> Self {
+        self.tenant_header = Some(name);
+        self
+    }
+
+    /// Create the span to handle a request in, including the tenant if configured
+    fn request_span(&self, headers: &HeaderMap) -> Span {
+        let tenant = self
+            .tenant_header
+            .as_ref()
+            .and_then(|h| headers.get(h))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                // Keep the number of distinct values (and the size of each) bounded regardless
+                // of what clients send: anything after the maximum length is dropped.
+                v.chars().take(MAX_TENANT_LENGTH).collect::<String>()
+            });
+
+        match tenant {
+            Some(t) => span!(Level::DEBUG, "donut_request", tenant = %t),
+            None => span!(Level::DEBUG, "donut_request"),
+        }
+    }
+}
+
+/// How a query was resolved, included in the envelope of JSON responses when enabled
+#[derive(Debug, Serialize)]
+struct ResolutionMeta {
+    /// Upstream server the query was forwarded to, if it wasn't answered locally
+    upstream: Option<String>,
+    /// If the query was answered from the cache: "hit", "miss", "stale", or "disabled"
+    cache: &'static str,
+    /// Time taken to resolve the query, not including parsing or encoding
+    latency_ms: u64,
+}
+
+/// Result of each step of resolving a query, returned by `GET /trace` requests
+#[derive(Debug, Serialize)]
+struct ResolutionTrace {
+    /// Error parsing the query, no other steps are performed if it couldn't be parsed
+    parse_error: Option<String>,
+    /// Questions of the parsed query in the form `<name> <class> <type>`
+    questions: Vec<String>,
+    /// If the query was answered with the maintenance response
+    maintenance: bool,
+    /// If the query was answered from the cache: "hit", "miss", or "disabled"
+    cache: &'static str,
+    /// Upstream server the query was forwarded to, if it wasn't answered locally
+    upstream: Option<String>,
+    /// Time taken to resolve the query, including parsing
+    latency_ms: Option<u64>,
+    /// Error resolving the query (timeouts, network errors, etc.)
+    error: Option<String>,
+    /// If the response was DNSSEC validated by the upstream server (the AD flag)
+    validated: Option<bool>,
+    response_code: Option<u16>,
+    num_answers: Option<usize>,
+}
+
+impl ResolutionTrace {
+    fn set_response(&mut self, res: &DnsResponse, start: Instant) {
+        self.latency_ms = Some(start.elapsed().as_millis() as u64);
+        self.validated = Some(res.authentic_data());
+        self.response_code = Some(u16::from(res.response_code()));
+        self.num_answers = Some(res.answers().len());
+    }
+}
+
+impl Default for ResolutionTrace {
+    fn default() -> Self {
+        ResolutionTrace {
+            parse_error: None,
+            questions: Vec::new(),
+            maintenance: false,
+            cache: "disabled",
+            upstream: None,
+            latency_ms: None,
+            error: None,
+            validated: None,
+            response_code: None,
+            num_answers: None,
+        }
+    }
+}
+
+/// Self-describing document that clients can use to configure themselves for this server
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerMetadata {
+    path: &'static str,
+    formats: Vec<FormatMetadata>,
+    max_message_size: usize,
+    max_labels: u8,
+}
+
+impl ServerMetadata {
+    pub fn new(max_labels: u8) -> Self {
+        ServerMetadata {
+            path: QUERY_PATH,
+            formats: vec![
+                FormatMetadata::new(WIRE_MESSAGE_FORMAT, vec!["GET", "POST"]),
+                FormatMetadata::new(JSON_MESSAGE_FORMAT, vec!["GET"]),
+                FormatMetadata::new(JSON_ALIAS_FORMAT, vec!["GET"]),
+                FormatMetadata::new(TEXT_MESSAGE_FORMAT, vec!["GET"]),
+            ],
+            max_message_size: crate::MAX_MESSAGE_SIZE,
+            max_labels,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FormatMetadata {
+    content_type: &'static str,
+    methods: Vec<&'static str>,
+}
+
+impl FormatMetadata {
+    fn new(content_type: &'static str, methods: Vec<&'static str>) -> Self {
+        FormatMetadata { content_type, methods }
+    }
+}
+
+/// Static header added to every HTTP response, parsed from the form `<name>=<value>`
+#[derive(Debug, Clone)]
+pub struct ResponseHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl ResponseHeader {
+    pub fn new(name: HeaderName, value: HeaderValue) -> Self {
+        ResponseHeader { name, value }
+    }
+
+    /// Build a map of headers suitable for use with `warp::reply::with::headers`
+    pub fn to_map(headers: &[ResponseHeader]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for h in headers {
+            map.append(h.name.clone(), h.value.clone());
+        }
+
+        map
+    }
+}
+
+impl FromStr for ResponseHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected '<name>=<value>', got '{}'", s))?;
+        let name = HeaderName::from_str(name.trim()).map_err(|e| format!("invalid header name: {}", e))?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|e| format!("invalid header value: {}", e))?;
+        Ok(ResponseHeader::new(name, value))
+    }
+}
+
+impl fmt::Display for ResponseHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value.to_str().unwrap_or_default())
     }
 }
 
@@ -70,6 +674,66 @@ struct JsonQuery {
     kind: String,
     #[serde(alias = "cd")]
     checking_disabled: Option<bool>,
+    #[serde(alias = "do")]
+    dnssec_ok: Option<bool>,
+    #[serde(alias = "edns_client_subnet")]
+    client_subnet: Option<String>,
+    #[serde(alias = "raw")]
+    raw: Option<bool>,
+    #[serde(alias = "envelope", default, deserialize_with = "deserialize_flag")]
+    envelope: Option<bool>,
+}
+
+/// Parse an optional boolean query parameter given as `true`/`false` or `1`/`0`
+fn deserialize_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|v| match v.as_str() {
+            "true" | "1" => Ok(true),
+            "false" | "0" => Ok(false),
+            _ => Err(de::Error::custom(format!(
+                "expected 'true', 'false', '1', or '0', got '{}'",
+                v
+            ))),
+        })
+        .transpose()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MaintenanceQuery {
+    enabled: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct MaintenanceState {
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFlushQuery {
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheFlushState {
+    removed: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheContents {
+    /// Number of entries in the cache, which may be more than are listed
+    total: usize,
+    entries: Vec<CacheContentsEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheContentsEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    ttl: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -78,15 +742,36 @@ struct WireGetQuery {
     dns: String,
 }
 
+/// Body of error responses to JSON requests
+#[derive(Debug, Serialize)]
+struct JsonError {
+    error: String,
+    status: u16,
+}
+
 #[derive(Debug)]
 struct DnsResponseReply {
     result: Result<(ResponseMetadata, Vec<u8>), DonutError>,
     content_type: &'static str,
+    deny_action: DenyAction,
 }
 
 impl DnsResponseReply {
     fn new(result: Result<(ResponseMetadata, Vec<u8>), DonutError>, content_type: &'static str) -> Self {
-        DnsResponseReply { result, content_type }
+        DnsResponseReply {
+            result,
+            content_type,
+            deny_action: DenyAction::Reject,
+        }
+    }
+
+    /// Reply to a request that was denied before being handled, according to `action`
+    fn denied(err: DonutError, content_type: &'static str, action: DenyAction) -> Self {
+        DnsResponseReply {
+            result: Err(err),
+            content_type,
+            deny_action: action,
+        }
     }
 
     fn success(content_type: &'static str, meta: ResponseMetadata, bytes: Vec<u8>) -> warp::reply::Response {
@@ -96,7 +781,13 @@ impl DnsResponseReply {
         headers.insert(warp::http::header::CONTENT_TYPE, HeaderValue::from_static(content_type));
 
         if let Some(ttl) = meta.min_ttl() {
-            let caching = HeaderValue::from_maybe_shared(format!("max-age={}", ttl)).unwrap();
+            // A TTL of zero means the response must not be cached at all, not just
+            // that it's immediately stale, so tell HTTP caches not to store it.
+            let caching = if ttl == 0 {
+                HeaderValue::from_static("max-age=0, no-store")
+            } else {
+                HeaderValue::from_maybe_shared(format!("max-age={}", ttl)).unwrap()
+            };
             headers.insert(warp::http::header::CACHE_CONTROL, caching);
         }
 
@@ -108,7 +799,9 @@ impl DnsResponseReply {
             ErrorKind::InputInvalid => StatusCode::BAD_REQUEST,
             ErrorKind::InputBodyTooLong => StatusCode::PAYLOAD_TOO_LARGE,
             ErrorKind::InputUriTooLong => StatusCode::URI_TOO_LONG,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
             ErrorKind::Timeout => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
             ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -119,7 +812,23 @@ impl DnsResponseReply {
             error_msg = %err,
         );
 
-        status_code.into_response()
+        // JSON clients are more likely to be people debugging things so tell them what went
+        // wrong. Other formats get an empty body since there's no way to represent errors in them.
+        match content_type {
+            JSON_MESSAGE_FORMAT | JSON_ALIAS_FORMAT => {
+                let body = JsonError {
+                    error: err.to_string(),
+                    status: status_code.as_u16(),
+                };
+
+                let mut res = warp::http::Response::new(serde_json::to_vec(&body).unwrap_or_default().into());
+                *res.status_mut() = status_code;
+                res.headers_mut()
+                    .insert(warp::http::header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+                res
+            }
+            _ => status_code.into_response(),
+        }
     }
 }
 
@@ -127,81 +836,857 @@ impl Reply for DnsResponseReply {
     fn into_response(self) -> warp::reply::Response {
         match self.result {
             Ok((meta, bytes)) => Self::success(self.content_type, meta, bytes),
-            Err(e) => Self::error(self.content_type, e),
+            Err(e) => self.deny_action.apply(Self::error(self.content_type, e)),
         }
     }
 }
 
-pub fn json_get(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+/// Filter for `GET /dns-query` requests using the JSON format (`Accept: application/dns-json`)
+///
+/// Requests with `Accept: application/json` are handled the same way. Responses to these use
+/// the `application/dns-json` content type unless `match_accept` is set, in which case they use
+/// `application/json` to match the request.
+pub fn json_get(
+    context: Arc<HandlerContext>,
+    match_accept: bool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let alias_format = if match_accept {
+        JSON_ALIAS_FORMAT
+    } else {
+        JSON_MESSAGE_FORMAT
+    };
+
     warp::path("dns-query")
         .and(warp::filters::method::get())
-        .and(warp::header::exact_ignore_case(ACCEPT.as_str(), JSON_MESSAGE_FORMAT))
+        .and(
+            warp::header::exact_ignore_case(ACCEPT.as_str(), JSON_MESSAGE_FORMAT)
+                .map(|| JSON_MESSAGE_FORMAT)
+                .or(warp::header::exact_ignore_case(ACCEPT.as_str(), JSON_ALIAS_FORMAT).map(move || alias_format))
+                .unify(),
+        )
         .and(warp::query::query::<JsonQuery>())
-        .and_then(move |q: JsonQuery| {
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<ClientAddr>())
+        .and_then(
+            move |content_type: &'static str, q: JsonQuery, headers: HeaderMap, client: Option<ClientAddr>| {
+                let context = context.clone();
+                let span = context.request_span(&headers);
+                async move {
+                    context.metrics.record_query("GET", "json");
+                    let _permit = match context.client_permit(client) {
+                        Ok(p) => p,
+                        Err(e) => return Ok(DnsResponseReply::denied(e, content_type, context.deny_action)),
+                    };
+
+                    let r = context
+                        .json_parser
+                        .parse(
+                            q.name,
+                            q.kind,
+                            q.checking_disabled.unwrap_or(false),
+                            q.dnssec_ok.unwrap_or(false),
+                            q.client_subnet.as_deref(),
+                        )
+                        .instrument(span!(Level::DEBUG, "donut_parser_json"))
+                        .and_then(|r| context.resolve_with_meta(r))
+                        .instrument(span!(Level::DEBUG, "donut_resolver_udp"))
+                        .and_then(|(meta, r)| {
+                            // Metadata includes the address of the upstream server so requests can
+                            // only opt out of the envelope, never opt in if it isn't enabled.
+                            let envelope = context.json_envelope && q.envelope.unwrap_or(true);
+                            context.encode_json(r, meta, q.raw.unwrap_or(false), envelope)
+                        })
+                        .instrument(span!(Level::DEBUG, "donut_encoder_json"))
+                        .await;
+
+                    if let Ok((_, bytes)) = &r {
+                        context.metrics.record_response_bytes("json", bytes.len());
+                    }
+
+                    Ok::<DnsResponseReply, Rejection>(DnsResponseReply::new(r, content_type))
+                }
+                .instrument(span)
+            },
+        )
+}
+
+/// Filter for `GET /dns-query` requests using a dig-like text format (`Accept: text/dns`)
+///
+/// Queries use the same parameters as the JSON format (`name`, `type`, `cd`, and `do`).
+pub fn text_get(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path("dns-query")
+        .and(warp::filters::method::get())
+        .and(warp::header::exact_ignore_case(ACCEPT.as_str(), TEXT_MESSAGE_FORMAT))
+        .and(warp::query::query::<JsonQuery>())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<ClientAddr>())
+        .and_then(move |q: JsonQuery, headers: HeaderMap, client: Option<ClientAddr>| {
             let context = context.clone();
+            let span = context.request_span(&headers);
             async move {
+                context.metrics.record_query("GET", "text");
+                let _permit = match context.client_permit(client) {
+                    Ok(p) => p,
+                    Err(e) => return Ok(DnsResponseReply::denied(e, TEXT_MESSAGE_FORMAT, context.deny_action)),
+                };
+
                 let r = context
                     .json_parser
-                    .parse(q.name, q.kind, q.checking_disabled.unwrap_or(false))
+                    .parse(
+                        q.name,
+                        q.kind,
+                        q.checking_disabled.unwrap_or(false),
+                        q.dnssec_ok.unwrap_or(false),
+                        q.client_subnet.as_deref(),
+                    )
                     .instrument(span!(Level::DEBUG, "donut_parser_json"))
-                    .and_then(|r| context.resolver.resolve(r))
+                    .and_then(|r| context.resolve(r))
                     .instrument(span!(Level::DEBUG, "donut_resolver_udp"))
-                    .and_then(|r| context.json_encoder.encode(r))
-                    .instrument(span!(Level::DEBUG, "donut_encoder_json"))
+                    .and_then(|r| context.text_encoder.encode(r))
+                    .instrument(span!(Level::DEBUG, "donut_encoder_text"))
                     .await;
 
-                Ok::<DnsResponseReply, Rejection>(DnsResponseReply::new(r, JSON_MESSAGE_FORMAT))
+                if let Ok((_, bytes)) = &r {
+                    context.metrics.record_response_bytes("text", bytes.len());
+                }
+
+                Ok::<DnsResponseReply, Rejection>(DnsResponseReply::new(r, TEXT_MESSAGE_FORMAT))
             }
+            .instrument(span)
         })
 }
 
+/// Filter for `GET /dns-query` requests using the wire format (`Accept: application/dns-message`)
 pub fn wire_get(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path("dns-query")
         .and(warp::filters::method::get())
         .and(warp::header::exact_ignore_case(ACCEPT.as_str(), WIRE_MESSAGE_FORMAT))
         .and(warp::query::query::<WireGetQuery>())
-        .and_then(move |q: WireGetQuery| {
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<ClientAddr>())
+        .and_then(move |q: WireGetQuery, headers: HeaderMap, client: Option<ClientAddr>| {
             let context = context.clone();
+            let span = context.request_span(&headers);
             async move {
+                context.metrics.record_query("GET", "wire");
+                let _permit = match context.client_permit(client) {
+                    Ok(p) => p,
+                    Err(e) => return Ok(DnsResponseReply::denied(e, WIRE_MESSAGE_FORMAT, context.deny_action)),
+                };
+
                 let r = context
                     .get_parser
                     .parse(q.dns)
                     .instrument(span!(Level::DEBUG, "donut_parser_get"))
-                    .and_then(|r| context.resolver.resolve(r))
+                    .and_then(|r| {
+                        let padding = requests_padding(&r);
+                        context.resolve(r).map_ok(move |r| (r, padding))
+                    })
                     .instrument(span!(Level::DEBUG, "donut_resolver_udp"))
-                    .and_then(|r| context.wire_encoder.encode(r))
+                    .and_then(|(r, padding)| context.wire_encoder.encode(r, padding))
                     .instrument(span!(Level::DEBUG, "donut_encoder_wire"))
                     .await;
 
+                if let Ok((_, bytes)) = &r {
+                    context.metrics.record_response_bytes("wire", bytes.len());
+                }
+
                 Ok::<DnsResponseReply, Rejection>(DnsResponseReply::new(r, WIRE_MESSAGE_FORMAT))
             }
+            .instrument(span)
         })
 }
 
+/// Filter for `POST /dns-query` requests using the wire format (`Accept: application/dns-message`)
 pub fn wire_post(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path("dns-query")
         .and(warp::filters::method::post())
         .and(warp::header::exact_ignore_case(ACCEPT.as_str(), WIRE_MESSAGE_FORMAT))
-        .and(warp::body::content_length_limit(crate::MAX_MESSAGE_SIZE as u64))
-        .and(warp::filters::body::bytes())
-        .and_then(move |body: Bytes| {
+        .and(warp::body::stream())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<ClientAddr>())
+        .and_then(move |body, headers: HeaderMap, client: Option<ClientAddr>| {
             let context = context.clone();
+            let span = context.request_span(&headers);
             async move {
-                let r = context
-                    .post_parser
-                    .parse(body)
+                context.metrics.record_query("POST", "wire");
+                let _permit = match context.client_permit(client) {
+                    Ok(p) => p,
+                    Err(e) => return Ok(DnsResponseReply::denied(e, WIRE_MESSAGE_FORMAT, context.deny_action)),
+                };
+
+                let r = read_body(body, crate::MAX_MESSAGE_SIZE)
+                    .and_then(|b| context.post_parser.parse(b))
                     .instrument(span!(Level::DEBUG, "donut_parser_post"))
-                    .and_then(|r| context.resolver.resolve(r))
+                    .and_then(|r| {
+                        let padding = requests_padding(&r);
+                        context.resolve(r).map_ok(move |r| (r, padding))
+                    })
                     .instrument(span!(Level::DEBUG, "donut_resolver_udp"))
-                    .and_then(|r| context.wire_encoder.encode(r))
+                    .and_then(|(r, padding)| context.wire_encoder.encode(r, padding))
                     .instrument(span!(Level::DEBUG, "donut_encoder_wire"))
                     .await;
 
+                if let Ok((_, bytes)) = &r {
+                    context.metrics.record_response_bytes("wire", bytes.len());
+                }
+
                 Ok::<DnsResponseReply, Rejection>(DnsResponseReply::new(r, WIRE_MESSAGE_FORMAT))
             }
+            .instrument(span)
         })
 }
 
+/// If the query includes an EDNS padding option, meaning the response should be padded too
+fn requests_padding(req: &DnsRequest) -> bool {
+    req.edns().is_some_and(|e| e.option(EdnsCode::Padding).is_some())
+}
+
+/// Read a request body, stopping once more than `limit` bytes have been read.
+///
+/// Bodies over the limit are rejected by the parser, the same as GET requests with messages
+/// that are too large, so there's no point in reading (and buffering) the rest of them.
+async fn read_body<S, B>(body: S, limit: usize) -> DonutResult<Bytes>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures_util::pin_mut!(body);
+    let mut buf = BytesMut::new();
+
+    while let Some(chunk) = body.next().await {
+        let mut chunk =
+            chunk.map_err(|e| DonutError::from((ErrorKind::InputInvalid, "unable to read request body", e)))?;
+        buf.put(&mut chunk);
+
+        if buf.len() > limit {
+            break;
+        }
+    }
+
+    Ok(buf.freeze())
+}
+
+/// Filter for `GET /.well-known/doh` requests, responding with metadata about this server
+pub fn metadata(meta: ServerMetadata) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(".well-known" / "doh")
+        .and(warp::filters::method::get())
+        .map(move || warp::reply::json(&meta))
+}
+
+/// Filter that responds `403 Forbidden` to requests from clients outside all of the `allowed`
+/// networks and rejects everything else so that it can be combined with other filters using
+/// `or`, e.g. `access_control(allowed, action).or(json_get(context))`. Denied requests are
+/// dropped instead when `action` is `DenyAction::Drop`.
+///
+/// All clients are allowed if there are no networks. Otherwise, clients are identified by
+/// the IP address of their connection which is only known when serving requests with
+/// `listen::serve`, unknown clients are denied.
+pub fn access_control(
+    allowed: Vec<AddressPrefix>,
+    action: DenyAction,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let allowed = Arc::new(allowed);
+
+    warp::ext::optional::<ClientAddr>().and_then(move |client: Option<ClientAddr>| {
+        let allowed = allowed.clone();
+        async move {
+            if allowed.is_empty() {
+                return Err(warp::reject::not_found());
+            }
+
+            match client {
+                Some(ClientAddr(addr)) if allowed.iter().any(|n| n.contains(addr.ip())) => {
+                    Err(warp::reject::not_found())
+                }
+                _ => {
+                    tracing::debug!(message = "denied request from client", client = ?client.map(|c| c.0));
+                    Ok(action.apply(StatusCode::FORBIDDEN.into_response()))
+                }
+            }
+        }
+    })
+}
+
+/// Filter for `GET /metrics` requests, responding with metrics in the Prometheus text format
+pub fn metrics(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("metrics").and(warp::filters::method::get()).map(move || {
+        let body = context.metrics.render(
+            &context.resolver.upstream_status(),
+            context.cache.as_ref().map(|c| c.len()),
+        );
+
+        warp::reply::with_header(body, warp::http::header::CONTENT_TYPE, PROMETHEUS_TEXT_FORMAT)
+    })
+}
+
+/// Filter for `GET /health` requests, responding with `200 OK` if the resolver is working
+/// or `503 Service Unavailable` if it isn't
+pub fn health(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("health")
+        .and(warp::filters::method::get())
+        .and_then(move || {
+            let context = context.clone();
+            async move {
+                let status = if context.check_health().await {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+
+                Ok::<StatusCode, Rejection>(status)
+            }
+        })
+}
+
+/// Filter for `POST /maintenance` requests, turning maintenance mode on or off
+///
+/// Maintenance mode is set to the value of the `enabled` parameter (`true` or `false`) or toggled
+/// if it isn't given. Responds with the new state as JSON. This should only be exposed to operators
+/// since it allows anyone that can reach it to stop queries from being resolved.
+pub fn maintenance(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("maintenance")
+        .and(warp::filters::method::post())
+        .and(warp::query::query::<MaintenanceQuery>())
+        .map(move |q: MaintenanceQuery| {
+            let enabled = match q.enabled {
+                Some(v) => {
+                    context.maintenance.set_enabled(v);
+                    v
+                }
+                None => context.maintenance.toggle(),
+            };
+
+            tracing::info!(message = "changed maintenance mode", enabled = enabled);
+            warp::reply::json(&MaintenanceState { enabled })
+        })
+}
+
+/// Filter for `POST /cache/flush` requests, removing responses from the cache
+///
+/// Only responses for the `name` parameter (of any type) are removed if it's given, otherwise
+/// the cache is emptied. Responds with the number of entries removed as JSON, zero if caching
+/// isn't enabled.
+pub fn cache_flush(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("cache" / "flush")
+        .and(warp::filters::method::post())
+        .and(warp::query::query::<CacheFlushQuery>())
+        .map(move |q: CacheFlushQuery| {
+            let name = match q.name.as_deref().map(Name::from_str).transpose() {
+                Ok(n) => n,
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            };
+
+            let removed = context.cache().map(|c| c.clear(name.as_ref())).unwrap_or(0);
+            tracing::info!(message = "flushed cache", name = ?q.name, removed = removed);
+            warp::reply::json(&CacheFlushState { removed }).into_response()
+        })
+}
+
+/// Filter for `GET /cache` requests, responding with the name, type, and remaining TTL of the
+/// most recently used entries in the cache as JSON
+///
+/// At most `MAX_CACHE_LISTING` entries are listed, along with the total number in the cache.
+/// The listing is empty if caching isn't enabled.
+pub fn cache_list(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("cache").and(warp::filters::method::get()).map(move || {
+        let (total, listing) = match context.cache() {
+            Some(c) => (c.len(), c.list(MAX_CACHE_LISTING)),
+            None => (0, Vec::new()),
+        };
+
+        let entries = listing
+            .into_iter()
+            .map(|e| CacheContentsEntry {
+                name: e.name,
+                kind: e.kind.to_string(),
+                ttl: e.ttl,
+            })
+            .collect();
+
+        warp::reply::json(&CacheContents { total, entries })
+    })
+}
+
+/// Filter for `GET /trace` requests, resolving a query and responding with the result of
+/// each step as JSON
+///
+/// Queries use the same parameters as the JSON format (`name`, `type`, `cd`, `do`, and
+/// `edns_client_subnet`). This is meant for operators debugging a single lookup and shouldn't
+/// be exposed to clients since it bypasses limits applied to other requests.
+pub fn trace(context: Arc<HandlerContext>) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("trace")
+        .and(warp::filters::method::get())
+        .and(warp::query::query::<JsonQuery>())
+        .and_then(move |q: JsonQuery| {
+            let context = context.clone();
+            async move {
+                let trace = context.trace(q).await;
+                Ok::<_, Rejection>(warp::reply::json(&trace))
+            }
+        })
+}
+
+/// Filter for any other `/dns-query` requests, responding with `400 Bad Request`
 pub fn fallback() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path("dns-query").map(|| StatusCode::BAD_REQUEST.into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        access_control, cache_flush, cache_list, json_get, Chaos, DenyAction, DnsResponseReply, HandlerContext,
+        JsonQuery, ResolutionMeta, JSON_MESSAGE_FORMAT,
+    };
+    use crate::cache::ResponseCache;
+    use crate::listen::{ClientAddr, DropConnection};
+    use crate::request::{RequestParserJsonGet, RequestParserWireGet, RequestParserWirePost};
+    use crate::resolve::{AddressPrefix, ResolverOptions, SourceAddrs, StaticRecord, StaticResolver, UpstreamResolver};
+    use crate::response::{
+        synthesize_response, ResponseEncoderJson, ResponseEncoderText, ResponseEncoderWire, ResponseMetadata,
+    };
+    use crate::types::ErrorKind;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::num::NonZeroUsize;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::net::UdpSocket;
+    use trust_dns_client::op::{DnsResponse, Message, MessageType, Query, ResponseCode};
+    use trust_dns_client::proto::xfer::{DnsRequest, DnsRequestOptions};
+    use trust_dns_client::rr::rdata::SOA;
+    use trust_dns_client::rr::{Name, RData, Record, RecordType};
+    use warp::http::header::CACHE_CONTROL;
+    use warp::Filter;
+
+    /// Start a UDP server that answers every query with three A records
+    async fn fake_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf[..len]).unwrap();
+                let name = req.queries()[0].name().clone();
+
+                let mut res = Message::new();
+                res.set_id(req.id());
+                res.set_message_type(MessageType::Response);
+                res.add_queries(req.queries().to_vec());
+                for i in 1..=3 {
+                    res.add_answer(Record::from_rdata(
+                        name.clone(),
+                        300,
+                        RData::A(Ipv4Addr::new(192, 0, 2, i)),
+                    ));
+                }
+
+                socket.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    /// Context that answers queries for `www.example.com` with 192.0.2.1 without an upstream server
+    fn static_context() -> HandlerContext {
+        let record = StaticRecord::new(
+            Name::from_str("www.example.com.").unwrap(),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+
+        HandlerContext::new(
+            RequestParserJsonGet::default(),
+            RequestParserWireGet::default(),
+            RequestParserWirePost::default(),
+            StaticResolver::new(vec![record], 60),
+            ResponseEncoderJson::default(),
+            ResponseEncoderWire::new(),
+            ResponseEncoderText::new(),
+        )
+    }
+
+    /// Negative response with an SOA record that has a TTL of 3600 and a minimum of 300
+    fn negative_response(code: ResponseCode) -> DnsResponse {
+        let mut res = synthesize_response(&request(), code, Vec::new());
+        let soa = SOA::new(
+            Name::from_str("ns1.example.com.").unwrap(),
+            Name::from_str("hostmaster.example.com.").unwrap(),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        res.add_name_server(Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            3600,
+            RData::SOA(soa),
+        ));
+        res
+    }
+
+    fn request() -> DnsRequest {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A));
+        msg.set_recursion_desired(true);
+        DnsRequest::new(msg, DnsRequestOptions::default())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_cached_answer_subset_rotates() {
+        let addr = fake_upstream().await;
+        let timeout = Duration::from_secs(1);
+        let client = crate::resolve::new_udp_client(addr, SourceAddrs::default(), timeout)
+            .await
+            .unwrap();
+        let options = ResolverOptions {
+            timeout: Some(timeout),
+            answer_subset: NonZeroUsize::new(1),
+            ..ResolverOptions::default()
+        };
+
+        let context = HandlerContext::new(
+            RequestParserJsonGet::default(),
+            RequestParserWireGet::default(),
+            RequestParserWirePost::default(),
+            UpstreamResolver::new(client, addr, options),
+            ResponseEncoderJson::default(),
+            ResponseEncoderWire::new(),
+            ResponseEncoderText::new(),
+        )
+        .with_cache(ResponseCache::new(NonZeroUsize::new(16).unwrap()));
+
+        let (meta1, res1) = context.resolve_with_meta(request()).await.unwrap();
+        let (meta2, res2) = context.resolve_with_meta(request()).await.unwrap();
+        let (meta3, res3) = context.resolve_with_meta(request()).await.unwrap();
+
+        assert_eq!("miss", meta1.cache);
+        assert_eq!("hit", meta2.cache);
+        assert_eq!("hit", meta3.cache);
+
+        assert_eq!(1, res1.answers().len());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 1)), res1.answers()[0].rdata());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 2)), res2.answers()[0].rdata());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 3)), res3.answers()[0].rdata());
+    }
+
+    #[tokio::test]
+    async fn test_encode_json_envelope_padded() {
+        let block = NonZeroUsize::new(128).unwrap();
+        let context = HandlerContext::new(
+            RequestParserJsonGet::default(),
+            RequestParserWireGet::default(),
+            RequestParserWirePost::default(),
+            StaticResolver::new(Vec::new(), 60),
+            ResponseEncoderJson::new(false, Some(block), None),
+            ResponseEncoderWire::new(),
+            ResponseEncoderText::new(),
+        );
+
+        for envelope in [false, true] {
+            let meta = ResolutionMeta {
+                upstream: Some("127.0.0.1:53".to_owned()),
+                cache: "disabled",
+                latency_ms: 12,
+            };
+
+            let res = synthesize_response(&request(), ResponseCode::NXDomain, Vec::new());
+            let (_, body) = context.encode_json(res, meta, false, envelope).await.unwrap();
+
+            assert_eq!(0, body.len() % block.get(), "envelope: {}", envelope);
+            assert_eq!(envelope, body.starts_with(b"{\"meta\":"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encode_json_envelope_matches_bare() {
+        let context = static_context();
+        let meta = || ResolutionMeta {
+            upstream: Some("127.0.0.1:53".to_owned()),
+            cache: "miss",
+            latency_ms: 12,
+        };
+
+        let res = synthesize_response(&request(), ResponseCode::NXDomain, Vec::new());
+        let (_, bare) = context.encode_json(res.clone(), meta(), false, false).await.unwrap();
+        let (_, wrapped) = context.encode_json(res, meta(), false, true).await.unwrap();
+
+        let bare: serde_json::Value = serde_json::from_slice(&bare).unwrap();
+        let wrapped: serde_json::Value = serde_json::from_slice(&wrapped).unwrap();
+
+        assert_eq!("127.0.0.1:53", wrapped["meta"]["upstream"]);
+        assert_eq!("miss", wrapped["meta"]["cache"]);
+        assert_eq!(12, wrapped["meta"]["latency_ms"]);
+        assert_eq!(bare, wrapped["response"]);
+        assert_eq!(2, wrapped.as_object().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_json_get_envelope_param() {
+        let cases = [
+            (false, "", 200, false),
+            (false, "&envelope=1", 200, false),
+            (false, "&envelope=true", 200, false),
+            (true, "", 200, true),
+            (true, "&envelope=1", 200, true),
+            (true, "&envelope=0", 200, false),
+            (true, "&envelope=false", 200, false),
+            (true, "&envelope=yes", 400, false),
+        ];
+
+        for (enabled, param, status, wrapped) in cases {
+            let filter = json_get(Arc::new(static_context().with_json_envelope(enabled)), false);
+            let res = warp::test::request()
+                .path(&format!("/dns-query?name=www.example.com&type=A{}", param))
+                .header("accept", "application/dns-json")
+                .reply(&filter)
+                .await;
+
+            assert_eq!(status, res.status().as_u16(), "enabled: {}, param: {}", enabled, param);
+            if status == 200 {
+                let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+                assert_eq!(
+                    wrapped,
+                    body.get("meta").is_some(),
+                    "enabled: {}, param: {}",
+                    enabled,
+                    param
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_get_response_bytes() {
+        let context = Arc::new(static_context());
+        let filter = json_get(context.clone(), false);
+
+        let res = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=A")
+            .header("accept", "application/dns-json")
+            .reply(&filter)
+            .await;
+        assert_eq!(200, res.status().as_u16());
+
+        // Errors don't have a body in the requested format so they aren't counted
+        let err = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=BOGUS")
+            .header("accept", "application/dns-json")
+            .reply(&filter)
+            .await;
+        assert_eq!(400, err.status().as_u16());
+
+        let expected = format!("donut_response_bytes_total{{format=\"json\"}} {}", res.body().len());
+        let out = context.metrics.render(&[], None);
+        assert!(out.lines().any(|l| l == expected), "{}", out);
+    }
+
+    #[test]
+    fn test_success_nodata_max_age() {
+        let res = negative_response(ResponseCode::NoError);
+        let reply = DnsResponseReply::success(JSON_MESSAGE_FORMAT, ResponseMetadata::from(&res), Vec::new());
+
+        assert_eq!("max-age=300", reply.headers()[CACHE_CONTROL]);
+    }
+
+    #[test]
+    fn test_success_nxdomain_max_age() {
+        let res = negative_response(ResponseCode::NXDomain);
+        let reply = DnsResponseReply::success(JSON_MESSAGE_FORMAT, ResponseMetadata::from(&res), Vec::new());
+
+        assert_eq!("max-age=300", reply.headers()[CACHE_CONTROL]);
+
+        // Without an SOA there's nothing to say how long the response can be cached for
+        let res = synthesize_response(&request(), ResponseCode::NXDomain, Vec::new());
+        let reply = DnsResponseReply::success(JSON_MESSAGE_FORMAT, ResponseMetadata::from(&res), Vec::new());
+
+        assert!(reply.headers().get(CACHE_CONTROL).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_json_get_stale_answer_on_error() {
+        // Upstream server that never answers so that every query times out
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let timeout = Duration::from_millis(200);
+        let client = crate::resolve::new_udp_client(addr, SourceAddrs::default(), timeout)
+            .await
+            .unwrap();
+        let options = ResolverOptions {
+            timeout: Some(timeout),
+            ..ResolverOptions::default()
+        };
+
+        let context = HandlerContext::new(
+            RequestParserJsonGet::default(),
+            RequestParserWireGet::default(),
+            RequestParserWirePost::default(),
+            UpstreamResolver::new(client, addr, options),
+            ResponseEncoderJson::default(),
+            ResponseEncoderWire::new(),
+            ResponseEncoderText::new(),
+        )
+        .with_cache(ResponseCache::new(NonZeroUsize::new(16).unwrap()).with_stale_ttl(Duration::from_secs(60)));
+
+        let req = request();
+        let answer = Record::from_rdata(
+            Name::from_str("www.example.com.").unwrap(),
+            1,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        );
+        context
+            .cache()
+            .unwrap()
+            .insert(&req, &synthesize_response(&req, ResponseCode::NoError, vec![answer]));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let filter = json_get(Arc::new(context), false);
+        let res = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=A")
+            .header("accept", "application/dns-json")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(200, res.status().as_u16());
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!("192.0.2.1", body["Answer"][0]["data"]);
+        assert_eq!(crate::cache::STALE_ANSWER_TTL, body["Answer"][0]["TTL"]);
+        drop(upstream);
+    }
+
+    #[tokio::test]
+    async fn test_access_control_allowed_and_denied() {
+        let allowed = vec![AddressPrefix::from_str("192.0.2.0/24").unwrap()];
+        let filter =
+            access_control(allowed.clone(), DenyAction::Reject).or(json_get(Arc::new(static_context()), false));
+
+        let res = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=A")
+            .header("accept", "application/dns-json")
+            .extension(ClientAddr(SocketAddr::from(([192, 0, 2, 10], 40000))))
+            .reply(&filter)
+            .await;
+        assert_eq!(200, res.status().as_u16());
+
+        let res = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=A")
+            .header("accept", "application/dns-json")
+            .extension(ClientAddr(SocketAddr::from(([198, 51, 100, 10], 40000))))
+            .reply(&filter)
+            .await;
+        assert_eq!(403, res.status().as_u16());
+        assert!(res.extensions().get::<DropConnection>().is_none());
+
+        // Clients are unknown when not served by `listen::serve` and are always denied
+        let res = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=A")
+            .header("accept", "application/dns-json")
+            .reply(&filter)
+            .await;
+        assert_eq!(403, res.status().as_u16());
+
+        let filter = access_control(allowed, DenyAction::Drop).or(json_get(Arc::new(static_context()), false));
+        let res = warp::test::request()
+            .path("/dns-query?name=www.example.com&type=A")
+            .header("accept", "application/dns-json")
+            .extension(ClientAddr(SocketAddr::from(([198, 51, 100, 10], 40000))))
+            .reply(&filter)
+            .await;
+        assert_eq!(403, res.status().as_u16());
+        assert!(res.extensions().get::<DropConnection>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_flush() {
+        let context = Arc::new(static_context().with_cache(ResponseCache::new(NonZeroUsize::new(16).unwrap())));
+        let (meta, _) = context.resolve_with_meta(request()).await.unwrap();
+        assert_eq!("miss", meta.cache);
+        let (meta, _) = context.resolve_with_meta(request()).await.unwrap();
+        assert_eq!("hit", meta.cache);
+
+        let filter = cache_flush(context.clone());
+        let res = warp::test::request()
+            .method("POST")
+            .path("/cache/flush?name=www.example.net")
+            .reply(&filter)
+            .await;
+        assert_eq!(200, res.status().as_u16());
+        assert_eq!(&b"{\"removed\":0}"[..], res.body());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/cache/flush?name=WWW.example.com")
+            .reply(&filter)
+            .await;
+        assert_eq!(200, res.status().as_u16());
+        assert_eq!(&b"{\"removed\":1}"[..], res.body());
+
+        let (meta, _) = context.resolve_with_meta(request()).await.unwrap();
+        assert_eq!("miss", meta.cache);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/cache/flush")
+            .reply(&filter)
+            .await;
+        assert_eq!(&b"{\"removed\":1}"[..], res.body());
+        assert!(context.cache().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_list() {
+        let context = Arc::new(static_context().with_cache(ResponseCache::new(NonZeroUsize::new(16).unwrap())));
+        context.resolve_with_meta(request()).await.unwrap();
+
+        let filter = cache_list(context.clone());
+        let res = warp::test::request().path("/cache").reply(&filter).await;
+        assert_eq!(200, res.status().as_u16());
+
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(1, body["total"]);
+        assert_eq!("www.example.com.", body["entries"][0]["name"]);
+        assert_eq!("A", body["entries"][0]["type"]);
+        assert_eq!(60, body["entries"][0]["ttl"]);
+    }
+
+    #[tokio::test]
+    async fn test_chaos_error_rate() {
+        let always = Chaos::new(None, 1.0);
+        let never = Chaos::new(None, 0.0);
+
+        for _ in 0..100 {
+            assert_eq!(ErrorKind::Unavailable, always.inject().await.unwrap_err().kind());
+            assert!(never.inject().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chaos_delay() {
+        let chaos = Chaos::new(Some(Duration::from_millis(50)), 0.0);
+        let start = Instant::now();
+        chaos.inject().await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_json_query_client_subnet_param() {
+        let filter = warp::query::query::<JsonQuery>();
+
+        for param in ["edns_client_subnet", "client_subnet"] {
+            let q = warp::test::request()
+                .path(&format!(
+                    "/dns-query?name=www.example.com&type=A&{}=192.0.2.0/24",
+                    param
+                ))
+                .filter(&filter)
+                .await
+                .unwrap();
+
+            assert_eq!(Some("192.0.2.0/24"), q.client_subnet.as_deref(), "param: {}", param);
+        }
+    }
+}
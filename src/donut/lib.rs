@@ -17,10 +17,27 @@
 //
 
 /// Max size for a DNS message in bytes (POST body or GET parameter after decoding)
+///
+/// Messages over this size are rejected with `ErrorKind::InputBodyTooLong` regardless of
+/// the HTTP method used to send them.
 pub const MAX_MESSAGE_SIZE: usize = 512;
 
+/// Max length of the base64 encoded `dns` parameter of GET requests
+///
+/// This limits the size of the URI, independent of the size of the DNS message it contains.
+/// Parameters over this length are rejected with `ErrorKind::InputUriTooLong` before being
+/// decoded. It's long enough that any message that isn't over `MAX_MESSAGE_SIZE` fits.
+pub const MAX_ENCODED_MESSAGE_LENGTH: usize = 2048;
+
+pub mod cache;
+#[cfg(feature = "dnstap")]
+pub mod dnstap;
 pub mod http;
+pub mod listen;
+pub mod metrics;
 pub mod request;
 pub mod resolve;
 pub mod response;
 pub mod types;
+
+pub use crate::http::{fallback, json_get, text_get, wire_get, wire_post, HandlerContext};
@@ -0,0 +1,685 @@
+// Donut - DNS over HTTPS server
+//
+// Copyright 2019 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Accept HTTP connections, optionally limiting how many are open at once and using TLS.
+
+use crate::types::{DonutError, DonutResult, ErrorKind};
+use futures_util::{future, Future, Stream, StreamExt};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    ClientHello, NoClientAuth, ProtocolVersion, ResolvesServerCert, ServerConfig, SupportedCipherSuite,
+    ALL_CIPHERSUITES,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::server::accept;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request, Server};
+use warp::{Filter, Rejection, Reply};
+
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Connection accepted by `incoming`, holding its slot until dropped.
+#[derive(Debug)]
+pub struct LimitedStream {
+    inner: TcpStream,
+    addr: SocketAddr,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Connection that knows the address of the client on the other end.
+pub trait PeerAddr {
+    fn peer_addr(&self) -> SocketAddr;
+}
+
+impl PeerAddr for LimitedStream {
+    fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl PeerAddr for TlsStream<LimitedStream> {
+    fn peer_addr(&self) -> SocketAddr {
+        self.get_ref().0.peer_addr()
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Stream of connections accepted from `listener` for use with `warp::Server::serve_incoming`.
+///
+/// If `max_connections` is set, no new connections are accepted while that many are open.
+/// Excess connections wait in the listen backlog of the OS until an open one is closed.
+pub fn incoming(
+    listener: TcpListener,
+    max_connections: Option<NonZeroUsize>,
+) -> impl Stream<Item = io::Result<LimitedStream>> {
+    let semaphore = max_connections.map(|n| Arc::new(Semaphore::new(n.get())));
+
+    futures_util::stream::unfold((listener, semaphore), |(listener, semaphore)| async move {
+        let permit = match &semaphore {
+            // The semaphore is never closed so acquiring a permit can't fail
+            Some(s) => Some(s.clone().acquire_owned().await.expect("connection semaphore closed")),
+            None => None,
+        };
+
+        loop {
+            match listener.accept().await {
+                Ok((inner, addr)) => {
+                    let stream = LimitedStream {
+                        inner,
+                        addr,
+                        _permit: permit,
+                    };
+                    return Some((Ok(stream), (listener, semaphore)));
+                }
+                Err(e) => {
+                    // Errors accepting connections (such as running out of file descriptors) end
+                    // the server if returned so back off and try again instead, the same way the
+                    // default Warp and Hyper listener does.
+                    tracing::warn!(message = "error accepting connection", error = %e);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                }
+            }
+        }
+    })
+}
+
+/// Oldest version of TLS that clients may use to connect.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMinVersion {
+    /// Accept TLS 1.2 and TLS 1.3
+    #[default]
+    V1_2,
+    /// Accept only TLS 1.3
+    V1_3,
+}
+
+impl TlsMinVersion {
+    fn versions(&self) -> Vec<ProtocolVersion> {
+        match self {
+            TlsMinVersion::V1_2 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            TlsMinVersion::V1_3 => vec![ProtocolVersion::TLSv1_3],
+        }
+    }
+}
+
+impl FromStr for TlsMinVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.2" => Ok(TlsMinVersion::V1_2),
+            "1.3" => Ok(TlsMinVersion::V1_3),
+            _ => Err(format!("expected '1.2' or '1.3', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for TlsMinVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsMinVersion::V1_2 => write!(f, "1.2"),
+            TlsMinVersion::V1_3 => write!(f, "1.3"),
+        }
+    }
+}
+
+/// Cipher suites that clients may use to connect, parsed from a comma separated list of
+/// names (e.g. `TLS13_AES_256_GCM_SHA384`). Defaults to every suite supported by rustls.
+#[derive(Clone, PartialEq)]
+pub struct TlsCipherSuites {
+    suites: Vec<&'static SupportedCipherSuite>,
+}
+
+impl TlsCipherSuites {
+    fn name(suite: &SupportedCipherSuite) -> String {
+        format!("{:?}", suite.suite)
+    }
+}
+
+impl Default for TlsCipherSuites {
+    fn default() -> Self {
+        TlsCipherSuites {
+            suites: ALL_CIPHERSUITES.to_vec(),
+        }
+    }
+}
+
+impl FromStr for TlsCipherSuites {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let suites = s
+            .split(',')
+            .map(|v| {
+                let v = v.trim();
+                ALL_CIPHERSUITES
+                    .iter()
+                    .copied()
+                    .find(|c| Self::name(c).eq_ignore_ascii_case(v))
+                    .ok_or_else(|| format!("unsupported cipher suite {:?}", v))
+            })
+            .collect::<Result<Vec<&'static SupportedCipherSuite>, String>>()?;
+
+        Ok(TlsCipherSuites { suites })
+    }
+}
+
+impl fmt::Display for TlsCipherSuites {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, c) in self.suites.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", Self::name(c))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TlsCipherSuites {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TlsCipherSuites({})", self)
+    }
+}
+
+/// Restrict the TLS versions and cipher suites clients may use, failing if none of the cipher
+/// suites can be used with any of the allowed versions.
+fn restrict_tls(config: &mut ServerConfig, min_version: TlsMinVersion, suites: &TlsCipherSuites) -> DonutResult<()> {
+    let versions = min_version.versions();
+    let usable: Vec<&'static SupportedCipherSuite> = suites
+        .suites
+        .iter()
+        .copied()
+        .filter(|c| versions.iter().any(|v| c.usable_for_version(*v)))
+        .collect();
+
+    if usable.is_empty() {
+        return Err(DonutError::from((
+            ErrorKind::Internal,
+            "no TLS cipher suites usable with the minimum TLS version",
+        )));
+    }
+
+    config.versions = versions;
+    config.ciphersuites = usable;
+    Ok(())
+}
+
+/// Certificate chain and private key for serving HTTPS that can be reloaded from their files
+/// while running, e.g. after they've been renewed.
+///
+/// Connections accepted after a reload use the new certificate, existing connections are
+/// unaffected.
+#[derive(Clone)]
+pub struct ReloadableCert {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    current: Arc<RwLock<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    /// Load the certificate chain and private key (PKCS8 or RSA) in the given files (PEM format)
+    pub fn load(cert_file: &Path, key_file: &Path) -> DonutResult<Self> {
+        let current = load_certified_key(cert_file, key_file)?;
+        Ok(ReloadableCert {
+            cert_file: cert_file.to_path_buf(),
+            key_file: key_file.to_path_buf(),
+            current: Arc::new(RwLock::new(current)),
+        })
+    }
+
+    /// Load the certificate chain and private key from their files again, keeping the current
+    /// ones if the files can't be loaded
+    pub fn reload(&self) -> DonutResult<()> {
+        let key = load_certified_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = key;
+        Ok(())
+    }
+
+    fn current(&self) -> CertifiedKey {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current())
+    }
+}
+
+impl fmt::Debug for ReloadableCert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableCert")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+fn load_certified_key(cert_file: &Path, key_file: &Path) -> DonutResult<CertifiedKey> {
+    let certs = fs::File::open(cert_file)
+        .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to read TLS certificate file", e)))
+        .and_then(|f| {
+            pemfile::certs(&mut io::BufReader::new(f))
+                .ok()
+                .filter(|c| !c.is_empty())
+                .ok_or_else(|| DonutError::from((ErrorKind::Internal, "no valid certificates in TLS certificate file")))
+        })?;
+
+    let contents =
+        fs::read(key_file).map_err(|e| DonutError::from((ErrorKind::Internal, "unable to read TLS key file", e)))?;
+    let key = pemfile::pkcs8_private_keys(&mut contents.as_slice())
+        .ok()
+        .filter(|k| !k.is_empty())
+        .or_else(|| pemfile::rsa_private_keys(&mut contents.as_slice()).ok())
+        .and_then(|mut k| if k.is_empty() { None } else { Some(k.remove(0)) })
+        .ok_or_else(|| DonutError::from((ErrorKind::Internal, "no valid private keys in TLS key file")))?;
+
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| DonutError::from((ErrorKind::Internal, "invalid or unsupported TLS private key")))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Create a TLS acceptor for serving HTTPS using `cert`, accepting connections using TLS
+/// versions of at least `min_version` and one of `suites`.
+///
+/// Both HTTP/2 and HTTP/1.1 are advertised to clients via ALPN.
+pub fn tls_acceptor(
+    cert: &ReloadableCert,
+    min_version: TlsMinVersion,
+    suites: &TlsCipherSuites,
+) -> DonutResult<TlsAcceptor> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = Arc::new(cert.clone());
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    restrict_tls(&mut config, min_version, suites)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Stream of TLS connections for use with `warp::Server::serve_incoming`, performing the TLS
+/// handshake for each connection accepted by `incoming`.
+///
+/// Several handshakes are performed at once so that slow clients don't hold up others.
+/// Connections with failed handshakes are logged and dropped without ending the stream.
+pub fn incoming_tls<S>(incoming: S, acceptor: TlsAcceptor) -> impl Stream<Item = io::Result<TlsStream<LimitedStream>>>
+where
+    S: Stream<Item = io::Result<LimitedStream>>,
+{
+    incoming
+        .map(move |res| {
+            let acceptor = acceptor.clone();
+            async move {
+                let stream = match res {
+                    Ok(s) => s,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(s)) => Some(Ok(s)),
+                    Ok(Err(e)) => {
+                        tracing::debug!(message = "TLS handshake failed", error = %e);
+                        None
+                    }
+                    Err(_) => {
+                        tracing::debug!(message = "TLS handshake timed out");
+                        None
+                    }
+                }
+            }
+        })
+        .buffer_unordered(MAX_PENDING_HANDSHAKES)
+        .filter_map(future::ready)
+}
+
+/// Address of the client that made a request, added to each request by `serve`.
+///
+/// Filters can get this using `warp::ext::get` or `warp::ext::optional`. Unlike the filters in
+/// `warp::addr`, this works for connections accepted from any stream, including TLS connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Extension added to a response to close the connection instead of sending the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DropConnection;
+
+/// Serve requests for each connection from `incoming` until `shutdown` completes.
+///
+/// This is the same as `warp::Server::serve_incoming_with_graceful_shutdown` except that the
+/// address of the client is added to each request as a `ClientAddr` extension and responses
+/// with a `DropConnection` extension close the connection without being sent.
+pub async fn serve<F, S, C>(
+    filter: F,
+    incoming: S,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), warp::hyper::Error>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+    S: Stream<Item = io::Result<C>>,
+    C: PeerAddr + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = warp::service(filter);
+    let make_service = make_service_fn(move |conn: &C| {
+        let client = ClientAddr(conn.peer_addr());
+        let mut service = service.clone();
+        future::ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+            req.extensions_mut().insert(client);
+            let res = service.call(req);
+            async move {
+                let res = res.await.unwrap_or_else(|e: Infallible| match e {});
+                if res.extensions().get::<DropConnection>().is_some() {
+                    // Hyper closes the connection (or resets the stream for HTTP/2) without
+                    // writing anything when the service returns an error.
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "dropped denied request",
+                    ));
+                }
+
+                Ok(res)
+            }
+        }))
+    });
+
+    Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+/// Limit on the number of queries being handled at once for each client, by IP address.
+#[derive(Debug)]
+pub struct ClientLimiter {
+    max: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientLimiter {
+    pub fn new(max: NonZeroUsize) -> Self {
+        ClientLimiter {
+            max: max.get(),
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Start handling a query from the client, if it isn't already at the limit. The query
+    /// counts towards the limit until the returned permit is dropped.
+    pub fn acquire(&self, addr: IpAddr) -> Option<ClientPermit> {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = active.entry(addr).or_default();
+        if *count >= self.max {
+            return None;
+        }
+
+        *count += 1;
+        Some(ClientPermit {
+            addr,
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// Query being handled for a client, see `ClientLimiter::acquire`.
+#[derive(Debug)]
+pub struct ClientPermit {
+    addr: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        // Remove clients without any queries so that the map only grows with the number of
+        // clients making queries right now, not every client ever seen.
+        if let Entry::Occupied(mut e) = active.entry(self.addr) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        incoming, restrict_tls, serve, ClientLimiter, DropConnection, ReloadableCert, TlsCipherSuites, TlsMinVersion,
+    };
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::num::NonZeroUsize;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::PoisonError;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::oneshot;
+    use tokio_rustls::rustls::internal::pemfile;
+    use tokio_rustls::rustls::{Certificate, NoClientAuth, ProtocolVersion, ServerConfig};
+    use warp::{Filter, Reply};
+
+    /// Make an HTTP/1.1 request for `path` and return everything sent back before the
+    /// connection was closed
+    async fn get(addr: std::net::SocketAddr, path: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+
+        let mut buf = Vec::new();
+        // The connection may be reset instead of closed, either way nothing must be read
+        let _ = stream.read_to_end(&mut buf).await;
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_serve_drop_connection() {
+        let filter = warp::path!("drop")
+            .map(|| {
+                let mut res = "dropped".into_response();
+                res.extensions_mut().insert(DropConnection);
+                res
+            })
+            .or(warp::path!("ok").map(|| "ok"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = serve(filter, incoming(listener, None), async {
+            let _ = rx.await;
+        });
+
+        let client = async {
+            let ok = get(addr, "/ok").await;
+            let dropped = get(addr, "/drop").await;
+            tx.send(()).unwrap();
+            (ok, dropped)
+        };
+
+        let (res, (ok, dropped)) = tokio::join!(server, client);
+        res.unwrap();
+
+        assert!(ok.starts_with(b"HTTP/1.1 200 OK"));
+        assert!(dropped.is_empty(), "response: {}", String::from_utf8_lossy(&dropped));
+    }
+
+    fn active(limiter: &ClientLimiter) -> usize {
+        limiter.active.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    #[test]
+    fn test_client_limiter_limit() {
+        let limiter = ClientLimiter::new(NonZeroUsize::new(2).unwrap());
+        let client1 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let client2 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        let _p1 = limiter.acquire(client1).unwrap();
+        let _p2 = limiter.acquire(client1).unwrap();
+        assert!(limiter.acquire(client1).is_none());
+
+        // Other clients have their own limit
+        assert!(limiter.acquire(client2).is_some());
+    }
+
+    #[test]
+    fn test_client_limiter_release_on_drop() {
+        let limiter = ClientLimiter::new(NonZeroUsize::new(1).unwrap());
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let permit = limiter.acquire(client).unwrap();
+        assert!(limiter.acquire(client).is_none());
+
+        drop(permit);
+        assert!(limiter.acquire(client).is_some());
+    }
+
+    #[test]
+    fn test_client_limiter_remove_idle_clients() {
+        let limiter = ClientLimiter::new(NonZeroUsize::new(2).unwrap());
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let p1 = limiter.acquire(client).unwrap();
+        let p2 = limiter.acquire(client).unwrap();
+        assert_eq!(1, active(&limiter));
+
+        drop(p1);
+        assert_eq!(1, active(&limiter));
+
+        drop(p2);
+        assert_eq!(0, active(&limiter));
+    }
+
+    #[test]
+    fn test_restrict_tls_min_version() {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        restrict_tls(&mut config, TlsMinVersion::V1_3, &TlsCipherSuites::default()).unwrap();
+
+        assert_eq!(vec![ProtocolVersion::TLSv1_3], config.versions);
+        assert!(!config.ciphersuites.is_empty());
+        assert!(config
+            .ciphersuites
+            .iter()
+            .all(|c| c.usable_for_version(ProtocolVersion::TLSv1_3)));
+    }
+
+    #[test]
+    fn test_restrict_tls_cipher_suites() {
+        let suites =
+            TlsCipherSuites::from_str("tls13_aes_256_gcm_sha384, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256").unwrap();
+        assert_eq!(
+            "TLS13_AES_256_GCM_SHA384,TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+            suites.to_string()
+        );
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        restrict_tls(&mut config, TlsMinVersion::V1_2, &suites).unwrap();
+        assert_eq!(
+            vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            config.versions
+        );
+        assert_eq!(2, config.ciphersuites.len());
+
+        // Only TLS 1.2 suites can't be used when TLS 1.3 is required
+        let suites = TlsCipherSuites::from_str("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256").unwrap();
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        assert!(restrict_tls(&mut config, TlsMinVersion::V1_3, &suites).is_err());
+
+        assert!(TlsCipherSuites::from_str("TLS_RSA_WITH_RC4_128_SHA").is_err());
+        assert!(TlsMinVersion::from_str("1.1").is_err());
+    }
+
+    /// Path of a certificate or key file used for tests
+    fn testdata(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(file)
+    }
+
+    fn read_cert(path: &Path) -> Certificate {
+        pemfile::certs(&mut fs::read(path).unwrap().as_slice())
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn test_reloadable_cert_reload() {
+        let dir = std::env::temp_dir().join(format!("donut-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::copy(testdata("cert1.pem"), &cert_file).unwrap();
+        fs::copy(testdata("key1.pem"), &key_file).unwrap();
+
+        let cert = ReloadableCert::load(&cert_file, &key_file).unwrap();
+        assert_eq!(vec![read_cert(&testdata("cert1.pem"))], cert.current().cert);
+
+        // Replace the files with a different certificate, it's only used after a reload
+        fs::copy(testdata("cert2.pem"), &cert_file).unwrap();
+        fs::copy(testdata("key2.pem"), &key_file).unwrap();
+        assert_eq!(vec![read_cert(&testdata("cert1.pem"))], cert.current().cert);
+
+        cert.reload().unwrap();
+        assert_eq!(vec![read_cert(&testdata("cert2.pem"))], cert.current().cert);
+
+        // Files that can't be loaded leave the current certificate in place
+        fs::write(&key_file, b"not a key").unwrap();
+        let res = cert.reload();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(res.is_err());
+        assert_eq!(vec![read_cert(&testdata("cert2.pem"))], cert.current().cert);
+    }
+}
@@ -0,0 +1,582 @@
+// Donut - DNS over HTTPS server
+//
+// Copyright 2019 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Counters and histograms describing the queries handled by the server.
+
+use crate::resolve::UpstreamStatus;
+use crate::types::ErrorKind;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use trust_dns_client::op::ResponseCode;
+
+/// Default upper bounds of latency histogram buckets in seconds. DNS queries usually take
+/// anywhere from under a millisecond (cached by the upstream server) to tens of milliseconds.
+pub const DEFAULT_LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Upper bounds of histogram buckets in seconds, parsed from a comma separated list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyBuckets {
+    bounds: Vec<f64>,
+}
+
+impl LatencyBuckets {
+    pub fn bounds(&self) -> &[f64] {
+        &self.bounds
+    }
+}
+
+impl Default for LatencyBuckets {
+    fn default() -> Self {
+        LatencyBuckets {
+            bounds: DEFAULT_LATENCY_BUCKETS.to_vec(),
+        }
+    }
+}
+
+impl FromStr for LatencyBuckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s
+            .split(',')
+            .map(|v| {
+                v.trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|b| b.is_finite() && *b > 0.0)
+                    .ok_or_else(|| format!("invalid bucket {:?}, must be a positive number of seconds", v))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+
+        if bounds.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format!("buckets must be in increasing order: {}", s));
+        }
+
+        Ok(LatencyBuckets { bounds })
+    }
+}
+
+impl fmt::Display for LatencyBuckets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.bounds.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", b)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Cumulative histogram of durations, in the style of Prometheus.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: LatencyBuckets,
+    /// Number of observations less than or equal to the bound of each bucket
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(buckets: LatencyBuckets) -> Self {
+        let counts = buckets.bounds().iter().map(|_| AtomicU64::new(0)).collect();
+        Histogram {
+            buckets,
+            counts,
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, count) in self.buckets.bounds().iter().zip(self.counts.iter()) {
+            if secs <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Upper bound of each bucket and the number of observations less than or equal to it
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        self.buckets
+            .bounds()
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(b, c)| (*b, c.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Total number of observations
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of all observations in seconds
+    pub fn sum(&self) -> f64 {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)).as_secs_f64()
+    }
+}
+
+/// Counts of events by the value of their labels.
+#[derive(Debug)]
+struct Counter<K: Ord> {
+    values: Mutex<BTreeMap<K, u64>>,
+}
+
+impl<K: Ord + Clone> Counter<K> {
+    fn inc(&self, key: K) {
+        self.add(key, 1);
+    }
+
+    fn add(&self, key: K, n: u64) {
+        *self
+            .values
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key)
+            .or_default() += n;
+    }
+
+    fn values(&self) -> Vec<(K, u64)> {
+        self.values
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    }
+}
+
+impl<K: Ord> Default for Counter<K> {
+    fn default() -> Self {
+        Counter {
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// Metrics about queries handled by the server, shared by all requests.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Queries received by HTTP method and request format
+    queries: Counter<(&'static str, &'static str)>,
+    /// Responses by DNS response code
+    responses: Counter<u16>,
+    /// Errors resolving queries by kind
+    errors: Counter<&'static str>,
+    /// Bytes of successful response bodies by response format
+    response_bytes: Counter<&'static str>,
+    latency: Histogram,
+}
+
+impl Metrics {
+    pub fn new(latency_buckets: LatencyBuckets) -> Self {
+        Metrics {
+            queries: Counter::default(),
+            responses: Counter::default(),
+            errors: Counter::default(),
+            response_bytes: Counter::default(),
+            latency: Histogram::new(latency_buckets),
+        }
+    }
+
+    /// Record a query received using the given HTTP method and format (json, text, or wire)
+    pub fn record_query(&self, method: &'static str, format: &'static str) {
+        self.queries.inc((method, format));
+    }
+
+    /// Record a response to a query, from the resolver or the cache
+    pub fn record_response(&self, code: ResponseCode) {
+        self.responses.inc(u16::from(code));
+    }
+
+    /// Record the size of a successful response body in the given format (json, text, or wire)
+    pub fn record_response_bytes(&self, format: &'static str, bytes: usize) {
+        self.response_bytes.add(format, bytes as u64);
+    }
+
+    /// Record an error from the resolver while answering a query
+    pub fn record_error(&self, kind: ErrorKind) {
+        self.errors.inc(error_kind_label(kind));
+    }
+
+    /// Record how long it took the resolver to answer a query
+    pub fn observe_latency(&self, duration: Duration) {
+        self.latency.observe(duration);
+    }
+
+    /// Time taken by the resolver to answer queries, not including queries answered from cache
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    /// Render all metrics in the Prometheus text format, along with the health of each upstream
+    /// server and the number of cache entries (if caching is enabled)
+    pub fn render(&self, upstreams: &[UpstreamStatus], cache_entries: Option<usize>) -> String {
+        let mut out = String::new();
+        // Writing to a String can't fail so the results here are ignored
+        let _ = self.write_prometheus(&mut out, upstreams, cache_entries);
+        out
+    }
+
+    fn write_prometheus(
+        &self,
+        out: &mut String,
+        upstreams: &[UpstreamStatus],
+        cache_entries: Option<usize>,
+    ) -> fmt::Result {
+        writeln!(
+            out,
+            "# HELP donut_queries_total Queries received by HTTP method and request format."
+        )?;
+        writeln!(out, "# TYPE donut_queries_total counter")?;
+        for ((method, format), v) in self.queries.values() {
+            writeln!(
+                out,
+                "donut_queries_total{{method=\"{}\",format=\"{}\"}} {}",
+                method, format, v
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_responses_total Responses to queries by DNS response code."
+        )?;
+        writeln!(out, "# TYPE donut_responses_total counter")?;
+        for (code, v) in self.responses.values() {
+            writeln!(out, "donut_responses_total{{rcode=\"{}\"}} {}", rcode_label(code), v)?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_response_bytes_total Bytes of successful response bodies by response format."
+        )?;
+        writeln!(out, "# TYPE donut_response_bytes_total counter")?;
+        for (format, v) in self.response_bytes.values() {
+            writeln!(out, "donut_response_bytes_total{{format=\"{}\"}} {}", format, v)?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_errors_total Errors resolving queries by kind."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_errors_total counter")?;
+        for (kind, v) in self.errors.values() {
+            writeln!(out, "donut_upstream_errors_total{{kind=\"{}\"}} {}", kind, v)?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_resolve_duration_seconds Time taken by the resolver to answer queries."
+        )?;
+        writeln!(out, "# TYPE donut_resolve_duration_seconds histogram")?;
+        for (bound, v) in self.latency.buckets() {
+            writeln!(out, "donut_resolve_duration_seconds_bucket{{le=\"{}\"}} {}", bound, v)?;
+        }
+        writeln!(
+            out,
+            "donut_resolve_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.latency.count()
+        )?;
+        writeln!(out, "donut_resolve_duration_seconds_sum {}", self.latency.sum())?;
+        writeln!(out, "donut_resolve_duration_seconds_count {}", self.latency.count())?;
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_healthy Whether the last query sent to an upstream server succeeded."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_healthy gauge")?;
+        for u in upstreams {
+            writeln!(
+                out,
+                "donut_upstream_healthy{{upstream=\"{}\"}} {}",
+                u.addr,
+                u8::from(u.healthy)
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_queries_total Queries sent to an upstream server, including health checks."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_queries_total counter")?;
+        for u in upstreams {
+            writeln!(
+                out,
+                "donut_upstream_queries_total{{upstream=\"{}\"}} {}",
+                u.addr, u.queries
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_query_errors_total Queries sent to an upstream server that timed out or failed."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_query_errors_total counter")?;
+        for u in upstreams {
+            writeln!(
+                out,
+                "donut_upstream_query_errors_total{{upstream=\"{}\"}} {}",
+                u.addr, u.errors
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_query_duration_seconds Time spent waiting for responses from an upstream server."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_query_duration_seconds summary")?;
+        for u in upstreams {
+            writeln!(
+                out,
+                "donut_upstream_query_duration_seconds_sum{{upstream=\"{}\"}} {}",
+                u.addr,
+                u.latency.as_secs_f64()
+            )?;
+            writeln!(
+                out,
+                "donut_upstream_query_duration_seconds_count{{upstream=\"{}\"}} {}",
+                u.addr, u.queries
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_connections_total Clients created for an upstream server, including reconnects."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_connections_total counter")?;
+        for u in upstreams {
+            writeln!(
+                out,
+                "donut_upstream_connections_total{{upstream=\"{}\"}} {}",
+                u.addr, u.connections
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_reconnects_total Clients created to replace one that exited or was closed."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_reconnects_total counter")?;
+        for u in upstreams {
+            writeln!(
+                out,
+                "donut_upstream_reconnects_total{{upstream=\"{}\"}} {}",
+                u.addr, u.reconnects
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP donut_upstream_connection_errors_total Errors connecting to an upstream server by reason."
+        )?;
+        writeln!(out, "# TYPE donut_upstream_connection_errors_total counter")?;
+        for u in upstreams {
+            for (reason, v) in u.connection_errors.by_reason() {
+                writeln!(
+                    out,
+                    "donut_upstream_connection_errors_total{{upstream=\"{}\",reason=\"{}\"}} {}",
+                    u.addr, reason, v
+                )?;
+            }
+        }
+
+        if let Some(entries) = cache_entries {
+            writeln!(out, "# HELP donut_cache_entries Responses stored in the cache.")?;
+            writeln!(out, "# TYPE donut_cache_entries gauge")?;
+            writeln!(out, "donut_cache_entries {}", entries)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new(LatencyBuckets::default())
+    }
+}
+
+/// Mnemonic for common DNS response codes or the numeric value for anything else
+fn rcode_label(code: u16) -> String {
+    match code {
+        0 => "NOERROR".to_owned(),
+        1 => "FORMERR".to_owned(),
+        2 => "SERVFAIL".to_owned(),
+        3 => "NXDOMAIN".to_owned(),
+        4 => "NOTIMP".to_owned(),
+        5 => "REFUSED".to_owned(),
+        v => v.to_string(),
+    }
+}
+
+fn error_kind_label(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::Internal => "internal",
+        ErrorKind::Timeout => "timeout",
+        ErrorKind::Unavailable => "unavailable",
+        ErrorKind::InputInvalid => "input_invalid",
+        ErrorKind::InputBodyTooLong => "input_body_too_long",
+        ErrorKind::InputUriTooLong => "input_uri_too_long",
+        ErrorKind::TooManyRequests => "too_many_requests",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencyBuckets, Metrics};
+    use crate::resolve::{ConnectionErrors, UpstreamStatus};
+    use crate::types::ErrorKind;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use trust_dns_client::op::ResponseCode;
+
+    #[test]
+    fn test_render_counters() {
+        let metrics = Metrics::default();
+        metrics.record_query("GET", "json");
+        metrics.record_query("GET", "json");
+        metrics.record_query("POST", "wire");
+        metrics.record_response(ResponseCode::NoError);
+        metrics.record_response(ResponseCode::NXDomain);
+        metrics.record_error(ErrorKind::Timeout);
+        metrics.record_response_bytes("json", 100);
+        metrics.record_response_bytes("json", 20);
+        metrics.record_response_bytes("wire", 64);
+        metrics.observe_latency(Duration::from_millis(3));
+
+        let out = metrics.render(&[], None);
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert!(lines.contains(&"donut_queries_total{method=\"GET\",format=\"json\"} 2"));
+        assert!(lines.contains(&"donut_queries_total{method=\"POST\",format=\"wire\"} 1"));
+        assert!(lines.contains(&"donut_responses_total{rcode=\"NOERROR\"} 1"));
+        assert!(lines.contains(&"donut_responses_total{rcode=\"NXDOMAIN\"} 1"));
+        assert!(lines.contains(&"donut_upstream_errors_total{kind=\"timeout\"} 1"));
+        assert!(lines.contains(&"donut_response_bytes_total{format=\"json\"} 120"));
+        assert!(lines.contains(&"donut_response_bytes_total{format=\"wire\"} 64"));
+        assert!(lines.contains(&"donut_resolve_duration_seconds_count 1"));
+        assert!(!out.contains("donut_cache_entries"));
+    }
+
+    #[test]
+    fn test_latency_buckets_custom() {
+        let buckets = LatencyBuckets::from_str("0.25, 0.5,2").unwrap();
+        assert_eq!(&[0.25, 0.5, 2.0], buckets.bounds());
+
+        let metrics = Metrics::new(buckets);
+        metrics.observe_latency(Duration::from_millis(300));
+
+        let out = metrics.render(&[], None);
+        let lines: Vec<&str> = out.lines().collect();
+        let buckets: Vec<&str> = lines
+            .iter()
+            .copied()
+            .filter(|l| l.starts_with("donut_resolve_duration_seconds_bucket"))
+            .collect();
+
+        assert_eq!(
+            vec![
+                "donut_resolve_duration_seconds_bucket{le=\"0.25\"} 0",
+                "donut_resolve_duration_seconds_bucket{le=\"0.5\"} 1",
+                "donut_resolve_duration_seconds_bucket{le=\"2\"} 1",
+                "donut_resolve_duration_seconds_bucket{le=\"+Inf\"} 1",
+            ],
+            buckets
+        );
+    }
+
+    #[test]
+    fn test_latency_buckets_invalid() {
+        assert!(LatencyBuckets::from_str("0.5,0.25").is_err());
+        assert!(LatencyBuckets::from_str("0.5,0.5").is_err());
+        assert!(LatencyBuckets::from_str("0,0.5").is_err());
+        assert!(LatencyBuckets::from_str("-0.1,0.5").is_err());
+        assert!(LatencyBuckets::from_str("0.1,inf").is_err());
+        assert!(LatencyBuckets::from_str("0.1,,0.5").is_err());
+    }
+
+    #[test]
+    fn test_render_upstreams() {
+        let upstreams = [
+            UpstreamStatus {
+                addr: SocketAddr::from(([192, 0, 2, 1], 53)),
+                healthy: true,
+                queries: 10,
+                errors: 1,
+                latency: Duration::from_millis(500),
+                connections: 1,
+                reconnects: 0,
+                connection_errors: ConnectionErrors::default(),
+            },
+            UpstreamStatus {
+                addr: SocketAddr::from(([192, 0, 2, 2], 853)),
+                healthy: false,
+                queries: 4,
+                errors: 4,
+                latency: Duration::from_secs(2),
+                connections: 3,
+                reconnects: 2,
+                connection_errors: ConnectionErrors {
+                    timeout: 1,
+                    connect: 2,
+                    failed: 1,
+                },
+            },
+        ];
+
+        let out = Metrics::default().render(&upstreams, Some(3));
+        let lines: Vec<&str> = out.lines().collect();
+
+        for expected in [
+            "donut_upstream_healthy{upstream=\"192.0.2.1:53\"} 1",
+            "donut_upstream_healthy{upstream=\"192.0.2.2:853\"} 0",
+            "donut_upstream_queries_total{upstream=\"192.0.2.1:53\"} 10",
+            "donut_upstream_queries_total{upstream=\"192.0.2.2:853\"} 4",
+            "donut_upstream_query_errors_total{upstream=\"192.0.2.1:53\"} 1",
+            "donut_upstream_query_errors_total{upstream=\"192.0.2.2:853\"} 4",
+            "donut_upstream_query_duration_seconds_sum{upstream=\"192.0.2.1:53\"} 0.5",
+            "donut_upstream_query_duration_seconds_sum{upstream=\"192.0.2.2:853\"} 2",
+            "donut_upstream_connections_total{upstream=\"192.0.2.1:53\"} 1",
+            "donut_upstream_connections_total{upstream=\"192.0.2.2:853\"} 3",
+            "donut_upstream_reconnects_total{upstream=\"192.0.2.1:53\"} 0",
+            "donut_upstream_reconnects_total{upstream=\"192.0.2.2:853\"} 2",
+            "donut_upstream_connection_errors_total{upstream=\"192.0.2.1:53\",reason=\"timeout\"} 0",
+            "donut_upstream_connection_errors_total{upstream=\"192.0.2.2:853\",reason=\"timeout\"} 1",
+            "donut_upstream_connection_errors_total{upstream=\"192.0.2.2:853\",reason=\"connect\"} 2",
+            "donut_upstream_connection_errors_total{upstream=\"192.0.2.2:853\",reason=\"failed\"} 1",
+            "donut_cache_entries 3",
+        ] {
+            assert!(lines.contains(&expected), "missing: {}", expected);
+        }
+    }
+}
@@ -18,29 +18,118 @@
 
 use crate::types::{DonutError, DonutResult, ErrorKind};
 use bytes::Bytes;
-use trust_dns_client::op::{MessageType, OpCode, Query};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use trust_dns_client::op::{Edns, MessageType, OpCode, Query};
 use trust_dns_client::proto::op::Message;
-use trust_dns_client::proto::serialize::binary::BinDecodable;
+use trust_dns_client::proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_client::proto::serialize::binary::{BinDecodable, BinDecoder};
 use trust_dns_client::proto::xfer::{DnsRequest, DnsRequestOptions};
 use trust_dns_client::rr::{Name, RecordType};
 
+/// Default max number of labels allowed in query names, the most allowed by DNS
+pub const DEFAULT_MAX_LABELS: u8 = 127;
+
+/// UDP payload size advertised to the upstream server when adding an OPT record to JSON
+/// requests, the size recommended to avoid IP fragmentation (DNS flag day 2020)
+const EDNS_MAX_PAYLOAD: u16 = 1232;
+
+/// How to handle JSON requests where the name is an IP address (`name=1.2.3.4`) instead of
+/// a domain name, which would otherwise be sent upstream as a name that can't exist.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IpLiteralPolicy {
+    /// Reject the request as invalid
+    #[default]
+    Reject,
+    /// Send the name upstream unchanged, the same as any other name
+    Forward,
+    /// Look up the PTR record for the reverse name of the address instead for A, AAAA, or PTR
+    /// queries, rejecting queries for any other type
+    Ptr,
+}
+
+impl FromStr for IpLiteralPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "forward" => Ok(IpLiteralPolicy::Forward),
+            "reject" => Ok(IpLiteralPolicy::Reject),
+            "ptr" => Ok(IpLiteralPolicy::Ptr),
+            _ => Err(format!("expected 'forward', 'reject', or 'ptr', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for IpLiteralPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpLiteralPolicy::Forward => write!(f, "forward"),
+            IpLiteralPolicy::Reject => write!(f, "reject"),
+            IpLiteralPolicy::Ptr => write!(f, "ptr"),
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone)]
-pub struct RequestParserJsonGet;
+pub struct RequestParserJsonGet {
+    validator: RequestValidator,
+    ip_literal: IpLiteralPolicy,
+}
 
 impl RequestParserJsonGet {
-    pub fn new() -> Self {
-        RequestParserJsonGet
+    pub fn new(validator: RequestValidator) -> Self {
+        RequestParserJsonGet {
+            validator,
+            ip_literal: IpLiteralPolicy::default(),
+        }
+    }
+
+    /// Handle requests where the name is an IP address according to `policy`
+    pub fn with_ip_literal(mut self, policy: IpLiteralPolicy) -> Self {
+        self.ip_literal = policy;
+        self
     }
 
-    pub async fn parse(&self, name: String, kind: String, checking_disabled: bool) -> DonutResult<DnsRequest> {
-        let parsed_name = Self::parse_query_name(&name)?;
+    /// Build a request for the given name and type, setting the DO bit in an OPT record to ask
+    /// for DNSSEC records if `dnssec_ok` is set.
+    ///
+    /// If `client_subnet` is given (`<ip>/<prefix>` or just `<ip>`), it's sent upstream as an
+    /// EDNS Client Subnet option. Requests never include the option otherwise.
+    pub async fn parse(
+        &self,
+        name: String,
+        kind: String,
+        checking_disabled: bool,
+        dnssec_ok: bool,
+        client_subnet: Option<&str>,
+    ) -> DonutResult<DnsRequest> {
         let parsed_kind = Self::parse_query_type(&kind)?;
+        let (parsed_name, parsed_kind) = match self.ip_literal(&name, parsed_kind)? {
+            Some(reverse) => (reverse, RecordType::PTR),
+            None => (Self::parse_query_name(&name)?, parsed_kind),
+        };
+        let parsed_subnet = client_subnet
+            .map(|s| {
+                s.parse::<ClientSubnet>()
+                    .map_err(|_| DonutError::from((ErrorKind::InputInvalid, "invalid client subnet")))
+            })
+            .transpose()?;
 
         let mut message = Message::default();
         message.add_query(Query::query(parsed_name, parsed_kind));
         message.set_checking_disabled(checking_disabled);
         message.set_recursion_desired(true);
-        message = validate_message(message)?;
+
+        if dnssec_ok {
+            new_edns(&mut message).set_dnssec_ok(true);
+        }
+
+        if let Some(subnet) = parsed_subnet {
+            new_edns(&mut message).options_mut().insert(subnet.to_option());
+        }
+        message = self.validator.validate(message)?;
 
         tracing::trace!(request = ?message);
         let meta = DnsRequestOptions {
@@ -51,12 +140,49 @@ impl RequestParserJsonGet {
         Ok(DnsRequest::new(message, meta))
     }
 
+    /// Reverse name to query for if the name is an IP address and they're looked up as PTR
+    /// queries, an error if they're rejected, or `None` if the name isn't an IP address or
+    /// they're forwarded unchanged
+    fn ip_literal(&self, name: &str, kind: RecordType) -> DonutResult<Option<Name>> {
+        let name = name.trim();
+        let addr = match name.strip_suffix('.').unwrap_or(name).parse::<IpAddr>() {
+            Ok(a) => a,
+            Err(_) => return Ok(None),
+        };
+
+        match self.ip_literal {
+            IpLiteralPolicy::Forward => Ok(None),
+            IpLiteralPolicy::Reject => Err(DonutError::from((
+                ErrorKind::InputInvalid,
+                "query name is an IP address, use a PTR query for its reverse name",
+            ))),
+            IpLiteralPolicy::Ptr => match kind {
+                RecordType::A | RecordType::AAAA | RecordType::PTR => Ok(Some(Name::from(addr))),
+                _ => Err(DonutError::from((
+                    ErrorKind::InputInvalid,
+                    "query name is an IP address, only A, AAAA, or PTR queries can be converted to a PTR query",
+                ))),
+            },
+        }
+    }
+
     fn parse_query_name(name: &str) -> DonutResult<Name> {
+        // Names with null bytes, newlines, or other control characters will never be valid
+        // so reject them with a clear error instead of a confusing parse failure (or worse,
+        // sending them to the upstream server).
+        if name.chars().any(|c| c.is_control()) {
+            return Err(DonutError::from((
+                ErrorKind::InputInvalid,
+                "control characters in query name",
+            )));
+        }
+
         name.parse()
             .map_err(|_| DonutError::from((ErrorKind::InputInvalid, "invalid query name")))
     }
 
     fn parse_query_type(kind: &str) -> DonutResult<RecordType> {
+        let kind = kind.trim();
         let parsed_type: Option<RecordType> = kind
             // Attempt to parse the input string as a number (1..65535)
             .parse::<u16>()
@@ -67,45 +193,183 @@ impl RequestParserJsonGet {
                 RecordType::Unknown(_) => None,
                 _ => Some(r),
             })
-            // If it wasn't a number, try to parse it as a string (A, AAAA, etc).
-            .or_else(|| kind.to_uppercase().parse().ok());
+            // If it wasn't a number, try to parse it as a string (A, AAAA, etc). Mnemonics
+            // are matched in any case and `*` is accepted as an alias for ANY. Trust DNS panics
+            // when parsing anything that isn't alphanumeric so handle `*` and reject other
+            // punctuation here.
+            .or_else(|| match kind {
+                "*" => Some(RecordType::ANY),
+                _ if kind.chars().all(|c| c.is_ascii_alphanumeric()) => kind.to_uppercase().parse().ok(),
+                _ => None,
+            });
 
         parsed_type.ok_or_else(|| DonutError::from((ErrorKind::InputInvalid, "invalid query type")))
     }
 }
 
+/// OPT record of a message, adding one with the payload size we advertise if there isn't one
+fn new_edns(message: &mut Message) -> &mut Edns {
+    if message.edns().is_none() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_MAX_PAYLOAD);
+        edns.set_version(0);
+        message.set_edns(edns);
+    }
+
+    message.edns_mut()
+}
+
+/// EDNS Client Subnet option (RFC 7871) telling the upstream server which network a query
+/// is from so that it can give answers appropriate for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    addr: IpAddr,
+    source_prefix: u8,
+    scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Default prefix length for IPv4 addresses given without one, as recommended by RFC 7871
+    pub const DEFAULT_IPV4_PREFIX: u8 = 24;
+    /// Default prefix length for IPv6 addresses given without one, as recommended by RFC 7871
+    pub const DEFAULT_IPV6_PREFIX: u8 = 56;
+
+    /// Create a new subnet, clearing any bits of the address after the prefix
+    pub fn new(addr: IpAddr, source_prefix: u8) -> Result<Self, String> {
+        let addr = match addr {
+            IpAddr::V4(v) if source_prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(source_prefix)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v) & mask))
+            }
+            IpAddr::V6(v) if source_prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(source_prefix)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v) & mask))
+            }
+            _ => return Err(format!("invalid prefix length {}", source_prefix)),
+        };
+
+        Ok(ClientSubnet {
+            addr,
+            source_prefix,
+            scope_prefix: 0,
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn source_prefix(&self) -> u8 {
+        self.source_prefix
+    }
+
+    /// Prefix length the upstream server says its answer is valid for, zero in queries
+    pub fn scope_prefix(&self) -> u8 {
+        self.scope_prefix
+    }
+
+    /// Client subnet option of a message, if it has one that's valid
+    pub fn from_edns(edns: &Edns) -> Option<Self> {
+        let data: Vec<u8> = edns.option(EdnsCode::Subnet)?.into();
+        if data.len() < 4 {
+            return None;
+        }
+
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let (source_prefix, scope_prefix) = (data[2], data[3]);
+        let addr = match family {
+            1 => {
+                let mut octets = [0; 4];
+                let len = data.len().saturating_sub(4).min(octets.len());
+                octets[..len].copy_from_slice(&data[4..4 + len]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 => {
+                let mut octets = [0; 16];
+                let len = data.len().saturating_sub(4).min(octets.len());
+                octets[..len].copy_from_slice(&data[4..4 + len]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        ClientSubnet::new(addr, source_prefix)
+            .ok()
+            .map(|s| ClientSubnet { scope_prefix, ..s })
+    }
+
+    /// Encode as an EDNS option, only including as many bytes of the address as the prefix covers
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets): (u16, Vec<u8>) = match self.addr {
+            IpAddr::V4(v) => (1, v.octets().to_vec()),
+            IpAddr::V6(v) => (2, v.octets().to_vec()),
+        };
+
+        let len = usize::from(self.source_prefix).div_ceil(8);
+        let mut data = Vec::with_capacity(4 + len);
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend_from_slice(&octets[..len]);
+
+        EdnsOption::Unknown(u16::from(EdnsCode::Subnet), data)
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid IP address '{}'", addr))?;
+        let prefix = match prefix {
+            Some(p) => p.parse().map_err(|_| format!("invalid prefix length '{}'", p))?,
+            None if addr.is_ipv4() => Self::DEFAULT_IPV4_PREFIX,
+            None => Self::DEFAULT_IPV6_PREFIX,
+        };
+
+        ClientSubnet::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.source_prefix)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
-pub struct RequestParserWireGet;
+pub struct RequestParserWireGet {
+    validator: RequestValidator,
+    strict: bool,
+}
 
 impl RequestParserWireGet {
-    pub fn new() -> Self {
-        RequestParserWireGet
+    /// Create a new parser, rejecting messages with trailing bytes if `strict` is set
+    pub fn new(validator: RequestValidator, strict: bool) -> Self {
+        RequestParserWireGet { validator, strict }
     }
 
     pub async fn parse(&self, dns: String) -> DonutResult<DnsRequest> {
+        // Limit the length of the URI separately from the size of the DNS message so that
+        // we don't bother decoding anything absurdly long. Messages that are too large after
+        // decoding get the same error as POST requests with bodies that are too large.
+        if dns.len() > crate::MAX_ENCODED_MESSAGE_LENGTH {
+            return Err(DonutError::from((ErrorKind::InputUriTooLong, "URI too long")));
+        }
+
         let bytes = base64::decode_config(&dns, base64::URL_SAFE_NO_PAD)
-            .map_err(|e| DonutError::from((ErrorKind::InputInvalid, "invalid base64 value", Box::new(e))))
-            .and_then(|b| {
-                // Ensure that size of the request (after base64 decoding) isn't longer
-                // than the max DNS message size that we allow (512 bytes, which matches
-                // the limit for POST requests).
-                if b.len() > crate::MAX_MESSAGE_SIZE {
-                    Err(DonutError::from((ErrorKind::InputUriTooLong, "URI too long")))
-                } else {
-                    Ok(b)
-                }
-            })?;
+            .map_err(|e| DonutError::from((ErrorKind::InputInvalid, "invalid base64 value", Box::new(e))))?;
 
         tracing::trace!(message = "parsed base64 bytes", num_bytes = bytes.len());
 
-        let message = Message::from_vec(&bytes)
-            // Any errors while parsing a DNS Message get mapped to invalid input
-            .map_err(|e| DonutError::from((ErrorKind::InputInvalid, "invalid DNS message", Box::new(e))))
-            .map(|mut m| {
-                m.set_recursion_desired(true);
-                m
-            })
-            .and_then(validate_message)?;
+        let message = decode_message(&bytes, self.strict)
+            .map(forwarded_message)
+            .and_then(|m| self.validator.validate(m))?;
 
         tracing::trace!(request = ?message);
         let meta = DnsRequestOptions {
@@ -118,26 +382,21 @@ impl RequestParserWireGet {
 }
 
 #[derive(Debug, Default, Clone)]
-pub struct RequestParserWirePost;
+pub struct RequestParserWirePost {
+    validator: RequestValidator,
+    strict: bool,
+}
 
 impl RequestParserWirePost {
-    pub fn new() -> Self {
-        RequestParserWirePost
+    /// Create a new parser, rejecting messages with trailing bytes if `strict` is set
+    pub fn new(validator: RequestValidator, strict: bool) -> Self {
+        RequestParserWirePost { validator, strict }
     }
 
     pub async fn parse(&self, bytes: Bytes) -> DonutResult<DnsRequest> {
-        // Assert (and potential panic) here because the length of the request body should have
-        // been validated already by the HTTP layer. If it hasn't, that's a bug in the server.
-        assert!(bytes.len() <= crate::MAX_MESSAGE_SIZE);
-
-        let message = Message::from_bytes(bytes.as_ref())
-            // Any errors while parsing a DNS Message get mapped to invalid input
-            .map_err(|e| DonutError::from((ErrorKind::InputInvalid, "invalid DNS message", Box::new(e))))
-            .map(|mut m| {
-                m.set_recursion_desired(true);
-                m
-            })
-            .and_then(validate_message)?;
+        let message = decode_message(bytes.as_ref(), self.strict)
+            .map(forwarded_message)
+            .and_then(|m| self.validator.validate(m))?;
 
         tracing::trace!(request = ?message);
         let meta = DnsRequestOptions {
@@ -149,23 +408,349 @@ impl RequestParserWirePost {
     }
 }
 
-/// Perform extra semantic validation of DNS Messages
-fn validate_message(message: Message) -> DonutResult<Message> {
-    // We only parse incoming queries, reject anything else (updates, notifications, responses)
-    if message.message_type() != MessageType::Query || message.op_code() != OpCode::Query {
+/// Decode a DNS message, optionally rejecting any bytes left over after the message
+///
+/// Messages larger than `MAX_MESSAGE_SIZE` are rejected the same way for GET and POST requests.
+fn decode_message(bytes: &[u8], strict: bool) -> DonutResult<Message> {
+    if bytes.len() > crate::MAX_MESSAGE_SIZE {
+        return Err(DonutError::from((ErrorKind::InputBodyTooLong, "DNS message too long")));
+    }
+
+    let mut decoder = BinDecoder::new(bytes);
+    let message = Message::read(&mut decoder)
+        // Any errors while parsing a DNS Message get mapped to invalid input
+        .map_err(|e| DonutError::from((ErrorKind::InputInvalid, "invalid DNS message", Box::new(e))))?;
+
+    if strict && !decoder.is_empty() {
         return Err(DonutError::from((
             ErrorKind::InputInvalid,
-            "invalid message type or op code",
+            "trailing data after DNS message",
         )));
     }
 
-    // NOTE: We use  the queries slice here instead of .query_count() since query counts
-    // are only updated when message is "finalized" right before being sent to the server.
-    // When we build the message piecemeal like for JSON requests, we don't have a "finalized"
-    // message when validating it.
-    if message.queries().is_empty() {
-        return Err(DonutError::from((ErrorKind::InputInvalid, "no DNS queries in message")));
+    Ok(message)
+}
+
+/// Set the flags of a decoded wire format message for forwarding it upstream
+///
+/// Only RD is overridden since we're always asking a recursive resolver, any other flags set by
+/// the client (such as CD) are forwarded unchanged. The same goes for the OPT record: the payload
+/// size, DO bit, and options (cookies, ECS, padding, keepalive, etc.) are all sent upstream as the
+/// client set them. Note that Trust DNS keeps options keyed by code so their order may change and
+/// only the last of any repeated option is kept.
+fn forwarded_message(mut message: Message) -> Message {
+    message.set_recursion_desired(true);
+    message
+}
+
+/// Perform extra semantic validation of DNS Messages
+///
+/// This is shared by all parsers so it's also where messages are changed before being sent
+/// upstream regardless of the format they were sent in (e.g. removing client subnet options).
+#[derive(Debug, Clone)]
+pub struct RequestValidator {
+    max_labels: u8,
+    strip_ecs: bool,
+}
+
+impl RequestValidator {
+    pub fn new(max_labels: u8) -> Self {
+        RequestValidator {
+            max_labels,
+            strip_ecs: false,
+        }
     }
 
-    Ok(message)
+    /// Remove any EDNS Client Subnet option from messages so that information about the
+    /// network of clients is never sent upstream, even if a client includes it.
+    pub fn with_strip_ecs(mut self, strip_ecs: bool) -> Self {
+        self.strip_ecs = strip_ecs;
+        self
+    }
+
+    pub fn validate(&self, mut message: Message) -> DonutResult<Message> {
+        // We only parse incoming queries, reject anything else (updates, notifications, responses)
+        if message.message_type() != MessageType::Query {
+            return Err(DonutError::from((
+                ErrorKind::InputInvalid,
+                "invalid message type, expected query",
+            )));
+        }
+
+        // Never forward anything that could change state on the upstream server (or anything it
+        // forwards to). This match is exhaustive on purpose so that any op codes added to Trust
+        // DNS must be handled here. Op codes Trust DNS doesn't know about (IQUERY, DSO, etc.)
+        // are already rejected when decoding messages.
+        match message.op_code() {
+            OpCode::Query => {}
+            OpCode::Status => {
+                return Err(DonutError::from((
+                    ErrorKind::InputInvalid,
+                    "unsupported op code STATUS",
+                )))
+            }
+            OpCode::Notify => {
+                return Err(DonutError::from((
+                    ErrorKind::InputInvalid,
+                    "unsupported op code NOTIFY",
+                )))
+            }
+            OpCode::Update => {
+                return Err(DonutError::from((
+                    ErrorKind::InputInvalid,
+                    "unsupported op code UPDATE",
+                )))
+            }
+        }
+
+        // NOTE: We use  the queries slice here instead of .query_count() since query counts
+        // are only updated when message is "finalized" right before being sent to the server.
+        // When we build the message piecemeal like for JSON requests, we don't have a "finalized"
+        // message when validating it.
+        if message.queries().is_empty() {
+            return Err(DonutError::from((ErrorKind::InputInvalid, "no DNS queries in message")));
+        }
+
+        // Zone transfers are queries as far as the op code is concerned but they're meant for
+        // replication between name servers, not for clients of a recursive resolver.
+        if message
+            .queries()
+            .iter()
+            .any(|q| matches!(q.query_type(), RecordType::AXFR | RecordType::IXFR))
+        {
+            return Err(DonutError::from((
+                ErrorKind::InputInvalid,
+                "zone transfers are not supported",
+            )));
+        }
+
+        // Deeply nested names can be used to probe or abuse recursive resolvers so reject
+        // anything with more labels than configured before sending it upstream.
+        if message
+            .queries()
+            .iter()
+            .any(|q| q.name().num_labels() > self.max_labels)
+        {
+            return Err(DonutError::from((
+                ErrorKind::InputInvalid,
+                "too many labels in query name",
+            )));
+        }
+
+        // Only messages that already have an OPT record can have the option, don't add one
+        if self.strip_ecs && message.edns().is_some() {
+            message.edns_mut().options_mut().remove(EdnsCode::Subnet);
+        }
+
+        Ok(message)
+    }
+}
+
+impl Default for RequestValidator {
+    fn default() -> Self {
+        RequestValidator::new(DEFAULT_MAX_LABELS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_message, ClientSubnet, IpLiteralPolicy, RequestParserJsonGet, RequestParserWireGet,
+        RequestParserWirePost, RequestValidator,
+    };
+    use crate::types::ErrorKind;
+    use bytes::Bytes;
+    use std::str::FromStr;
+    use trust_dns_client::op::{Message, Query};
+    use trust_dns_client::proto::rr::rdata::opt::EdnsCode;
+    use trust_dns_client::rr::{Name, RecordType};
+
+    const IPV4_REVERSE: &str = "4.3.2.1.in-addr.arpa";
+    const IPV6_REVERSE: &str = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+
+    async fn parse_json(parser: &RequestParserJsonGet, name: &str, kind: &str) -> crate::types::DonutResult<Message> {
+        parser
+            .parse(name.to_owned(), kind.to_owned(), false, false, None)
+            .await
+            .map(|r| Message::clone(&r))
+    }
+
+    #[tokio::test]
+    async fn test_json_ip_literal_rejected_by_default() {
+        let parser = RequestParserJsonGet::default();
+
+        for name in ["1.2.3.4", "2001:db8::1"] {
+            let err = parse_json(&parser, name, "A").await.unwrap_err();
+            assert_eq!(ErrorKind::InputInvalid, err.kind());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_ip_literal_ptr() {
+        let parser = RequestParserJsonGet::default().with_ip_literal(IpLiteralPolicy::Ptr);
+
+        for (name, kind, reverse) in [("1.2.3.4", "A", IPV4_REVERSE), ("2001:db8::1", "AAAA", IPV6_REVERSE)] {
+            let req = parse_json(&parser, name, kind).await.unwrap();
+            let q = &req.queries()[0];
+
+            assert_eq!(RecordType::PTR, q.query_type());
+            assert_eq!(reverse, q.name().to_utf8().trim_end_matches('.'));
+        }
+
+        let err = parse_json(&parser, "1.2.3.4", "MX").await.unwrap_err();
+        assert_eq!(ErrorKind::InputInvalid, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_json_ip_literal_forward() {
+        let parser = RequestParserJsonGet::default().with_ip_literal(IpLiteralPolicy::Forward);
+        let req = parse_json(&parser, "1.2.3.4", "A").await.unwrap();
+
+        assert_eq!(RecordType::A, req.queries()[0].query_type());
+        assert_eq!("1.2.3.4", req.queries()[0].name().to_utf8().trim_end_matches('.'));
+    }
+
+    /// Wire format query followed by `trailing` extra bytes
+    fn query_bytes(trailing: usize) -> Vec<u8> {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A));
+
+        let mut bytes = msg.to_vec().unwrap();
+        bytes.extend(std::iter::repeat(0).take(trailing));
+        bytes
+    }
+
+    #[test]
+    fn test_decode_message_trailing_bytes() {
+        assert!(decode_message(&query_bytes(0), true).is_ok());
+        assert!(decode_message(&query_bytes(0), false).is_ok());
+        assert!(decode_message(&query_bytes(2), false).is_ok());
+
+        let err = decode_message(&query_bytes(2), true).unwrap_err();
+        assert_eq!(ErrorKind::InputInvalid, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_wire_get_trailing_bytes() {
+        let encode = |bytes: Vec<u8>| base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD);
+        let lenient = RequestParserWireGet::new(RequestValidator::default(), false);
+        let strict = RequestParserWireGet::new(RequestValidator::default(), true);
+
+        assert!(lenient.parse(encode(query_bytes(0))).await.is_ok());
+        assert!(lenient.parse(encode(query_bytes(2))).await.is_ok());
+        assert!(strict.parse(encode(query_bytes(0))).await.is_ok());
+        assert!(strict.parse(encode(query_bytes(2))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wire_post_trailing_bytes() {
+        let lenient = RequestParserWirePost::new(RequestValidator::default(), false);
+        let strict = RequestParserWirePost::new(RequestValidator::default(), true);
+
+        assert!(lenient.parse(Bytes::from(query_bytes(0))).await.is_ok());
+        assert!(lenient.parse(Bytes::from(query_bytes(2))).await.is_ok());
+        assert!(strict.parse(Bytes::from(query_bytes(0))).await.is_ok());
+        assert!(strict.parse(Bytes::from(query_bytes(2))).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_query_type() {
+        for (kind, expected) in [
+            ("A", RecordType::A),
+            ("28", RecordType::AAAA),
+            ("*", RecordType::ANY),
+            (" aaaa ", RecordType::AAAA),
+            ("Mx", RecordType::MX),
+        ] {
+            assert_eq!(
+                expected,
+                RequestParserJsonGet::parse_query_type(kind).unwrap(),
+                "kind: {:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_query_type_invalid() {
+        for kind in ["", "A-", "A.", "**", "MX;", "BOGUS"] {
+            let err = RequestParserJsonGet::parse_query_type(kind).unwrap_err();
+            assert_eq!(ErrorKind::InputInvalid, err.kind(), "kind: {:?}", kind);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_control_characters_in_name() {
+        let parser = RequestParserJsonGet::default();
+
+        for name in ["www.exa\0mple.com", "www.example.com\n", "www.example\r\n.com"] {
+            let err = parse_json(&parser, name, "A").await.unwrap_err();
+            assert_eq!(ErrorKind::InputInvalid, err.kind(), "name: {:?}", name);
+            assert!(err.to_string().contains("control characters"), "name: {:?}", name);
+        }
+    }
+
+    fn query_message(name: &str) -> Message {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        msg
+    }
+
+    #[test]
+    fn test_validate_max_labels() {
+        let validator = RequestValidator::new(3);
+
+        assert!(validator.validate(query_message("a.example.com.")).is_ok());
+        assert!(validator.validate(query_message("example.com.")).is_ok());
+
+        let err = validator.validate(query_message("b.a.example.com.")).unwrap_err();
+        assert_eq!(ErrorKind::InputInvalid, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_wire_checking_disabled_preserved() {
+        for checking_disabled in [true, false] {
+            let mut msg = query_message("www.example.com.");
+            msg.set_checking_disabled(checking_disabled);
+            let bytes = msg.to_vec().unwrap();
+
+            let get = RequestParserWireGet::default()
+                .parse(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+                .await
+                .unwrap();
+            let post = RequestParserWirePost::default()
+                .parse(Bytes::from(bytes))
+                .await
+                .unwrap();
+
+            assert_eq!(checking_disabled, get.checking_disabled());
+            assert_eq!(checking_disabled, post.checking_disabled());
+            assert!(get.recursion_desired());
+            assert!(post.recursion_desired());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_client_subnet_round_trip() {
+        let parser = RequestParserJsonGet::default();
+
+        for (param, expected) in [
+            ("192.0.2.123/24", "192.0.2.0/24"),
+            ("2001:db8:1:2::1", "2001:db8:1::/56"),
+        ] {
+            let req = parser
+                .parse("www.example.com".to_owned(), "A".to_owned(), false, false, Some(param))
+                .await
+                .unwrap();
+            let edns = req.edns().unwrap();
+
+            assert!(edns.option(EdnsCode::Subnet).is_some(), "param: {}", param);
+            let subnet = ClientSubnet::from_edns(edns).unwrap();
+            assert_eq!(ClientSubnet::from_str(expected).unwrap(), subnet, "param: {}", param);
+            assert_eq!(0, subnet.scope_prefix());
+        }
+
+        let req = parse_json(&parser, "www.example.com", "A").await.unwrap();
+        assert!(req.edns().is_none());
+    }
 }
@@ -16,52 +16,2264 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use crate::types::DonutResult;
+#[cfg(feature = "dnstap")]
+use crate::dnstap::{DnstapLogger, SocketProtocol};
+use crate::response::{synthesize_address_answers, synthesize_response, DEFAULT_SYNTHETIC_TTL};
+use crate::types::{DonutError, DonutResult, ErrorKind};
+use futures_util::{future, Stream};
+use rand::Rng;
 use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::webpki::{DNSName, DNSNameRef};
+use tokio_rustls::TlsConnector;
 use trust_dns_client::client::AsyncClient;
-use trust_dns_client::op::DnsResponse;
-use trust_dns_client::proto::xfer::DnsRequest;
+use trust_dns_client::op::{DnsResponse, Message, Query, ResponseCode};
+use trust_dns_client::proto::error::{ProtoError, ProtoErrorKind};
+use trust_dns_client::proto::iocompat::AsyncIoTokioAsStd;
+use trust_dns_client::proto::tcp::TcpStream as DnsTcpStream;
+use trust_dns_client::proto::xfer::{
+    BufDnsStreamHandle, DnsRequest, DnsRequestOptions, DnsRequestSender, DnsResponseFuture,
+};
 use trust_dns_client::proto::DnsHandle;
+use trust_dns_client::rr::{DNSClass, Name, RData, Record, RecordType};
+use trust_dns_client::serialize::txt::{Lexer, Parser};
+use trust_dns_client::tcp::TcpClientStream;
 
-/// Facade over a Trust DNS `AsyncClient` instance (UDP).
+/// Default number of dots in a name for it to not be retried with a search domain
+pub const DEFAULT_NDOTS: u8 = 1;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest UDP response that may be sent to a query without EDNS (RFC 1035)
+const MIN_UDP_PAYLOAD: u16 = 512;
+
+/// Local addresses to send queries to upstream servers from, for each address family. The
+/// operating system picks the address when one isn't set for the family of a server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SourceAddrs {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl SourceAddrs {
+    /// Source address for queries to an upstream server, if one is set for its family
+    fn for_upstream(&self, upstream: SocketAddr) -> Option<IpAddr> {
+        match upstream {
+            SocketAddr::V4(_) => self.v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+    }
+}
+
+/// Sends queries to an upstream server over UDP, from a particular local address if set.
+///
+/// This is used in place of the UDP client from Trust DNS, which always binds its socket to
+/// the unspecified address and silently drops responses that don't fit in a 2048 byte buffer
+/// (leaving the query to time out). Like that client, each query uses a new socket and a random
+/// ID and responses with any other ID are ignored.
+struct UdpSender {
+    upstream: SocketAddr,
+    source: Option<IpAddr>,
+    timeout: Duration,
+    is_shutdown: bool,
+}
+
+impl DnsRequestSender for UdpSender {
+    fn send_message(&mut self, mut req: DnsRequest) -> DnsResponseFuture {
+        req.set_id(rand::random());
+        let query = match req.to_vec() {
+            Ok(bytes) => bytes,
+            Err(e) => return e.into(),
+        };
+
+        let max_size = usize::from(
+            req.edns()
+                .map_or(MIN_UDP_PAYLOAD, |e| e.max_payload().max(MIN_UDP_PAYLOAD)),
+        );
+        let (upstream, source, timeout, id) = (self.upstream, self.source, self.timeout, req.id());
+        DnsResponseFuture::from(Box::pin(async move {
+            match tokio::time::timeout(timeout, send_udp_from(source, upstream, id, query, max_size)).await {
+                Ok(res) => res,
+                Err(_) => Err(ProtoError::from(ProtoErrorKind::Timeout)),
+            }
+        }))
+    }
+
+    fn shutdown(&mut self) {
+        self.is_shutdown = true;
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.is_shutdown
+    }
+}
+
+impl Stream for UdpSender {
+    type Item = Result<(), ProtoError>;
+
+    // There's no connection to drive since each query has its own socket
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_shutdown {
+            Poll::Ready(None)
+        } else {
+            Poll::Ready(Some(Ok(())))
+        }
+    }
+}
+
+/// Send a query from a new socket bound to `source` (or the unspecified address) and wait
+/// for the response with the same ID.
+///
+/// Responses larger than `max_size`, the payload size advertised in the query, are marked as
+/// truncated so that they're retried over TCP like any other truncated response. The upstream
+/// server shouldn't have sent them and some of the records may be unusable by clients.
+async fn send_udp_from(
+    source: Option<IpAddr>,
+    upstream: SocketAddr,
+    id: u16,
+    query: Vec<u8>,
+    max_size: usize,
+) -> Result<DnsResponse, ProtoError> {
+    let source = source.unwrap_or_else(|| match upstream {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+
+    // Port zero means the operating system picks a random ephemeral port
+    let socket = UdpSocket::bind(SocketAddr::new(source, 0)).await?;
+    socket.send_to(&query, upstream).await?;
+
+    // Big enough for any UDP datagram so that oversized responses are never cut off
+    let mut buf = vec![0u8; usize::from(u16::MAX)];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from != upstream {
+            continue;
+        }
+
+        // Anything else is a late response to some other query or an attempt to spoof one
+        match Message::from_vec(&buf[..len]) {
+            Ok(mut msg) if msg.id() == id => {
+                if len > max_size {
+                    tracing::debug!(
+                        message = "upstream response larger than advertised size",
+                        upstream = %upstream,
+                        size = len,
+                        max_size = max_size,
+                    );
+                    msg.set_truncated(true);
+                }
+
+                return Ok(DnsResponse::from(msg));
+            }
+            _ => continue,
+        }
+    }
+}
+
+/// Connect to an upstream server over TCP from the source address, if any
+async fn connect_tcp(addr: SocketAddr, source: Option<IpAddr>) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    if let Some(ip) = source {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+
+    socket.connect(addr).await
+}
+
+/// Create a new Trust DNS client for the given upstream server (via DNS over UDP).
+///
+/// The background future that performs network activity for the client is spawned
+/// on the current Tokio runtime so this must be called from within a runtime. If the
+/// background future ever exits, it is logged and a new client is created to replace it.
+/// Queries are sent from the address in `source` for the family of the server, if set.
+pub async fn new_udp_client(addr: SocketAddr, source: SourceAddrs, timeout: Duration) -> DonutResult<UpstreamClient> {
+    new_client(addr, Transport::Udp, source, timeout, false).await
+}
+
+/// Create a new Trust DNS client for the given upstream server (via DNS over TLS).
+///
+/// A single connection is used for all queries and a new one is made if it's closed by
+/// the upstream server. See `new_udp_client` for details about the background future.
+pub async fn new_tls_client(
+    addr: SocketAddr,
+    tls: TlsUpstream,
+    source: SourceAddrs,
+    timeout: Duration,
+) -> DonutResult<UpstreamClient> {
+    new_client(addr, Transport::Tls(tls), source, timeout, false).await
+}
+
+/// Create a new Trust DNS client for the given upstream server (via DNS over UDP) even if
+/// it can't be connected to yet.
+///
+/// Connecting is retried in the background until it succeeds, queries sent before then fail
+/// with `ErrorKind::Unavailable`. See `new_udp_client` for details about the background future.
+pub async fn new_lazy_udp_client(
+    addr: SocketAddr,
+    source: SourceAddrs,
+    timeout: Duration,
+) -> DonutResult<UpstreamClient> {
+    new_client(addr, Transport::Udp, source, timeout, true).await
+}
+
+/// Create a new Trust DNS client for the given upstream server (via DNS over TLS) even if
+/// it can't be connected to yet. See `new_lazy_udp_client` for details.
+pub async fn new_lazy_tls_client(
+    addr: SocketAddr,
+    tls: TlsUpstream,
+    source: SourceAddrs,
+    timeout: Duration,
+) -> DonutResult<UpstreamClient> {
+    new_client(addr, Transport::Tls(tls), source, timeout, true).await
+}
+
+async fn new_client(
+    addr: SocketAddr,
+    transport: Transport,
+    source: SourceAddrs,
+    timeout: Duration,
+    lazy: bool,
+) -> DonutResult<UpstreamClient> {
+    let client = UpstreamClient {
+        client: Arc::new(RwLock::new(None)),
+        transport,
+        source: source.for_upstream(addr),
+        stats: Arc::new(ConnectionStats::default()),
+    };
+
+    let handle = match connect(addr, &client.transport, client.source, timeout).await {
+        Ok((c, h)) => {
+            client.stats.connections.fetch_add(1, Ordering::Relaxed);
+            client.replace(c);
+            Some(h)
+        }
+        Err(e) if lazy => {
+            client.stats.record_connect_error(&e);
+            tracing::warn!(message = "unable to connect upstream client, retrying", upstream = %addr, error = %e);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    tokio::spawn(supervise(addr, timeout, handle, client.clone()));
+    Ok(client)
+}
+
+async fn connect(
+    addr: SocketAddr,
+    transport: &Transport,
+    source: Option<IpAddr>,
+    timeout: Duration,
+) -> DonutResult<(AsyncClient, JoinHandle<Result<(), ProtoError>>)> {
+    // Trust DNS clients are really just handles for talking to a future running in the background
+    // that actually does all the network activity and DNS lookups. Start the background future here
+    // on whatever Tokio executor has been set up when `main()` was run.
+    match transport {
+        Transport::Udp => {
+            let conn = future::ready(Ok(UdpSender {
+                upstream: addr,
+                source,
+                timeout,
+                is_shutdown: false,
+            }));
+            let (client, bg) = AsyncClient::connect(conn).await?;
+            Ok((client, tokio::spawn(bg)))
+        }
+        Transport::Tls(tls) => {
+            let stream = tokio::time::timeout(tls.connect_timeout.unwrap_or(timeout), tls.connect(addr, source))
+                .await
+                .map_err(|_| DonutError::from((ErrorKind::Timeout, "upstream TLS connection timed out")))??;
+
+            // DNS over TLS uses the same framing as DNS over TCP so the TCP client stream from
+            // Trust DNS can be used on top of our TLS connection.
+            let (stream, sender) = DnsTcpStream::from_stream(AsyncIoTokioAsStd(stream), addr);
+            let conn = future::ready(Ok(TcpClientStream::from_stream(stream)));
+            let (client, bg) =
+                AsyncClient::with_timeout(conn, Box::new(BufDnsStreamHandle::new(addr, sender)), timeout, None).await?;
+            Ok((client, tokio::spawn(bg)))
+        }
+    }
+}
+
+/// Wait for the background future of a client to exit and replace the client when it does
+///
+/// If there is no background future (because the client couldn't connect initially), a new
+/// client is created right away.
+async fn supervise(
+    addr: SocketAddr,
+    timeout: Duration,
+    mut handle: Option<JoinHandle<Result<(), ProtoError>>>,
+    client: UpstreamClient,
+) {
+    loop {
+        // The background future only exits normally once all clients using it have been
+        // dropped (which never happens since we hold one) or the connection is closed when
+        // using TLS. Any exit means we're unable to resolve anything until the client is
+        // replaced.
+        if let Some(h) = handle.take() {
+            match h.await {
+                // Servers close idle TLS connections so this is expected, just reconnect
+                Ok(Ok(())) if matches!(client.transport, Transport::Tls(_)) => {
+                    tracing::info!(message = "upstream connection closed", upstream = %addr)
+                }
+                Ok(Ok(())) => tracing::error!(message = "upstream client background task exited", upstream = %addr),
+                Ok(Err(e)) => {
+                    client.stats.failed_errors.fetch_add(1, Ordering::Relaxed);
+                    tracing::error!(message = "upstream client background task failed", upstream = %addr, error = %e)
+                }
+                Err(e) => {
+                    client.stats.failed_errors.fetch_add(1, Ordering::Relaxed);
+                    tracing::error!(message = "upstream client background task panicked", upstream = %addr, error = %e)
+                }
+            }
+        }
+
+        handle = loop {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            match connect(addr, &client.transport, client.source, timeout).await {
+                Ok((c, h)) => {
+                    client.stats.connections.fetch_add(1, Ordering::Relaxed);
+                    client.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                    client.replace(c);
+                    tracing::info!(message = "reconnected upstream client", upstream = %addr);
+                    break Some(h);
+                }
+                Err(e) => {
+                    client.stats.record_connect_error(&e);
+                    tracing::error!(message = "unable to reconnect upstream client", upstream = %addr, error = %e);
+                }
+            }
+        };
+    }
+}
+
+/// How the client talks to the upstream server.
+#[derive(Debug, Clone)]
+enum Transport {
+    Udp,
+    Tls(TlsUpstream),
+}
+
+#[cfg(feature = "dnstap")]
+impl Transport {
+    fn dnstap_protocol(&self) -> SocketProtocol {
+        match self {
+            Transport::Udp => SocketProtocol::Udp,
+            Transport::Tls(_) => SocketProtocol::Dot,
+        }
+    }
+}
+
+/// Settings for connecting to an upstream server using DNS over TLS (RFC 7858).
+#[derive(Clone)]
+pub struct TlsUpstream {
+    connector: TlsConnector,
+    name: DNSName,
+    connect_timeout: Option<Duration>,
+}
+
+impl TlsUpstream {
+    /// Verify that the certificate of the upstream server is valid for `name`, signed by one
+    /// of the root certificates of the system or by one of the certificates in `ca_file` (PEM
+    /// format) instead, if set.
+    pub fn new(name: &str, ca_file: Option<&Path>) -> DonutResult<Self> {
+        let name = DNSNameRef::try_from_ascii_str(name)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "invalid upstream TLS name", e)))?
+            .to_owned();
+
+        let mut config = ClientConfig::new();
+        config.root_store = match ca_file {
+            Some(path) => {
+                let file = fs::File::open(path)
+                    .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to read upstream CA file", e)))?;
+                let mut store = RootCertStore::empty();
+                match store.add_pem_file(&mut io::BufReader::new(file)) {
+                    Ok((valid, _)) if valid > 0 => store,
+                    _ => {
+                        return Err(DonutError::from((
+                            ErrorKind::Internal,
+                            "no valid certificates in upstream CA file",
+                        )))
+                    }
+                }
+            }
+            // Use whatever certificates could be loaded even if some of them were invalid,
+            // connecting will fail anyway if the one we need is missing.
+            None => rustls_native_certs::load_native_certs()
+                .or_else(|(partial, e)| partial.ok_or(e))
+                .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to load system root certificates", e)))?,
+        };
+
+        Ok(TlsUpstream {
+            connector: TlsConnector::from(Arc::new(config)),
+            name,
+            connect_timeout: None,
+        })
+    }
+
+    /// Limit the time taken to connect to the upstream server (including the TLS handshake)
+    /// separately from queries. If not set, the query timeout of the client is used.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    async fn connect(&self, addr: SocketAddr, source: Option<IpAddr>) -> DonutResult<TlsStream<TcpStream>> {
+        let tcp = connect_tcp(addr, source)
+            .await
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to connect to upstream server", e)))?;
+
+        self.connector
+            .connect(self.name.as_ref(), tcp)
+            .await
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "upstream TLS handshake failed", e)))
+    }
+}
+
+impl fmt::Debug for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name: &str = self.name.as_ref().into();
+        write!(
+            f,
+            "TlsUpstream {{ name: {:?}, connect_timeout: {:?} }}",
+            name, self.connect_timeout
+        )
+    }
+}
+
+/// Trust DNS client (via DNS over UDP or TLS) that is replaced if its background future exits.
+///
+/// Cloning this is cheap and all clones share the same underlying client. There is no
+/// underlying client until the upstream server has been connected to when created with
+/// `new_lazy_udp_client` or `new_lazy_tls_client`.
+#[derive(Clone)]
+pub struct UpstreamClient {
+    client: Arc<RwLock<Option<AsyncClient>>>,
+    transport: Transport,
+    /// Local address to send queries from, if set for the family of the upstream server
+    source: Option<IpAddr>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl UpstreamClient {
+    /// Get a handle to the current underlying client, if connected
+    fn get(&self) -> DonutResult<AsyncClient> {
+        self.client
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .ok_or_else(|| DonutError::from((ErrorKind::Unavailable, "upstream server not connected yet")))
+    }
+
+    fn replace(&self, client: AsyncClient) {
+        *self.client.write().unwrap_or_else(PoisonError::into_inner) = Some(client);
+    }
+}
+
+/// Counts of the clients created for an upstream server and errors creating or using them.
+///
+/// For UDP, a "connection" is just a new client since there's no connection to speak of.
+#[derive(Debug, Default)]
+struct ConnectionStats {
+    /// Clients created, including the first one
+    connections: AtomicU64,
+    /// Clients created to replace one whose background future exited
+    reconnects: AtomicU64,
+    timeout_errors: AtomicU64,
+    connect_errors: AtomicU64,
+    failed_errors: AtomicU64,
+}
+
+impl ConnectionStats {
+    fn record_connect_error(&self, err: &DonutError) {
+        match err.kind() {
+            ErrorKind::Timeout => self.timeout_errors.fetch_add(1, Ordering::Relaxed),
+            _ => self.connect_errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    fn errors(&self) -> ConnectionErrors {
+        ConnectionErrors {
+            timeout: self.timeout_errors.load(Ordering::Relaxed),
+            connect: self.connect_errors.load(Ordering::Relaxed),
+            failed: self.failed_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Errors connecting to an upstream server or using an existing connection to it, by reason
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionErrors {
+    /// Connecting (including the TLS handshake) took longer than the timeout
+    pub timeout: u64,
+    /// Connecting failed for any other reason
+    pub connect: u64,
+    /// The background future of a client failed or panicked instead of exiting normally
+    pub failed: u64,
+}
+
+impl ConnectionErrors {
+    /// Count of errors for each reason, labeled for use in metrics
+    pub fn by_reason(&self) -> [(&'static str, u64); 3] {
+        [
+            ("timeout", self.timeout),
+            ("connect", self.connect),
+            ("failed", self.failed),
+        ]
+    }
+}
+
+/// How to respond to clients when the upstream server returns SERVFAIL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ServFailPolicy {
+    /// Return the SERVFAIL response from the upstream server as-is
+    #[default]
+    Propagate,
+    /// Return an NXDOMAIN response instead
+    NxDomain,
+    /// Answer A or AAAA queries with the given address instead. Queries that can't be answered
+    /// with the address, including those for the other address family, get the SERVFAIL as-is.
+    Sinkhole(IpAddr),
+}
+
+impl ServFailPolicy {
+    fn apply(&self, req: &DnsRequest, res: DnsResponse, ttl: u32) -> DnsResponse {
+        if res.response_code() != ResponseCode::ServFail {
+            return res;
+        }
+
+        match self {
+            ServFailPolicy::Propagate => res,
+            ServFailPolicy::NxDomain => synthesize_response(req, ResponseCode::NXDomain, Vec::new()),
+            ServFailPolicy::Sinkhole(addr) => {
+                let answers = synthesize_address_answers(req.queries(), *addr, ttl);
+                if answers.is_empty() {
+                    res
+                } else {
+                    synthesize_response(req, ResponseCode::NoError, answers)
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for ServFailPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "propagate" => Ok(ServFailPolicy::Propagate),
+            "nxdomain" => Ok(ServFailPolicy::NxDomain),
+            v => v
+                .parse()
+                .map(ServFailPolicy::Sinkhole)
+                .map_err(|_| format!("expected 'propagate', 'nxdomain', or an IP address, got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for ServFailPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServFailPolicy::Propagate => write!(f, "propagate"),
+            ServFailPolicy::NxDomain => write!(f, "nxdomain"),
+            ServFailPolicy::Sinkhole(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Response given to every query while in maintenance mode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceResponse {
+    /// Return a SERVFAIL response
+    #[default]
+    ServFail,
+    /// Return an NXDOMAIN response
+    NxDomain,
+    /// Answer A or AAAA queries with the given address
+    Sinkhole(IpAddr),
+}
+
+impl FromStr for MaintenanceResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "servfail" => Ok(MaintenanceResponse::ServFail),
+            "nxdomain" => Ok(MaintenanceResponse::NxDomain),
+            v => v
+                .parse()
+                .map(MaintenanceResponse::Sinkhole)
+                .map_err(|_| format!("expected 'servfail', 'nxdomain', or an IP address, got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaintenanceResponse::ServFail => write!(f, "servfail"),
+            MaintenanceResponse::NxDomain => write!(f, "nxdomain"),
+            MaintenanceResponse::Sinkhole(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Maintenance mode that can be turned on and off at runtime, answering every query with a
+/// fixed response instead of resolving it while on.
+#[derive(Debug)]
+pub struct Maintenance {
+    response: MaintenanceResponse,
+    ttl: u32,
+    enabled: AtomicBool,
+}
+
+impl Maintenance {
+    /// Create a new maintenance mode, answering with records using `ttl` for sinkhole responses
+    pub fn new(response: MaintenanceResponse, ttl: u32, enabled: bool) -> Self {
+        Maintenance {
+            response,
+            ttl,
+            enabled: AtomicBool::new(enabled),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+
+    /// Switch maintenance mode on if it's off or off if it's on, returning the new state
+    pub fn toggle(&self) -> bool {
+        !self.enabled.fetch_xor(true, Ordering::AcqRel)
+    }
+
+    /// Build the maintenance response for a request if maintenance mode is on
+    pub fn answer(&self, req: &DnsRequest) -> Option<DnsResponse> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        Some(match self.response {
+            MaintenanceResponse::ServFail => synthesize_response(req, ResponseCode::ServFail, Vec::new()),
+            MaintenanceResponse::NxDomain => synthesize_response(req, ResponseCode::NXDomain, Vec::new()),
+            MaintenanceResponse::Sinkhole(addr) => synthesize_response(
+                req,
+                ResponseCode::NoError,
+                synthesize_address_answers(req.queries(), addr, self.ttl),
+            ),
+        })
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance::new(MaintenanceResponse::default(), DEFAULT_SYNTHETIC_TTL, false)
+    }
+}
+
+/// How to pick which upstream server to send each query to when there are several.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamStrategy {
+    /// Use the upstream server that most recently succeeded, trying the others in order on failure
+    #[default]
+    Failover,
+    /// Use each healthy upstream server in turn, trying the next one on failure
+    RoundRobin,
+}
+
+impl FromStr for UpstreamStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "failover" => Ok(UpstreamStrategy::Failover),
+            "round-robin" => Ok(UpstreamStrategy::RoundRobin),
+            _ => Err(format!("expected 'failover' or 'round-robin', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamStrategy::Failover => write!(f, "failover"),
+            UpstreamStrategy::RoundRobin => write!(f, "round-robin"),
+        }
+    }
+}
+
+/// How to handle queries with more than one question, which most DNS servers don't support.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MultiQuestionPolicy {
+    /// Reject the query as invalid input
+    Reject,
+    /// Send each question upstream as a separate query and merge the responses
+    Split,
+    /// Send the query upstream as-is
+    #[default]
+    Forward,
+}
+
+impl FromStr for MultiQuestionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(MultiQuestionPolicy::Reject),
+            "split" => Ok(MultiQuestionPolicy::Split),
+            "forward" => Ok(MultiQuestionPolicy::Forward),
+            _ => Err(format!("expected 'reject', 'split', or 'forward', got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for MultiQuestionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultiQuestionPolicy::Reject => write!(f, "reject"),
+            MultiQuestionPolicy::Split => write!(f, "split"),
+            MultiQuestionPolicy::Forward => write!(f, "forward"),
+        }
+    }
+}
+
+/// Address and name to answer PTR queries for locally instead of forwarding them upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfPtr {
+    addr: IpAddr,
+    reverse: Name,
+    name: Name,
+}
+
+impl SelfPtr {
+    pub fn new(addr: IpAddr, name: Name) -> Self {
+        SelfPtr {
+            addr,
+            reverse: Name::from(addr),
+            name,
+        }
+    }
+
+    /// Answer the request if it is a single PTR query for our address
+    fn answer(&self, req: &DnsRequest, ttl: u32) -> Option<DnsResponse> {
+        match req.queries() {
+            // NOTE: We compare names with == here since they may or may not be fully qualified
+            // depending on how the request was parsed and equality (unlike hashing) ignores that.
+            [q] if q.query_type() == RecordType::PTR && *q.name() == self.reverse => {
+                let answer = Record::from_rdata(q.name().clone(), ttl, RData::PTR(self.name.clone()));
+                Some(synthesize_response(req, ResponseCode::NoError, vec![answer]))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for SelfPtr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, name) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected '<ip>=<name>', got '{}'", s))?;
+        let addr = addr.parse().map_err(|_| format!("invalid IP address '{}'", addr))?;
+        let mut name = Name::from_utf8(name).map_err(|e| format!("invalid name '{}': {}", name, e))?;
+        name.set_fqdn(true);
+
+        Ok(SelfPtr::new(addr, name))
+    }
+}
+
+impl fmt::Display for SelfPtr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.addr, self.name)
+    }
+}
+
+/// NS records (and optional glue) for the root and TLDs used to answer NS queries locally.
+///
+/// Records are loaded from a file in zone file format, such as the root hints file
+/// distributed by IANA. Only NS queries for names with preloaded NS records are answered,
+/// all other records in the file are only used as glue for the additional section.
+#[derive(Debug, Default, Clone)]
+pub struct Delegations {
+    records: Vec<Record>,
+}
+
+impl Delegations {
+    pub fn new(records: Vec<Record>) -> Self {
+        Delegations { records }
+    }
+
+    /// Load records from a zone file, relative names are relative to the root
+    pub fn from_file<P: AsRef<Path>>(path: P) -> DonutResult<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to read delegations file", e)))?;
+        let (_, sets) = Parser::new()
+            .parse(Lexer::new(&contents), Some(Name::root()), Some(DNSClass::IN))
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to parse delegations file", e)))?;
+
+        Ok(Self::new(
+            sets.into_values()
+                .flat_map(|set| set.records_without_rrsigs().cloned().collect::<Vec<_>>())
+                .collect(),
+        ))
+    }
+
+    /// Number of NS records loaded
+    pub fn len(&self) -> usize {
+        self.records
+            .iter()
+            .filter(|r| r.record_type() == RecordType::NS)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Answer the request if it is a single NS query for a name with preloaded NS records
+    fn answer(&self, req: &DnsRequest) -> Option<DnsResponse> {
+        let q = match req.queries() {
+            [q] if q.query_type() == RecordType::NS => q,
+            _ => return None,
+        };
+
+        // NOTE: Names are compared with == since they may or may not be fully qualified
+        // depending on how the request was parsed, see `SelfPtr::answer`.
+        let answers: Vec<Record> = self
+            .records
+            .iter()
+            .filter(|r| r.record_type() == RecordType::NS && *r.name() == *q.name())
+            .cloned()
+            .collect();
+
+        if answers.is_empty() {
+            return None;
+        }
+
+        let glue: Vec<Record> = self
+            .records
+            .iter()
+            .filter(|r| matches!(r.record_type(), RecordType::A | RecordType::AAAA))
+            .filter(|r| {
+                answers
+                    .iter()
+                    .any(|a| matches!(a.rdata(), RData::NS(target) if *target == *r.name()))
+            })
+            .cloned()
+            .collect();
+
+        let mut res = synthesize_response(req, ResponseCode::NoError, answers);
+        res.insert_additionals(glue);
+        Some(res)
+    }
+}
+
+/// IPv6 addresses to answer AAAA queries with when the upstream server has no AAAA records.
+///
+/// Mappings are loaded from a file with one `<name> <ipv6>` pair per line. Names starting
+/// with `*.` match any subdomain of the rest of the name. Empty lines and lines starting
+/// with `#` are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct AaaaMap {
+    mappings: Vec<AaaaMapping>,
+}
+
+#[derive(Debug, Clone)]
+struct AaaaMapping {
+    name: Name,
+    wildcard: bool,
+    addr: Ipv6Addr,
+}
+
+impl AaaaMap {
+    /// Load mappings from a file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> DonutResult<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to read AAAA map file", e)))?;
+
+        let mut mappings = Vec::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, addr) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [name, addr] => (name, addr),
+                _ => {
+                    return Err(DonutError::from((
+                        ErrorKind::Internal,
+                        "invalid AAAA map entry, expected '<name> <ipv6>'",
+                    )))
+                }
+            };
+
+            let (name, wildcard) = match name.strip_prefix("*.") {
+                Some(rest) => (rest, true),
+                None => (name, false),
+            };
+
+            mappings.push(AaaaMapping {
+                name: Name::from_utf8(name)?,
+                wildcard,
+                addr: addr
+                    .parse()
+                    .map_err(|e| DonutError::from((ErrorKind::Internal, "invalid AAAA map address", e)))?,
+            });
+        }
+
+        Ok(AaaaMap { mappings })
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Answer AAAA queries for mapped names if the response from the upstream server has none
+    fn apply(&self, req: &DnsRequest, res: DnsResponse, ttl: u32) -> DnsResponse {
+        if self.mappings.is_empty()
+            || res.response_code() != ResponseCode::NoError
+            || res.answers().iter().any(|r| r.record_type() == RecordType::AAAA)
+        {
+            return res;
+        }
+
+        let answers: Vec<Record> = req
+            .queries()
+            .iter()
+            .filter(|q| q.query_type() == RecordType::AAAA)
+            .filter_map(|q| {
+                self.mappings
+                    .iter()
+                    .find(|m| {
+                        // NOTE: Names are compared with == since they may or may not be fully
+                        // qualified depending on how the request was parsed, see `SelfPtr::answer`.
+                        if m.wildcard {
+                            m.name.zone_of(q.name()) && m.name != *q.name()
+                        } else {
+                            m.name == *q.name()
+                        }
+                    })
+                    .map(|m| Record::from_rdata(q.name().clone(), ttl, RData::AAAA(m.addr)))
+            })
+            .collect();
+
+        if answers.is_empty() {
+            res
+        } else {
+            synthesize_response(req, ResponseCode::NoError, answers)
+        }
+    }
+}
+
+/// Timeout to use instead of the default upstream timeout for queries of a particular type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeTimeout {
+    kind: RecordType,
+    timeout: Duration,
+}
+
+impl TypeTimeout {
+    pub fn new(kind: RecordType, timeout: Duration) -> Self {
+        TypeTimeout { kind, timeout }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+impl FromStr for TypeTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, ms) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected '<type>=<ms>', got '{}'", s))?;
+        let kind = kind
+            .to_uppercase()
+            .parse()
+            .map_err(|_| format!("invalid record type '{}'", kind))?;
+        let ms = ms
+            .parse::<u64>()
+            .ok()
+            .filter(|v| *v > 0)
+            .ok_or_else(|| format!("invalid timeout '{}'", ms))?;
+
+        Ok(TypeTimeout::new(kind, Duration::from_millis(ms)))
+    }
+}
+
+impl fmt::Display for TypeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.kind, self.timeout.as_millis())
+    }
+}
+
+/// Network of IPv4 or IPv6 addresses, parsed from CIDR notation (`<ip>/<prefix>`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressPrefix {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl AddressPrefix {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(format!("invalid prefix length {}", prefix));
+        }
+
+        Ok(AddressPrefix { addr, prefix })
+    }
+
+    /// Return true if the address is part of this network
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AddressPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected '<ip>/<prefix>', got '{}'", s))?;
+        let addr = addr.parse().map_err(|_| format!("invalid IP address '{}'", addr))?;
+        let prefix = prefix
+            .parse()
+            .map_err(|_| format!("invalid prefix length '{}'", prefix))?;
+
+        AddressPrefix::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for AddressPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Move A and AAAA answers in any of the preferred networks before all other answers.
+///
+/// The relative order of answers is otherwise unchanged. Answers are ordered by the first
+/// network they're part of so networks listed earlier are preferred over later ones.
+fn prefer_addresses(mut res: DnsResponse, preferred: &[AddressPrefix]) -> DnsResponse {
+    let mut answers = res.take_answers();
+    // Sorting is stable so answers that aren't in any preferred network (or aren't addresses)
+    // keep their original order after all the preferred ones.
+    answers.sort_by_key(|r| {
+        let addr = match r.rdata() {
+            RData::A(v) => IpAddr::V4(*v),
+            RData::AAAA(v) => IpAddr::V6(*v),
+            _ => return preferred.len(),
+        };
+
+        preferred
+            .iter()
+            .position(|p| p.contains(addr))
+            .unwrap_or(preferred.len())
+    });
+
+    res.insert_answers(answers);
+    res
+}
+
+/// Rewrite addresses in one network to the same host in another network of the same size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatMapping {
+    from: IpAddr,
+    to: IpAddr,
+    prefix: u8,
+}
+
+impl NatMapping {
+    /// Create a new mapping between networks of the same address family and prefix length
+    pub fn new(from: IpAddr, to: IpAddr, prefix: u8) -> Result<Self, String> {
+        let max = match (from, to) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => 32,
+            (IpAddr::V6(_), IpAddr::V6(_)) => 128,
+            _ => return Err("networks must be the same address family".to_owned()),
+        };
+
+        if prefix > max {
+            return Err(format!("invalid prefix length {}", prefix));
+        }
+
+        Ok(NatMapping { from, to, prefix })
+    }
+
+    /// Return the address in the `to` network if the address is in the `from` network
+    fn rewrite(&self, addr: IpAddr) -> Option<IpAddr> {
+        match (self.from, self.to, addr) {
+            (IpAddr::V4(from), IpAddr::V4(to), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                let (from, to, addr) = (u32::from(from), u32::from(to), u32::from(addr));
+                (addr & mask == from & mask).then(|| IpAddr::V4(Ipv4Addr::from((to & mask) | (addr & !mask))))
+            }
+            (IpAddr::V6(from), IpAddr::V6(to), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                let (from, to, addr) = (u128::from(from), u128::from(to), u128::from(addr));
+                (addr & mask == from & mask).then(|| IpAddr::V6(Ipv6Addr::from((to & mask) | (addr & !mask))))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for NatMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected '<from-cidr>=<to-cidr>', got '{}'", s))?;
+        let from: AddressPrefix = from.parse()?;
+        let to: AddressPrefix = to.parse()?;
+
+        if from.prefix != to.prefix {
+            return Err(format!(
+                "prefix lengths must match, got {} and {}",
+                from.prefix, to.prefix
+            ));
+        }
+
+        NatMapping::new(from.addr, to.addr, from.prefix)
+    }
+}
+
+impl fmt::Display for NatMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}={}/{}", self.from, self.prefix, self.to, self.prefix)
+    }
+}
+
+/// Rewrite A and AAAA answers using the first mapping that matches each address
+fn rewrite_nat(mut res: DnsResponse, mappings: &[NatMapping]) -> DnsResponse {
+    let answers = res
+        .take_answers()
+        .into_iter()
+        .map(|mut r| {
+            let addr = match r.rdata() {
+                RData::A(v) => IpAddr::V4(*v),
+                RData::AAAA(v) => IpAddr::V6(*v),
+                _ => return r,
+            };
+
+            match mappings.iter().find_map(|m| m.rewrite(addr)) {
+                Some(IpAddr::V4(v)) => r.set_rdata(RData::A(v)),
+                Some(IpAddr::V6(v)) => r.set_rdata(RData::AAAA(v)),
+                None => &mut r,
+            };
+
+            r
+        })
+        .collect();
+
+    res.insert_answers(answers);
+    res
+}
+
+/// Remove AAAA records from the answer and additional sections of a response.
+fn strip_aaaa(mut res: DnsResponse) -> DnsResponse {
+    let answers = res
+        .take_answers()
+        .into_iter()
+        .filter(|r| r.record_type() != RecordType::AAAA)
+        .collect();
+    let additionals = res
+        .take_additionals()
+        .into_iter()
+        .filter(|r| r.record_type() != RecordType::AAAA)
+        .collect();
+
+    res.insert_answers(answers);
+    res.insert_additionals(additionals);
+    res
+}
+
+/// Replace a CNAME chain ending in A or AAAA records with just the address records.
+///
+/// The address records are renamed to the name that was queried and use the lowest TTL
+/// of any record in the chain. Responses are returned unmodified unless they are for a single
+/// A or AAAA query and the chain resolves fully to records of that type.
+fn flatten_cname_chain(mut res: DnsResponse) -> DnsResponse {
+    let (query_name, query_type) = match res.queries() {
+        [q] if q.query_type() == RecordType::A || q.query_type() == RecordType::AAAA => {
+            (q.name().clone(), q.query_type())
+        }
+        _ => return res,
+    };
+
+    let answers = res.take_answers();
+    let mut current: &Name = &query_name;
+    let mut min_ttl = u32::MAX;
+    let mut hops = 0;
+
+    // Bound the number of hops by the number of answers so that a loop of CNAMEs
+    // can't cause us to spin forever.
+    while hops < answers.len() {
+        let next = answers.iter().find_map(|r| match r.rdata() {
+            RData::CNAME(target) if r.name() == current => Some((target, r.ttl())),
+            _ => None,
+        });
+
+        match next {
+            Some((target, ttl)) => {
+                current = target;
+                min_ttl = min_ttl.min(ttl);
+                hops += 1;
+            }
+            None => break,
+        }
+    }
+
+    let addresses: Vec<Record> = answers
+        .iter()
+        .filter(|r| r.name() == current && r.record_type() == query_type)
+        .map(|r| Record::from_rdata(query_name.clone(), min_ttl.min(r.ttl()), r.rdata().clone()))
+        .collect();
+
+    if hops == 0 || addresses.is_empty() {
+        res.insert_answers(answers);
+    } else {
+        tracing::debug!(message = "flattened CNAME chain", name = %query_name, hops = hops);
+        res.insert_answers(addresses);
+    }
+
+    res
+}
+
+/// Limit A and AAAA answers to a window of `size` records, starting at `offset` and wrapping.
+///
+/// Other types of answers (e.g. CNAMEs) are left in place. Responses with `size` or fewer
+/// address records are returned unmodified.
+fn answer_subset(mut res: DnsResponse, size: usize, offset: usize) -> DnsResponse {
+    let (addresses, mut others): (Vec<Record>, Vec<Record>) = res
+        .take_answers()
+        .into_iter()
+        .partition(|r| r.record_type() == RecordType::A || r.record_type() == RecordType::AAAA);
+
+    if addresses.len() <= size {
+        others.extend(addresses);
+    } else {
+        let start = offset % addresses.len();
+        others.extend(addresses.iter().cycle().skip(start).take(size).cloned());
+    }
+
+    res.insert_answers(others);
+    res
+}
+
+/// Sort SRV answers by ascending priority, ordering records with the same priority using the
+/// weighted random selection from RFC 2782. Other types of answers are left before them.
+fn sort_srv(mut res: DnsResponse) -> DnsResponse {
+    let (records, mut others): (Vec<Record>, Vec<Record>) = res
+        .take_answers()
+        .into_iter()
+        .partition(|r| r.record_type() == RecordType::SRV);
+
+    let mut srv: Vec<(u16, u16, Record)> = records
+        .into_iter()
+        .filter_map(|r| match r.rdata() {
+            RData::SRV(v) => Some((v.priority(), v.weight(), r.clone())),
+            _ => None,
+        })
+        .collect();
+
+    // Records with a weight of zero go first within each priority so that they have a
+    // small chance of being selected first, as described in the RFC.
+    srv.sort_by_key(|(priority, weight, _)| (*priority, *weight != 0));
+
+    let mut rng = rand::thread_rng();
+    let mut remaining = srv.as_mut_slice();
+    while !remaining.is_empty() {
+        let priority = remaining[0].0;
+        let end = remaining
+            .iter()
+            .position(|(p, _, _)| *p != priority)
+            .unwrap_or(remaining.len());
+        let (group, rest) = std::mem::take(&mut remaining).split_at_mut(end);
+
+        // Pick a random number between zero and the sum of the weights of the records left
+        // and select the first record where the running sum of weights is at least that.
+        for i in 0..group.len() {
+            let total: u32 = group[i..].iter().map(|(_, w, _)| u32::from(*w)).sum();
+            let target = rng.gen_range(0..=total);
+            let mut sum = 0;
+            let selected = group[i..]
+                .iter()
+                .position(|(_, w, _)| {
+                    sum += u32::from(*w);
+                    sum >= target
+                })
+                .unwrap_or(0);
+            group.swap(i, i + selected);
+        }
+
+        remaining = rest;
+    }
+
+    others.extend(srv.into_iter().map(|(_, _, r)| r));
+    res.insert_answers(others);
+    res
+}
+
+/// Behavior of a resolver beyond forwarding queries to an upstream server
+#[derive(Debug, Clone)]
+pub struct ResolverOptions {
+    /// How to respond when the upstream server returns SERVFAIL
+    pub servfail: ServFailPolicy,
+    /// Replace CNAME chains ending in address records with just the address records
+    pub flatten_cname: bool,
+    /// Log a warning for queries that take longer than this to resolve
+    pub slow_query_threshold: Option<Duration>,
+    /// Answer PTR queries for these addresses without forwarding them upstream
+    pub self_ptr: Vec<SelfPtr>,
+    /// Return a rotating window of at most this many A or AAAA answers
+    pub answer_subset: Option<NonZeroUsize>,
+    /// Sort SRV answers by priority, shuffling records of the same priority by weight
+    pub sort_srv: bool,
+    /// Timeout for upstream queries, if not set only the timeout of the client is used
+    pub timeout: Option<Duration>,
+    /// Timeout for connecting to upstream servers over TCP, separate from `timeout`
+    pub connect_timeout: Option<Duration>,
+    /// Timeouts to use instead of `timeout` for queries of particular types
+    pub type_timeouts: Vec<TypeTimeout>,
+    /// Don't emit an event for every query resolved, errors and slow queries are still logged
+    pub disable_query_log: bool,
+    /// TTL for records in responses synthesized instead of forwarding queries upstream
+    pub synthetic_ttl: u32,
+    /// Answer NS queries for these delegations without forwarding them upstream
+    pub delegations: Delegations,
+    /// Answer AAAA queries with these addresses when the upstream server has no AAAA records
+    pub aaaa_map: AaaaMap,
+    /// Rewrite A and AAAA answers in one network to the same host in another network
+    pub nat_map: Vec<NatMapping>,
+    /// Move A and AAAA answers in these networks to the front of the answer section
+    pub prefer_address: Vec<AddressPrefix>,
+    /// How to pick which upstream server to send each query to
+    pub upstream_strategy: UpstreamStrategy,
+    /// Retry queries over TCP when the response from the upstream server is truncated or
+    /// larger than the payload size advertised in the query
+    pub tcp_fallback: bool,
+    /// Retry queries with this domain appended to the name when the upstream server returns
+    /// NXDOMAIN, for names with fewer than `ndots` dots
+    pub search_domain: Option<Name>,
+    /// Minimum number of dots in a name for it to not be retried with `search_domain`
+    pub ndots: u8,
+    /// Answer AAAA queries with no records and remove AAAA records from other responses
+    pub no_aaaa: bool,
+    /// How to handle queries with more than one question
+    pub multi_question: MultiQuestionPolicy,
+    /// Randomize the case of names in queries sent to upstream servers over UDP, rejecting
+    /// responses that don't echo the same case (0x20 encoding)
+    pub qname_randomize: bool,
+    /// Emit dnstap messages for each query forwarded upstream and its response
+    #[cfg(feature = "dnstap")]
+    pub dnstap: Option<DnstapLogger>,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        ResolverOptions {
+            servfail: ServFailPolicy::default(),
+            flatten_cname: false,
+            slow_query_threshold: None,
+            self_ptr: Vec::new(),
+            answer_subset: None,
+            sort_srv: false,
+            timeout: None,
+            connect_timeout: None,
+            type_timeouts: Vec::new(),
+            disable_query_log: false,
+            synthetic_ttl: DEFAULT_SYNTHETIC_TTL,
+            delegations: Delegations::default(),
+            aaaa_map: AaaaMap::default(),
+            nat_map: Vec::new(),
+            prefer_address: Vec::new(),
+            upstream_strategy: UpstreamStrategy::default(),
+            tcp_fallback: true,
+            search_domain: None,
+            ndots: DEFAULT_NDOTS,
+            no_aaaa: false,
+            multi_question: MultiQuestionPolicy::default(),
+            qname_randomize: false,
+            #[cfg(feature = "dnstap")]
+            dnstap: None,
+        }
+    }
+}
+
+/// Upstream server that queries are forwarded to, whether the last query sent to it succeeded,
+/// and counts of the queries sent to it
+struct Upstream {
+    addr: SocketAddr,
+    client: UpstreamClient,
+    healthy: AtomicBool,
+    queries: AtomicU64,
+    errors: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+impl Upstream {
+    fn new(client: UpstreamClient, addr: SocketAddr) -> Self {
+        Upstream {
+            addr,
+            client,
+            healthy: AtomicBool::new(true),
+            queries: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a query sent to this upstream, how long it took, and if it succeeded
+    fn record(&self, elapsed: Duration, success: bool) {
+        self.healthy.store(success, Ordering::Relaxed);
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.latency_micros.fetch_add(
+            u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+
+        if !success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Address of an upstream server, whether the last query sent to it succeeded, and totals
+/// for all queries sent to it (including health checks).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    pub addr: SocketAddr,
+    pub healthy: bool,
+    /// Queries sent, including ones that failed
+    pub queries: u64,
+    /// Queries that failed because of timeouts or network errors, not error responses
+    pub errors: u64,
+    /// Total time spent waiting for responses
+    pub latency: Duration,
+    /// Clients created for the upstream server, including the first one
+    pub connections: u64,
+    /// Clients created to replace one that exited or was closed by the upstream server
+    pub reconnects: u64,
+    pub connection_errors: ConnectionErrors,
+}
+
+/// Facade over one or more Trust DNS `AsyncClient` instances (UDP or TLS).
+///
+/// Queries are sent to upstream servers based on the `UpstreamStrategy` of the resolver. If
+/// sending a query fails (timeouts or network errors, not error responses), the remaining
+/// upstream servers are tried.
+///
+/// Note that this struct is thread safe but does not implement `Clone`. It is meant to be
+/// used as part of a reference counted (`Arc`) context object that is shared between all
+/// requests, being handled on various threads.
+pub struct UpstreamResolver {
+    upstreams: Vec<Upstream>,
+    /// Most recently successful upstream for failover, next upstream to use for round-robin
+    preferred: AtomicUsize,
+    options: ResolverOptions,
+    rotation: AtomicUsize,
+}
+
+impl UpstreamResolver {
+    pub fn new(client: UpstreamClient, upstream: SocketAddr, options: ResolverOptions) -> Self {
+        UpstreamResolver {
+            upstreams: vec![Upstream::new(client, upstream)],
+            preferred: AtomicUsize::new(0),
+            options,
+            rotation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add another upstream server to send queries to when the others fail
+    pub fn with_upstream(mut self, client: UpstreamClient, upstream: SocketAddr) -> Self {
+        self.upstreams.push(Upstream::new(client, upstream));
+        self
+    }
+
+    /// Address of each upstream server and whether the last query sent to it succeeded
+    pub fn upstream_status(&self) -> Vec<UpstreamStatus> {
+        self.upstreams
+            .iter()
+            .map(|u| UpstreamStatus {
+                addr: u.addr,
+                healthy: u.healthy.load(Ordering::Relaxed),
+                queries: u.queries.load(Ordering::Relaxed),
+                errors: u.errors.load(Ordering::Relaxed),
+                latency: Duration::from_micros(u.latency_micros.load(Ordering::Relaxed)),
+                connections: u.client.stats.connections.load(Ordering::Relaxed),
+                reconnects: u.client.stats.reconnects.load(Ordering::Relaxed),
+                connection_errors: u.client.stats.errors(),
+            })
+            .collect()
+    }
+
+    pub async fn resolve(&self, req: DnsRequest) -> DonutResult<DnsResponse> {
+        self.resolve_with_upstream(req).await.map(|(_, res)| res)
+    }
+
+    /// Resolve a request, also returning the address of the upstream server that answered it
+    /// (`None` if it was answered locally or split into several queries)
+    pub async fn resolve_with_upstream(&self, req: DnsRequest) -> DonutResult<(Option<SocketAddr>, DnsResponse)> {
+        self.resolve_cacheable(req)
+            .await
+            .map(|(upstream, res)| (upstream, self.reorder(res)))
+    }
+
+    /// Resolve a request the same way as `resolve_with_upstream` without the changes to the
+    /// order of answers that are different for each response (`reorder`). Responses that are
+    /// cached must come from this so that answers still rotate when they're used.
+    pub async fn resolve_cacheable(&self, req: DnsRequest) -> DonutResult<(Option<SocketAddr>, DnsResponse)> {
+        if req.queries().len() > 1 {
+            match self.options.multi_question {
+                MultiQuestionPolicy::Reject => {
+                    return Err(DonutError::from((
+                        ErrorKind::InputInvalid,
+                        "multiple questions in query are not supported",
+                    )))
+                }
+                MultiQuestionPolicy::Split => return self.resolve_split(&req).await.map(|res| (None, res)),
+                MultiQuestionPolicy::Forward => {}
+            }
+        }
+
+        self.resolve_one(req).await
+    }
+
+    /// Resolve each question of a request as a separate query, merging the responses into one.
+    ///
+    /// Records from every response are included. The response code is the first one that isn't
+    /// NOERROR, if any, and the AD flag is only set if it was set for every question.
+    async fn resolve_split(&self, req: &DnsRequest) -> DonutResult<DnsResponse> {
+        let questions = future::try_join_all(req.queries().iter().map(|q| {
+            let mut message = Message::clone(req);
+            message.take_queries();
+            message.add_query(q.clone());
+
+            let options = DnsRequestOptions {
+                expects_multiple_responses: false,
+                ..*req.options()
+            };
+
+            self.resolve_one(DnsRequest::new(message, options))
+        }))
+        .await?
+        .into_iter()
+        .map(|(_, res)| res)
+        .collect::<Vec<DnsResponse>>();
+
+        tracing::debug!(
+            message = "split query with multiple questions",
+            num_queries = questions.len()
+        );
+
+        let code = questions
+            .iter()
+            .map(|r| r.response_code())
+            .find(|c| *c != ResponseCode::NoError)
+            .unwrap_or(ResponseCode::NoError);
+        let authentic_data = questions.iter().all(|r| r.authentic_data());
+
+        let mut res = synthesize_response(req, code, Vec::new());
+        res.set_authentic_data(authentic_data);
+        for mut q in questions {
+            res.add_answers(q.take_answers());
+            res.add_name_servers(q.take_name_servers());
+            for r in q.take_additionals() {
+                res.add_additional(r);
+            }
+        }
+
+        Ok(res)
+    }
+
+    async fn resolve_one(&self, req: DnsRequest) -> DonutResult<(Option<SocketAddr>, DnsResponse)> {
+        if self.options.no_aaaa && req.queries().iter().all(|q| q.query_type() == RecordType::AAAA) {
+            tracing::debug!(message = "answered AAAA query locally", queries = %QueryDisplay::new(req.clone()));
+            return Ok((None, synthesize_response(&req, ResponseCode::NoError, Vec::new())));
+        }
+
+        if let Some(res) = self
+            .options
+            .self_ptr
+            .iter()
+            .find_map(|p| p.answer(&req, self.options.synthetic_ttl))
+        {
+            tracing::debug!(message = "answered PTR query locally", queries = %QueryDisplay::new(req.clone()));
+            return Ok((None, res));
+        }
+
+        if let Some(res) = self.options.delegations.answer(&req) {
+            tracing::debug!(message = "answered NS query locally", queries = %QueryDisplay::new(req.clone()));
+            return Ok((None, res));
+        }
+
+        // Clone the request and use a wrapper so that we can use 'Display' and defer it
+        // until needed by the tracing library (e.g. only if log level is INFO or lower).
+        let queries = QueryDisplay::new(req.clone());
+        let start = Instant::now();
+        let (upstream, res) = self.send(req.clone()).await?;
+        let elapsed = start.elapsed();
+        let code = res.response_code();
+
+        if !self.options.disable_query_log {
+            tracing::debug!(
+                queries = %queries,
+                num_queries = res.query_count(),
+                num_answers = res.answer_count(),
+                response_code = u16::from(code),
+                response_msg = %code,
+                latency_ms = elapsed.as_millis() as u64,
+            );
+        }
+
+        if let Some(threshold) = self.options.slow_query_threshold {
+            if elapsed > threshold {
+                tracing::warn!(
+                    message = "slow query",
+                    queries = %queries,
+                    upstream = %upstream,
+                    latency_ms = elapsed.as_millis() as u64,
+                    threshold_ms = threshold.as_millis() as u64,
+                );
+            }
+        }
+
+        let res = match &self.options.search_domain {
+            Some(domain) if code == ResponseCode::NXDomain => self.search(&req, domain, res).await,
+            _ => res,
+        };
+
+        let mut res = self.options.servfail.apply(&req, res, self.options.synthetic_ttl);
+        res = self.options.aaaa_map.apply(&req, res, self.options.synthetic_ttl);
+        if self.options.flatten_cname {
+            res = flatten_cname_chain(res);
+        }
+
+        if self.options.no_aaaa {
+            res = strip_aaaa(res);
+        }
+
+        if !self.options.nat_map.is_empty() {
+            res = rewrite_nat(res, &self.options.nat_map);
+        }
+
+        // Preferred addresses need to be moved to the front before picking a subset
+        // of them so that they're actually included in the subset.
+        if !self.options.prefer_address.is_empty() {
+            res = prefer_addresses(res, &self.options.prefer_address);
+        }
+
+        Ok((Some(upstream), res))
+    }
+
+    /// Pick the subset of address answers to use and shuffle SRV answers, if enabled. This
+    /// is done for every response, including those answered from the cache.
+    pub fn reorder(&self, mut res: DnsResponse) -> DnsResponse {
+        if let Some(size) = self.options.answer_subset {
+            res = answer_subset(res, size.get(), self.rotation.fetch_add(1, Ordering::Relaxed));
+        }
+
+        if self.options.sort_srv {
+            res = sort_srv(res);
+        }
+
+        res
+    }
+}
+
+impl UpstreamResolver {
+    async fn send(&self, req: DnsRequest) -> DonutResult<(SocketAddr, DnsResponse)> {
+        let timeout = self
+            .options
+            .type_timeouts
+            .iter()
+            .find(|t| req.queries().iter().any(|q| q.query_type() == t.kind))
+            .map(|t| t.timeout)
+            .or(self.options.timeout);
+
+        let mut last_err = None;
+
+        for i in self.upstream_order() {
+            let upstream = &self.upstreams[i];
+            let start = Instant::now();
+            let res = self.send_to(upstream, timeout, req.clone()).await;
+            upstream.record(start.elapsed(), res.is_ok());
+
+            match res {
+                Ok(res) => {
+                    if self.options.upstream_strategy == UpstreamStrategy::Failover {
+                        self.preferred.store(i, Ordering::Relaxed);
+                    }
+
+                    return Ok((upstream.addr, res));
+                }
+                Err(e) => {
+                    if self.upstreams.len() > 1 {
+                        tracing::warn!(message = "upstream query failed", upstream = %upstream.addr, error = %e);
+                    }
+
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| DonutError::from((ErrorKind::Internal, "no upstream servers"))))
+    }
+
+    /// Check that an upstream server is working by asking for the root name servers, without
+    /// answering the query locally even if it would be otherwise
+    pub async fn check_health(&self) -> DonutResult<()> {
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(Name::root(), RecordType::NS))
+            .set_recursion_desired(true);
+
+        let (_, res) = self
+            .send(DnsRequest::new(message, DnsRequestOptions::default()))
+            .await?;
+
+        match res.response_code() {
+            ResponseCode::NoError => Ok(()),
+            _ => Err(DonutError::from((
+                ErrorKind::Internal,
+                "unexpected response code from upstream server",
+            ))),
+        }
+    }
+
+    /// Indexes of upstream servers in the order they should be tried for a query
+    fn upstream_order(&self) -> Vec<usize> {
+        let len = self.upstreams.len();
+        match self.options.upstream_strategy {
+            // Start with whichever upstream worked last so that we don't wait for a timeout
+            // from a dead server for every query, then try the rest in their original order.
+            UpstreamStrategy::Failover => {
+                let preferred = self.preferred.load(Ordering::Relaxed);
+                std::iter::once(preferred)
+                    .chain((0..len).filter(|&i| i != preferred))
+                    .collect()
+            }
+            // Start with the next upstream in turn and skip over unhealthy ones, only trying
+            // them (so that they can recover) after all the healthy ones have failed.
+            UpstreamStrategy::RoundRobin => {
+                let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) =
+                    (0..len).partition(|&i| self.upstreams[i].healthy.load(Ordering::Relaxed));
+
+                if !healthy.is_empty() {
+                    let start = self.preferred.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(start);
+                }
+
+                healthy.extend(unhealthy);
+                healthy
+            }
+        }
+    }
+
+    /// Retry a request that got an NXDOMAIN response with the search domain appended to the
+    /// query name, returning the original response if that doesn't work either.
+    async fn search(&self, req: &DnsRequest, domain: &Name, res: DnsResponse) -> DnsResponse {
+        let (original, searched) = match req.queries() {
+            [q] => (q.name(), q.name().clone().append_domain(domain)),
+            _ => return res,
+        };
+
+        // Names that already end with the search domain are never retried so that we don't
+        // end up appending it multiple times.
+        if original.is_root() || original.num_labels() > self.options.ndots || domain.zone_of(original) {
+            return res;
+        }
+
+        let mut message = Message::clone(req);
+        let queries: Vec<Query> = message
+            .take_queries()
+            .into_iter()
+            .map(|mut q| {
+                q.set_name(searched.clone());
+                q
+            })
+            .collect();
+        message.add_queries(queries);
+
+        let mut searched_res = match self.send(DnsRequest::new(message, *req.options())).await {
+            Ok((_, r)) if r.response_code() != ResponseCode::NXDomain => r,
+            _ => return res,
+        };
+
+        tracing::debug!(message = "retried query with search domain", original = %original, name = %searched);
+
+        // Answer the original query with a CNAME pointing to the name we actually resolved so
+        // that clients accept the answers even though their names don't match the query.
+        let cname = Record::from_rdata(original.clone(), self.options.synthetic_ttl, RData::CNAME(searched));
+        let mut answers = vec![cname];
+        answers.extend(searched_res.take_answers());
+
+        searched_res.take_queries();
+        searched_res.add_queries(req.queries().to_vec());
+        searched_res.insert_answers(answers);
+        searched_res
+    }
+
+    async fn send_to(
+        &self,
+        upstream: &Upstream,
+        timeout: Option<Duration>,
+        req: DnsRequest,
+    ) -> DonutResult<DnsResponse> {
+        // Responses over TLS can't be spoofed by an off-path attacker so only UDP queries
+        // need the extra entropy of randomizing the case of the names in them.
+        if self.options.qname_randomize && matches!(upstream.client.transport, Transport::Udp) {
+            let randomized = randomize_case(&req);
+            let res = self.send_with_fallback(upstream, timeout, randomized.clone()).await?;
+            let res = verify_response(&randomized, res)?;
+            return restore_case(&req, &randomized, res);
+        }
+
+        let res = self.send_with_fallback(upstream, timeout, req.clone()).await?;
+        verify_response(&req, res)
+    }
+
+    async fn send_with_fallback(
+        &self,
+        upstream: &Upstream,
+        timeout: Option<Duration>,
+        req: DnsRequest,
+    ) -> DonutResult<DnsResponse> {
+        // Note that we clone the client here because it requires a mutable reference and
+        // cloning is the simplest and way to do that (and it's reasonably performant).
+        let mut client = upstream.client.get()?;
+
+        // Responses are only truncated when using UDP since TLS connections have no size limit
+        let retry =
+            (self.options.tcp_fallback && matches!(upstream.client.transport, Transport::Udp)).then(|| req.clone());
+        #[cfg(feature = "dnstap")]
+        let (query_time, query) = (std::time::SystemTime::now(), req.clone());
+        let res = with_timeout(timeout, client.send(req)).await?;
+        #[cfg(feature = "dnstap")]
+        self.log_dnstap(
+            upstream,
+            upstream.client.transport.dnstap_protocol(),
+            &query,
+            query_time,
+            &res,
+        );
+
+        match retry {
+            Some(req) if res.truncated() => {
+                // Retry truncated responses over TCP since they're probably missing records. If
+                // that doesn't work, the truncated response is better than nothing.
+                let connect_timeout = self.options.connect_timeout.or(timeout);
+                #[cfg(feature = "dnstap")]
+                let (query_time, query) = (std::time::SystemTime::now(), req.clone());
+                match send_tcp(upstream.addr, upstream.client.source, connect_timeout, timeout, req).await {
+                    Ok(tcp_res) => {
+                        tracing::debug!(message = "retried truncated response over TCP", upstream = %upstream.addr);
+                        #[cfg(feature = "dnstap")]
+                        self.log_dnstap(upstream, SocketProtocol::Tcp, &query, query_time, &tcp_res);
+                        Ok(tcp_res)
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            message = "unable to retry truncated response over TCP",
+                            upstream = %upstream.addr,
+                            error = %e,
+                        );
+                        Ok(res)
+                    }
+                }
+            }
+            _ => Ok(res),
+        }
+    }
+
+    /// Emit dnstap messages for a query sent to `upstream` and its response.
+    ///
+    /// The query is logged as sent, including any randomized case, except for its ID: clients
+    /// replace the ID with a random one internally that isn't exposed so the ID of the original
+    /// request is logged instead.
+    #[cfg(feature = "dnstap")]
+    fn log_dnstap(
+        &self,
+        upstream: &Upstream,
+        protocol: SocketProtocol,
+        req: &DnsRequest,
+        query_time: std::time::SystemTime,
+        res: &DnsResponse,
+    ) {
+        if let Some(dnstap) = &self.options.dnstap {
+            if let (Ok(query), Ok(response)) = (req.to_vec(), res.to_vec()) {
+                dnstap.log(upstream.addr, protocol, &query, query_time, &response);
+            }
+        }
+    }
+}
+
+/// Check that the question section of a response matches the request it's supposedly for
+/// and give it the same ID as the request.
 ///
-/// Note that this struct is thread safe but does not implement `Clone`. It is meant to be
-/// used as part of a reference counted (`Arc`) context object that is shared between all
-/// requests, being handled on various threads.
-pub struct UdpResolver {
-    client: AsyncClient,
+/// The client for each transport sends queries with a random ID instead of the ID of the
+/// request and only accepts responses with that ID. Responses get the original ID back so
+/// that clients see the ID they sent. Error responses may omit the question section entirely
+/// so a missing question section is only rejected for successful responses.
+fn verify_response(req: &DnsRequest, mut res: DnsResponse) -> DonutResult<DnsResponse> {
+    let omitted = res.queries().is_empty() && res.response_code() != ResponseCode::NoError;
+    if !omitted && res.queries() != req.queries() {
+        tracing::warn!(
+            message = "question in upstream response doesn't match query, possibly spoofed",
+            queries = %QueryDisplay::new(req.clone()),
+            response_queries = ?res.queries(),
+        );
+
+        return Err(DonutError::from((
+            ErrorKind::Internal,
+            "question in upstream response doesn't match query",
+        )));
+    }
+
+    res.set_id(req.id());
+    Ok(res)
+}
+
+/// Copy a request, randomly changing the case of each letter in the names of its queries
+fn randomize_case(req: &DnsRequest) -> DnsRequest {
+    let mut rng = rand::thread_rng();
+    let mut message = Message::clone(req);
+    let queries = message
+        .take_queries()
+        .into_iter()
+        .map(|mut q| {
+            let labels = q.name().iter().map(|label| {
+                label
+                    .iter()
+                    .map(|b| {
+                        if rng.gen() {
+                            b.to_ascii_uppercase()
+                        } else {
+                            b.to_ascii_lowercase()
+                        }
+                    })
+                    .collect::<Vec<u8>>()
+            });
+
+            // Labels are the same length as the originals so they can't be invalid but fall
+            // back to the original name instead of failing the query just in case.
+            if let Ok(mut name) = Name::from_labels(labels) {
+                name.set_fqdn(q.name().is_fqdn());
+                q.set_name(name);
+            }
+
+            q
+        })
+        .collect::<Vec<Query>>();
+
+    message.add_queries(queries);
+    DnsRequest::new(message, *req.options())
+}
+
+/// Check that the names in a response to a `randomized` request have exactly the same case,
+/// then replace them with the names from the `original` request.
+///
+/// Responses with different names are assumed to be spoofed, since an attacker would have to
+/// guess the case of every letter in addition to the query ID and port.
+fn restore_case(original: &DnsRequest, randomized: &DnsRequest, mut res: DnsResponse) -> DonutResult<DnsResponse> {
+    let matches = res.queries().len() == randomized.queries().len()
+        && res
+            .queries()
+            .iter()
+            .zip(randomized.queries())
+            .all(|(r, q)| r.name().eq_case(q.name()));
+
+    if !matches {
+        tracing::warn!(
+            message = "query name case mismatch in upstream response, possibly spoofed",
+            queries = %QueryDisplay::new(randomized.clone()),
+        );
+
+        return Err(DonutError::from((
+            ErrorKind::Internal,
+            "query name case mismatch in upstream response",
+        )));
+    }
+
+    // Records for the names queried usually echo the randomized case too, restore those so
+    // that clients only ever see the name they asked for.
+    for (q, orig) in randomized.queries().iter().zip(original.queries()) {
+        let answers = res.answers_mut().iter_mut();
+        for r in answers.filter(|r| r.name().eq_case(q.name())) {
+            r.set_name(orig.name().clone());
+        }
+
+        let name_servers = res.name_servers_mut().iter_mut();
+        for r in name_servers.filter(|r| r.name().eq_case(q.name())) {
+            r.set_name(orig.name().clone());
+        }
+    }
+
+    res.take_queries();
+    res.add_queries(original.queries().to_vec());
+    Ok(res)
+}
+
+/// Wait for a response from the upstream server, up to the timeout if there is one
+async fn with_timeout<F>(timeout: Option<Duration>, f: F) -> DonutResult<DnsResponse>
+where
+    F: Future<Output = Result<DnsResponse, ProtoError>>,
+{
+    match timeout {
+        Some(t) => tokio::time::timeout(t, f)
+            .await
+            .map_err(|_| DonutError::from((ErrorKind::Timeout, "upstream query timed out")))?
+            .map_err(DonutError::from),
+        None => Ok(f.await?),
+    }
+}
+
+/// Send a request to the upstream server over a new TCP connection used only for this request
+///
+/// Connecting and sending the query are limited by separate timeouts so that a slow connection
+/// doesn't use up the time allowed for the query.
+async fn send_tcp(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    req: DnsRequest,
+) -> DonutResult<DnsResponse> {
+    let tcp = tokio::time::timeout(
+        connect_timeout.unwrap_or(DEFAULT_TCP_TIMEOUT),
+        connect_tcp(addr, source),
+    )
+    .await
+    .map_err(|_| DonutError::from((ErrorKind::Timeout, "upstream TCP connection timed out")))?
+    .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to connect to upstream server", e)))?;
+
+    let (stream, sender) = DnsTcpStream::from_stream(AsyncIoTokioAsStd(tcp), addr);
+    let conn = future::ready(Ok(TcpClientStream::from_stream(stream)));
+    let (mut client, bg) = AsyncClient::with_timeout(
+        conn,
+        Box::new(BufDnsStreamHandle::new(addr, sender)),
+        timeout.unwrap_or(DEFAULT_TCP_TIMEOUT),
+        None,
+    )
+    .await?;
+
+    // The background future exits (closing the connection) once the client is dropped
+    tokio::spawn(bg);
+    with_timeout(timeout, client.send(req)).await
+}
+
+impl fmt::Debug for UpstreamResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let upstreams: Vec<SocketAddr> = self.upstreams.iter().map(|u| u.addr).collect();
+        write!(
+            f,
+            "UpstreamResolver {{ client: AsyncClient(...), upstreams: {:?}, options: {:?} }}",
+            upstreams, self.options
+        )
+    }
+}
+
+/// Name and address to answer A or AAAA queries with when using a `StaticResolver`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticRecord {
+    name: Name,
+    addr: IpAddr,
+}
+
+impl StaticRecord {
+    pub fn new(name: Name, addr: IpAddr) -> Self {
+        StaticRecord { name, addr }
+    }
+}
+
+impl FromStr for StaticRecord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addr) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected '<name>=<ip>', got '{}'", s))?;
+        let mut name = Name::from_utf8(name).map_err(|e| format!("invalid name '{}': {}", name, e))?;
+        name.set_fqdn(true);
+        let addr = addr.parse().map_err(|_| format!("invalid IP address '{}'", addr))?;
+
+        Ok(StaticRecord::new(name, addr))
+    }
+}
+
+impl fmt::Display for StaticRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.addr)
+    }
+}
+
+/// Resolver that answers A and AAAA queries from a static table without any network access.
+///
+/// Queries for names not in the table get an NXDOMAIN response, queries for names in the
+/// table with no records of the requested type get an empty NOERROR response. This is meant
+/// for testing and CI environments without a DNS server available.
+#[derive(Debug, Clone)]
+pub struct StaticResolver {
+    records: Vec<StaticRecord>,
+    ttl: u32,
 }
 
-impl UdpResolver {
-    pub fn new(client: AsyncClient) -> Self {
-        UdpResolver { client }
+impl StaticResolver {
+    pub fn new(records: Vec<StaticRecord>, ttl: u32) -> Self {
+        StaticResolver { records, ttl }
     }
 
     pub async fn resolve(&self, req: DnsRequest) -> DonutResult<DnsResponse> {
-        // Note that we clone the client here because it requires a mutable reference and
-        // cloning is the simplest and way to do that (and it's reasonably performant).
-        let mut client = self.client.clone();
-        // Clone the request and use a wrapper so that we can use 'Display' and defer it
-        // until needed by the tracing library (e.g. only if log level is INFO or lower).
-        let queries = QueryDisplay::new(req.clone());
-        let res = client.send(req).await?;
-        let code = res.response_code();
+        // NOTE: Names are compared with == since they may or may not be fully qualified
+        // depending on how the request was parsed, see `SelfPtr::answer`.
+        let known = req
+            .queries()
+            .iter()
+            .all(|q| self.records.iter().any(|r| r.name == *q.name()));
+
+        let res = if known {
+            let answers = req
+                .queries()
+                .iter()
+                .flat_map(|q| {
+                    self.records
+                        .iter()
+                        .filter(move |r| r.name == *q.name())
+                        .flat_map(move |r| synthesize_address_answers(std::slice::from_ref(q), r.addr, self.ttl))
+                })
+                .collect();
+
+            synthesize_response(&req, ResponseCode::NoError, answers)
+        } else {
+            synthesize_response(&req, ResponseCode::NXDomain, Vec::new())
+        };
 
         tracing::debug!(
-            queries = %queries,
-            num_queries = res.query_count(),
-            num_answers = res.answer_count(),
-            response_code = u16::from(code),
-            response_msg = %code,
+            queries = %QueryDisplay::new(req.clone()),
+            num_answers = res.answers().len(),
+            response_code = u16::from(res.response_code()),
+            response_msg = %res.response_code(),
         );
 
         Ok(res)
     }
 }
 
-impl fmt::Debug for UdpResolver {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "UdpResolver {{ client: AsyncClient(...) }}")
+/// Resolver used to answer queries, forwarding them upstream or answering them locally.
+// There's only ever a single instance of this shared by all requests so the size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Resolver {
+    /// Forward queries to an upstream server over UDP or TLS
+    Upstream(UpstreamResolver),
+    /// Answer queries from a static table
+    Static(StaticResolver),
+}
+
+impl Resolver {
+    pub async fn resolve(&self, req: DnsRequest) -> DonutResult<DnsResponse> {
+        match self {
+            Resolver::Upstream(r) => r.resolve(req).await,
+            Resolver::Static(r) => r.resolve(req).await,
+        }
+    }
+
+    /// Resolve a request, also returning the address of the upstream server that answered it
+    /// (`None` if it wasn't forwarded to one)
+    pub async fn resolve_with_upstream(&self, req: DnsRequest) -> DonutResult<(Option<SocketAddr>, DnsResponse)> {
+        match self {
+            Resolver::Upstream(r) => r.resolve_with_upstream(req).await,
+            Resolver::Static(r) => r.resolve(req).await.map(|res| (None, res)),
+        }
+    }
+
+    /// Resolve a request without reordering its answers, see `UpstreamResolver::resolve_cacheable`
+    pub async fn resolve_cacheable(&self, req: DnsRequest) -> DonutResult<(Option<SocketAddr>, DnsResponse)> {
+        match self {
+            Resolver::Upstream(r) => r.resolve_cacheable(req).await,
+            Resolver::Static(r) => r.resolve(req).await.map(|res| (None, res)),
+        }
+    }
+
+    /// Reorder the answers of a response, see `UpstreamResolver::reorder`
+    pub fn reorder(&self, res: DnsResponse) -> DnsResponse {
+        match self {
+            Resolver::Upstream(r) => r.reorder(res),
+            Resolver::Static(_) => res,
+        }
+    }
+
+    /// Check that queries can be resolved, always true if queries aren't forwarded upstream
+    pub async fn check_health(&self) -> DonutResult<()> {
+        match self {
+            Resolver::Upstream(r) => r.check_health().await,
+            Resolver::Static(_) => Ok(()),
+        }
+    }
+
+    /// Address of each upstream server and whether the last query sent to it succeeded, empty
+    /// if queries aren't forwarded upstream
+    pub fn upstream_status(&self) -> Vec<UpstreamStatus> {
+        match self {
+            Resolver::Upstream(r) => r.upstream_status(),
+            Resolver::Static(_) => Vec::new(),
+        }
+    }
+}
+
+impl From<UpstreamResolver> for Resolver {
+    fn from(r: UpstreamResolver) -> Self {
+        Resolver::Upstream(r)
+    }
+}
+
+impl From<StaticResolver> for Resolver {
+    fn from(r: StaticResolver) -> Self {
+        Resolver::Static(r)
     }
 }
 
@@ -88,3 +2300,673 @@ impl fmt::Display for QueryDisplay {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        flatten_cname_chain, new_tls_client, new_udp_client, randomize_case, restore_case, send_udp_from, sort_srv,
+        verify_response, MultiQuestionPolicy, ResolverOptions, ServFailPolicy, SourceAddrs, TlsUpstream,
+        UpstreamResolver,
+    };
+    use crate::listen::{tls_acceptor, ReloadableCert, TlsCipherSuites, TlsMinVersion};
+    use crate::types::ErrorKind;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+    use trust_dns_client::op::{DnsResponse, Edns, Message, MessageType, Query, ResponseCode};
+    use trust_dns_client::proto::xfer::{DnsRequest, DnsRequestOptions};
+    use trust_dns_client::rr::rdata::SRV;
+    use trust_dns_client::rr::{Name, RData, Record, RecordType};
+
+    fn name(s: &str) -> Name {
+        Name::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_flatten_cname_chain_two_hops() {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(name("www.example.com."), RecordType::A));
+        msg.add_answer(Record::from_rdata(
+            name("www.example.com."),
+            300,
+            RData::CNAME(name("a.example.com.")),
+        ));
+        msg.add_answer(Record::from_rdata(
+            name("a.example.com."),
+            60,
+            RData::CNAME(name("b.example.com.")),
+        ));
+        msg.add_answer(Record::from_rdata(
+            name("b.example.com."),
+            120,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        ));
+        msg.add_answer(Record::from_rdata(
+            name("b.example.com."),
+            30,
+            RData::A(Ipv4Addr::new(192, 0, 2, 2)),
+        ));
+
+        let res = flatten_cname_chain(DnsResponse::from(msg));
+        let answers = res.answers();
+
+        assert_eq!(2, answers.len());
+        assert!(answers.iter().all(|r| r.name() == &name("www.example.com.")));
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 1)), answers[0].rdata());
+        assert_eq!(60, answers[0].ttl());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 2)), answers[1].rdata());
+        assert_eq!(30, answers[1].ttl());
+    }
+
+    #[test]
+    fn test_flatten_cname_chain_no_addresses() {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(name("www.example.com."), RecordType::A));
+        msg.add_answer(Record::from_rdata(
+            name("www.example.com."),
+            300,
+            RData::CNAME(name("a.example.com.")),
+        ));
+
+        let res = flatten_cname_chain(DnsResponse::from(msg));
+        assert_eq!(1, res.answers().len());
+        assert_eq!(&RData::CNAME(name("a.example.com.")), res.answers()[0].rdata());
+    }
+
+    fn response_to(req: &Message, qname: &str) -> DnsResponse {
+        let mut res = Message::new();
+        res.set_id(req.id().wrapping_add(1));
+        res.set_message_type(MessageType::Response);
+        res.add_query(Query::query(name(qname), RecordType::A));
+        DnsResponse::from(res)
+    }
+
+    fn request(qname: &str) -> DnsRequest {
+        let mut msg = Message::new();
+        msg.set_id(1234);
+        msg.add_query(Query::query(name(qname), RecordType::A));
+        DnsRequest::new(msg, DnsRequestOptions::default())
+    }
+
+    #[test]
+    fn test_verify_response_matching_question() {
+        let req = request("www.example.com.");
+        let res = verify_response(&req, response_to(&req, "www.example.com.")).unwrap();
+
+        assert_eq!(req.id(), res.id());
+    }
+
+    #[test]
+    fn test_verify_response_mismatched_question() {
+        let req = request("www.example.com.");
+        let res = verify_response(&req, response_to(&req, "www.example.net."));
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_verify_response_omitted_question() {
+        let req = request("www.example.com.");
+        let mut res = response_to(&req, "www.example.com.");
+        res.take_queries();
+        res.set_response_code(ResponseCode::ServFail);
+
+        assert!(verify_response(&req, res.clone()).is_ok());
+        res.set_response_code(ResponseCode::NoError);
+        assert!(verify_response(&req, res).is_err());
+    }
+
+    // IDs are checked by the transport that picked them since responses from Trust DNS clients
+    // only have the random ID they sent, not the ID of the request, by the time they're verified.
+    #[tokio::test]
+    async fn test_send_udp_from_mismatched_id() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        let mut query = Message::new();
+        query.set_id(1234);
+        query.add_query(Query::query(name("www.example.com."), RecordType::A));
+
+        let client = tokio::spawn(send_udp_from(
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            addr,
+            query.id(),
+            query.to_vec().unwrap(),
+            512,
+        ));
+
+        let mut buf = [0u8; 512];
+        let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+        let received = Message::from_vec(&buf[..len]).unwrap();
+
+        // Send a response with the wrong ID first, it has to be ignored
+        let mut spoofed = Message::clone(&received);
+        spoofed.set_id(received.id().wrapping_add(1));
+        spoofed.set_message_type(MessageType::Response);
+        spoofed.add_answer(Record::from_rdata(
+            name("www.example.com."),
+            60,
+            RData::A(Ipv4Addr::new(203, 0, 113, 1)),
+        ));
+        upstream.send_to(&spoofed.to_vec().unwrap(), from).await.unwrap();
+
+        let mut genuine = Message::clone(&received);
+        genuine.set_message_type(MessageType::Response);
+        genuine.add_answer(Record::from_rdata(
+            name("www.example.com."),
+            60,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        ));
+        upstream.send_to(&genuine.to_vec().unwrap(), from).await.unwrap();
+
+        let res = client.await.unwrap().unwrap();
+        assert_eq!(1234, res.id());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 1)), res.answers()[0].rdata());
+    }
+
+    #[test]
+    fn test_randomize_case_same_name() {
+        let req = request("www.example.com.");
+        let randomized = randomize_case(&req);
+
+        assert_eq!(req.id(), randomized.id());
+        assert_eq!(1, randomized.queries().len());
+        assert_eq!(req.queries()[0].name(), randomized.queries()[0].name());
+        assert!(randomized.queries()[0].name().is_fqdn());
+        assert_eq!(RecordType::A, randomized.queries()[0].query_type());
+    }
+
+    #[test]
+    fn test_restore_case_mismatch() {
+        let req = request("www.example.com.");
+        let randomized = request("wWw.ExAmPlE.cOm.");
+        let res = restore_case(&req, &randomized, response_to(&randomized, "www.example.com."));
+
+        assert_eq!(ErrorKind::Internal, res.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_restore_case_original_name() {
+        let req = request("www.example.com.");
+        let randomized = request("wWw.ExAmPlE.cOm.");
+        let mut res = response_to(&randomized, "wWw.ExAmPlE.cOm.");
+        res.add_answer(Record::from_rdata(
+            name("wWw.ExAmPlE.cOm."),
+            60,
+            RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        ));
+
+        let res = restore_case(&req, &randomized, res).unwrap();
+
+        assert!(res.queries()[0].name().eq_case(&name("www.example.com.")));
+        assert!(res.answers()[0].name().eq_case(&name("www.example.com.")));
+    }
+
+    fn servfail(req: &DnsRequest) -> DnsResponse {
+        let mut res = response_to(req, &req.queries()[0].name().to_ascii());
+        res.set_response_code(ResponseCode::ServFail);
+        res
+    }
+
+    #[test]
+    fn test_servfail_policy_propagate() {
+        let req = request("www.example.com.");
+        let res = ServFailPolicy::Propagate.apply(&req, servfail(&req), 60);
+
+        assert_eq!(ResponseCode::ServFail, res.response_code());
+    }
+
+    #[test]
+    fn test_servfail_policy_nxdomain() {
+        let req = request("www.example.com.");
+        let res = ServFailPolicy::NxDomain.apply(&req, servfail(&req), 60);
+
+        assert_eq!(ResponseCode::NXDomain, res.response_code());
+        assert_eq!(req.id(), res.id());
+        assert!(res.answers().is_empty());
+    }
+
+    #[test]
+    fn test_servfail_policy_sinkhole() {
+        let req = request("www.example.com.");
+        let policy = ServFailPolicy::Sinkhole(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100)));
+        let res = policy.apply(&req, servfail(&req), 60);
+
+        assert_eq!(ResponseCode::NoError, res.response_code());
+        assert_eq!(1, res.answers().len());
+        assert_eq!(&name("www.example.com."), res.answers()[0].name());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 100)), res.answers()[0].rdata());
+        assert_eq!(60, res.answers()[0].ttl());
+    }
+
+    #[test]
+    fn test_servfail_policy_sinkhole_other_family() {
+        let req = request("www.example.com.");
+        let policy = ServFailPolicy::Sinkhole(IpAddr::from_str("2001:db8::100").unwrap());
+        let res = policy.apply(&req, servfail(&req), 60);
+
+        assert_eq!(ResponseCode::ServFail, res.response_code());
+        assert!(res.answers().is_empty());
+    }
+
+    #[test]
+    fn test_servfail_policy_ignores_other_codes() {
+        let req = request("www.example.com.");
+        let res = ServFailPolicy::NxDomain.apply(&req, response_to(&req, "www.example.com."), 60);
+
+        assert_eq!(ResponseCode::NoError, res.response_code());
+    }
+
+    /// Start a UDP server that answers each question of a query with an A record for 192.0.2.N
+    /// where N is the number of questions in the query.
+    async fn fake_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf[..len]).unwrap();
+                let num = req.queries().len() as u8;
+
+                let mut res = Message::new();
+                res.set_id(req.id());
+                res.set_message_type(MessageType::Response);
+                res.add_queries(req.queries().to_vec());
+                for q in req.queries() {
+                    res.add_answer(Record::from_rdata(
+                        q.name().clone(),
+                        300,
+                        RData::A(Ipv4Addr::new(192, 0, 2, num)),
+                    ));
+                }
+
+                socket.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    async fn multi_question_resolver(addr: SocketAddr, policy: MultiQuestionPolicy) -> UpstreamResolver {
+        let client = new_udp_client(addr, SourceAddrs::default(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        let options = ResolverOptions {
+            multi_question: policy,
+            ..ResolverOptions::default()
+        };
+
+        UpstreamResolver::new(client, addr, options)
+    }
+
+    fn two_questions() -> DnsRequest {
+        let mut msg = Message::new();
+        msg.set_id(1234);
+        msg.add_query(Query::query(name("a.example.com."), RecordType::A));
+        msg.add_query(Query::query(name("b.example.com."), RecordType::A));
+        DnsRequest::new(msg, DnsRequestOptions::default())
+    }
+
+    #[tokio::test]
+    async fn test_multi_question_reject() {
+        let addr = fake_upstream().await;
+        let resolver = multi_question_resolver(addr, MultiQuestionPolicy::Reject).await;
+        let res = resolver.resolve_cacheable(two_questions()).await;
+
+        assert_eq!(ErrorKind::InputInvalid, res.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn test_multi_question_split() {
+        let addr = fake_upstream().await;
+        let resolver = multi_question_resolver(addr, MultiQuestionPolicy::Split).await;
+        let req = two_questions();
+        let (upstream, res) = resolver.resolve_cacheable(req.clone()).await.unwrap();
+
+        // Each question is sent as a separate query so the upstream only sees one at a time
+        assert_eq!(None, upstream);
+        assert_eq!(req.id(), res.id());
+        assert_eq!(ResponseCode::NoError, res.response_code());
+        assert_eq!(req.queries(), res.queries());
+        assert_eq!(2, res.answers().len());
+
+        let mut names: Vec<Name> = res.answers().iter().map(|r| r.name().clone()).collect();
+        names.sort();
+        assert_eq!(vec![name("a.example.com."), name("b.example.com.")], names);
+        assert!(res
+            .answers()
+            .iter()
+            .all(|r| r.rdata() == &RData::A(Ipv4Addr::new(192, 0, 2, 1))));
+    }
+
+    #[tokio::test]
+    async fn test_multi_question_forward() {
+        let addr = fake_upstream().await;
+        let resolver = multi_question_resolver(addr, MultiQuestionPolicy::Forward).await;
+        let req = two_questions();
+        let (upstream, res) = resolver.resolve_cacheable(req.clone()).await.unwrap();
+
+        // Both questions are sent upstream in the same query
+        assert_eq!(Some(addr), upstream);
+        assert_eq!(req.id(), res.id());
+        assert_eq!(req.queries(), res.queries());
+        assert_eq!(2, res.answers().len());
+        assert!(res
+            .answers()
+            .iter()
+            .all(|r| r.rdata() == &RData::A(Ipv4Addr::new(192, 0, 2, 2))));
+    }
+
+    fn srv_response(records: &[(u16, u16, &str)]) -> DnsResponse {
+        let mut msg = Message::new();
+        msg.add_query(Query::query(name("_sip._udp.example.com."), RecordType::SRV));
+        for (priority, weight, target) in records {
+            msg.add_answer(Record::from_rdata(
+                name("_sip._udp.example.com."),
+                300,
+                RData::SRV(SRV::new(*priority, *weight, 5060, name(target))),
+            ));
+        }
+
+        DnsResponse::from(msg)
+    }
+
+    fn srv_targets(res: &DnsResponse) -> Vec<String> {
+        res.answers()
+            .iter()
+            .filter_map(|r| match r.rdata() {
+                RData::SRV(v) => Some(v.target().to_ascii()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_srv_priority() {
+        let mut res = srv_response(&[
+            (30, 10, "c.example.com."),
+            (10, 10, "a.example.com."),
+            (20, 10, "b.example.com."),
+        ]);
+        let cname = Record::from_rdata(name("www.example.com."), 300, RData::CNAME(name("a.example.com.")));
+        res.add_answer(cname.clone());
+
+        let res = sort_srv(res);
+
+        assert_eq!(&cname, &res.answers()[0]);
+        assert_eq!(
+            vec!["a.example.com.", "b.example.com.", "c.example.com."],
+            srv_targets(&res)
+        );
+    }
+
+    #[test]
+    fn test_sort_srv_zero_weight_first() {
+        // A zero weight record only has a chance of being selected first if it's placed first
+        // within its priority, otherwise it's always selected last.
+        let mut zero_first = 0;
+        let mut weighted_first = 0;
+
+        for _ in 0..100 {
+            let res = sort_srv(srv_response(&[
+                (10, 1, "weighted.example.com."),
+                (10, 0, "zero.example.com."),
+                (20, 0, "backup.example.com."),
+            ]));
+            let targets = srv_targets(&res);
+
+            assert_eq!("backup.example.com.", targets[2]);
+            match targets[0].as_str() {
+                "zero.example.com." => zero_first += 1,
+                "weighted.example.com." => weighted_first += 1,
+                t => panic!("unexpected target {}", t),
+            }
+        }
+
+        assert!(zero_first > 0);
+        assert!(weighted_first > 0);
+    }
+
+    /// Start a UDP server that answers A queries for names in `corp.example.com.` with
+    /// 192.0.2.1 and NXDOMAIN for everything else
+    async fn search_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let domain = name("corp.example.com.");
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf[..len]).unwrap();
+                let qname = req.queries()[0].name().clone();
+
+                let mut res = Message::new();
+                res.set_id(req.id());
+                res.set_message_type(MessageType::Response);
+                res.add_queries(req.queries().to_vec());
+                if domain.zone_of(&qname) {
+                    res.add_answer(Record::from_rdata(qname, 300, RData::A(Ipv4Addr::new(192, 0, 2, 1))));
+                } else {
+                    res.set_response_code(ResponseCode::NXDomain);
+                }
+
+                socket.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    async fn search_resolver() -> UpstreamResolver {
+        let addr = search_upstream().await;
+        let client = new_udp_client(addr, SourceAddrs::default(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        let options = ResolverOptions {
+            search_domain: Some(name("corp.example.com.")),
+            ..ResolverOptions::default()
+        };
+
+        UpstreamResolver::new(client, addr, options)
+    }
+
+    #[tokio::test]
+    async fn test_search_domain_resolves() {
+        let resolver = search_resolver().await;
+        let req = request("intranet.");
+        let (_, res) = resolver.resolve_cacheable(req.clone()).await.unwrap();
+
+        assert_eq!(req.id(), res.id());
+        assert_eq!(ResponseCode::NoError, res.response_code());
+        assert_eq!(req.queries(), res.queries());
+        assert_eq!(2, res.answers().len());
+
+        // Clients get a CNAME from the name they asked for to the name that was resolved
+        assert_eq!(&name("intranet."), res.answers()[0].name());
+        assert_eq!(
+            &RData::CNAME(name("intranet.corp.example.com.")),
+            res.answers()[0].rdata()
+        );
+        assert_eq!(&name("intranet.corp.example.com."), res.answers()[1].name());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 1)), res.answers()[1].rdata());
+    }
+
+    #[tokio::test]
+    async fn test_search_domain_not_retried() {
+        let resolver = search_resolver().await;
+
+        // Names with more dots than `ndots` are never retried with the search domain
+        let req = request("intranet.example.net.");
+        let (_, res) = resolver.resolve_cacheable(req.clone()).await.unwrap();
+
+        assert_eq!(ResponseCode::NXDomain, res.response_code());
+        assert_eq!(req.queries(), res.queries());
+        assert!(res.answers().is_empty());
+    }
+
+    /// Response to a query with an A record for 192.0.2.N
+    fn address_response(req: &Message, n: u8) -> Message {
+        let mut res = Message::new();
+        res.set_id(req.id());
+        res.set_message_type(MessageType::Response);
+        res.add_queries(req.queries().to_vec());
+        res.add_answer(Record::from_rdata(
+            req.queries()[0].name().clone(),
+            300,
+            RData::A(Ipv4Addr::new(192, 0, 2, n)),
+        ));
+        res
+    }
+
+    #[tokio::test]
+    async fn test_send_with_fallback_truncated() {
+        // Bind TCP first since the UDP server has to use the same port
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(addr).await.unwrap();
+
+        // UDP responses only have part of the answer and are marked as truncated
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf[..len]).unwrap();
+                let mut res = address_response(&req, 1);
+                res.set_truncated(true);
+                udp.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+
+            let req = Message::from_vec(&buf).unwrap();
+            let res = address_response(&req, 2).to_vec().unwrap();
+            stream.write_u16(res.len() as u16).await.unwrap();
+            stream.write_all(&res).await.unwrap();
+        });
+
+        let client = new_udp_client(addr, SourceAddrs::default(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        let options = ResolverOptions {
+            tcp_fallback: true,
+            ..ResolverOptions::default()
+        };
+        let resolver = UpstreamResolver::new(client, addr, options);
+
+        let req = request("www.example.com.");
+        let (_, res) = resolver.resolve_cacheable(req.clone()).await.unwrap();
+
+        assert_eq!(req.id(), res.id());
+        assert!(!res.truncated());
+        assert_eq!(1, res.answers().len());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 2)), res.answers()[0].rdata());
+    }
+
+    #[tokio::test]
+    async fn test_tls_client_reconnect_stats() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let cert = ReloadableCert::load(&testdata.join("cert1.pem"), &testdata.join("key1.pem")).unwrap();
+        let acceptor = tls_acceptor(&cert, TlsMinVersion::default(), &TlsCipherSuites::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Close every connection right after the handshake so the client has to reconnect
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let tls = TlsUpstream::new("localhost", Some(&testdata.join("cert1.pem"))).unwrap();
+        let client = new_tls_client(addr, tls, SourceAddrs::default(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        let resolver = UpstreamResolver::new(client, addr, ResolverOptions::default());
+
+        let mut status = resolver.upstream_status()[0];
+        for _ in 0..50 {
+            if status.reconnects > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            status = resolver.upstream_status()[0];
+        }
+
+        assert!(status.reconnects >= 1, "{:?}", status);
+        assert_eq!(status.connections, status.reconnects + 1);
+        assert_eq!(0, status.connection_errors.timeout);
+        assert_eq!(0, status.connection_errors.connect);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_fallback_oversized() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(addr).await.unwrap();
+
+        // UDP responses are bigger than the advertised payload size but not marked as truncated
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+                let req = Message::from_vec(&buf[..len]).unwrap();
+                let mut res = address_response(&req, 1);
+                for i in 0..200 {
+                    res.add_answer(Record::from_rdata(
+                        req.queries()[0].name().clone(),
+                        300,
+                        RData::A(Ipv4Addr::new(198, 51, 100, i)),
+                    ));
+                }
+
+                let bytes = res.to_vec().unwrap();
+                assert!(bytes.len() > 1232);
+                udp.send_to(&bytes, from).await.unwrap();
+            }
+        });
+
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+
+            let req = Message::from_vec(&buf).unwrap();
+            let res = address_response(&req, 2).to_vec().unwrap();
+            stream.write_u16(res.len() as u16).await.unwrap();
+            stream.write_all(&res).await.unwrap();
+        });
+
+        let client = new_udp_client(addr, SourceAddrs::default(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        let options = ResolverOptions {
+            tcp_fallback: true,
+            ..ResolverOptions::default()
+        };
+        let resolver = UpstreamResolver::new(client, addr, options);
+
+        let mut req = request("www.example.com.");
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        req.set_edns(edns);
+        let (_, res) = resolver.resolve_cacheable(req.clone()).await.unwrap();
+
+        assert_eq!(req.id(), res.id());
+        assert!(!res.truncated());
+        assert_eq!(1, res.answers().len());
+        assert_eq!(&RData::A(Ipv4Addr::new(192, 0, 2, 2)), res.answers()[0].rdata());
+    }
+}
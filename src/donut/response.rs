@@ -16,42 +16,145 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use std::str;
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::num::NonZeroUsize;
 
 use serde::Serialize;
-use trust_dns_client::op::DnsResponse;
-use trust_dns_client::proto::serialize::binary::BinEncodable;
-use trust_dns_client::rr::{RData, Record};
+use trust_dns_client::op::{DnsResponse, Message, MessageType, Query, ResponseCode};
+use trust_dns_client::proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use trust_dns_client::proto::serialize::binary::{BinEncodable, BinEncoder};
+use trust_dns_client::rr::rdata::{caa, svcb};
+use trust_dns_client::rr::{Name, RData, Record, RecordType};
 
+use crate::request::ClientSubnet;
 use crate::types::{DonutError, DonutResult, ErrorKind};
 
 #[derive(Clone, Default, Debug, PartialEq, Eq, Hash)]
 pub struct ResponseMetadata {
     min_ttl: Option<u32>,
+    negative_ttl: Option<u32>,
 }
 
 impl ResponseMetadata {
+    /// Lowest TTL of any record in the answer, authority, or additional sections, limited to
+    /// the negative TTL for negative responses. `None` if there are no records.
     pub fn min_ttl(&self) -> Option<u32> {
         self.min_ttl
     }
+
+    /// How long a negative (NXDOMAIN or NODATA) response may be cached for, based on the SOA
+    /// record in the authority section if there is one
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.negative_ttl
+    }
 }
 
 impl From<&DnsResponse> for ResponseMetadata {
     fn from(r: &DnsResponse) -> Self {
-        let min_ttl = r.answers().iter().map(|a| a.ttl()).min();
-        ResponseMetadata { min_ttl }
+        let negative = r.response_code() == ResponseCode::NXDomain
+            || (r.response_code() == ResponseCode::NoError && r.answers().is_empty());
+
+        // The negative TTL is the smaller of the TTL of the SOA record and its MINIMUM field (RFC 2308)
+        let negative_ttl = if negative {
+            r.name_servers().iter().find_map(|rec| match rec.rdata() {
+                RData::SOA(soa) => Some(rec.ttl().min(soa.minimum())),
+                _ => None,
+            })
+        } else {
+            None
+        };
+
+        // Records in the authority and additional sections (delegations, SOA records for negative
+        // responses, glue) go stale at the same time as answers so they count towards the TTL too.
+        let min_ttl = r
+            .answers()
+            .iter()
+            .chain(r.name_servers())
+            .chain(r.additionals())
+            .map(|a| a.ttl())
+            .chain(negative_ttl)
+            .min();
+
+        ResponseMetadata { min_ttl, negative_ttl }
     }
 }
 
+/// Default TTL for records in responses synthesized by Donut instead of an upstream server
+pub const DEFAULT_SYNTHETIC_TTL: u32 = 60;
+
+/// Build a response to the given request message without consulting an upstream server
+pub fn synthesize_response(req: &Message, code: ResponseCode, answers: Vec<Record>) -> DnsResponse {
+    let mut message = Message::new();
+    message
+        .set_id(req.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(req.op_code())
+        .set_recursion_desired(req.recursion_desired())
+        .set_recursion_available(true)
+        .set_checking_disabled(req.checking_disabled())
+        .set_response_code(code)
+        .add_queries(req.queries().to_vec())
+        .add_answers(answers);
+
+    // Only the lower 4 bits of the response code fit in the header, the rest go in an OPT record
+    if code.high() != 0 {
+        message.edns_mut().set_rcode_high(code.high());
+    }
+
+    DnsResponse::from(message)
+}
+
+/// Build A or AAAA records answering each query of the matching type with the given address
+pub fn synthesize_address_answers(queries: &[Query], addr: IpAddr, ttl: u32) -> Vec<Record> {
+    queries
+        .iter()
+        .filter_map(|q| {
+            let rdata = match (q.query_type(), addr) {
+                (RecordType::A, IpAddr::V4(v)) => RData::A(v),
+                (RecordType::AAAA, IpAddr::V6(v)) => RData::AAAA(v),
+                _ => return None,
+            };
+
+            Some(Record::from_rdata(q.name().clone(), ttl, rdata))
+        })
+        .collect()
+}
+
+/// Number of bytes added by an empty padding field: `,"_padding":""`
+const JSON_PADDING_OVERHEAD: usize = 14;
+
 #[derive(Debug, Default, Clone)]
-pub struct ResponseEncoderJson;
+pub struct ResponseEncoderJson {
+    allow_raw: bool,
+    pad_block: Option<NonZeroUsize>,
+    comment: Option<String>,
+}
 
 impl ResponseEncoderJson {
-    pub fn new() -> Self {
-        ResponseEncoderJson
+    pub fn new(allow_raw: bool, pad_block: Option<NonZeroUsize>, comment: Option<String>) -> Self {
+        ResponseEncoderJson {
+            allow_raw,
+            pad_block,
+            comment,
+        }
     }
 
-    pub async fn encode(&self, res: DnsResponse) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
+    /// Encode a response as JSON, optionally including the base64 encoded wire format of
+    /// the response if `raw` is set and this encoder allows it.
+    pub async fn encode(&self, res: DnsResponse, raw: bool) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
+        self.encode_enclosed(res, raw, 0).await
+    }
+
+    /// Encode a response the same way as `encode` to be embedded in a larger JSON document that
+    /// adds `overhead` bytes, padding it so that the size of the whole document is a multiple of
+    /// the block size instead.
+    pub async fn encode_enclosed(
+        &self,
+        res: DnsResponse,
+        raw: bool,
+        overhead: usize,
+    ) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
         tracing::trace!(response = ?res);
 
         let questions: Vec<JsonQuestion> = res
@@ -75,38 +178,77 @@ impl ResponseEncoderJson {
             .collect();
 
         let meta = ResponseMetadata::from(&res);
-        let bytes = serde_json::to_vec(&JsonResponse::new(
+        let mut body = JsonResponse::new(
             u16::from(res.response_code()),
             res.truncated(),
             res.recursion_desired(),
             res.recursion_available(),
-            false,
+            res.authentic_data(),
             res.checking_disabled(),
             questions,
             answers,
-        ))
-        .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to serialize to response", Box::new(e))))?;
+        );
+
+        // Tell clients which network the answers are valid for (the scope the upstream server
+        // returned), the same as the address and prefix Google's JSON API includes.
+        body.client_subnet = res
+            .edns()
+            .and_then(ClientSubnet::from_edns)
+            .map(|s| format!("{}/{}", s.addr(), s.scope_prefix()));
+        body.comment = self.comment.clone();
+
+        if raw && self.allow_raw {
+            // Trust DNS doesn't keep the bytes received from the upstream server around so
+            // this is the response re-encoded into the wire format.
+            let wire = res.to_bytes()?;
+            body.raw = Some(base64::encode_config(&wire, base64::URL_SAFE_NO_PAD));
+        }
+
+        let mut bytes = Self::serialize(&body)?;
+
+        if let Some(block) = self.pad_block {
+            // Pad the response to a multiple of the block size with a field of spaces (which
+            // don't need escaping) so that the size of the response reveals less about it.
+            let unpadded = bytes.len() + JSON_PADDING_OVERHEAD + overhead;
+            let padded = unpadded.div_ceil(block.get()) * block.get();
+            body.padding = Some(" ".repeat(padded - unpadded));
+            bytes = Self::serialize(&body)?;
+        }
 
         tracing::debug!(message = "encoded DNS result to JSON format", num_bytes = bytes.len());
         Ok((meta, bytes))
     }
 }
 
+impl ResponseEncoderJson {
+    fn serialize(body: &JsonResponse) -> DonutResult<Vec<u8>> {
+        serde_json::to_vec(body)
+            .map_err(|e| DonutError::from((ErrorKind::Internal, "unable to serialize to response", Box::new(e))))
+    }
+}
+
 pub fn record_to_data(record: &Record) -> String {
     match record.rdata() {
         RData::A(v) => v.to_string(),
         RData::AAAA(v) => v.to_string(),
         RData::ANAME(v) => v.to_string(),
-        //RData::CAA(v) => ,
+        RData::CAA(v) => format!(
+            "{} {} \"{}\"",
+            // The issuer critical flag is the most significant bit of the flags byte
+            if v.issuer_critical() { 128 } else { 0 },
+            v.tag(),
+            caa_value(v.value()),
+        ),
         RData::CNAME(v) => v.to_utf8(),
+        RData::HTTPS(v) => svcb_data(v),
         RData::MX(v) => format!("{} {}", v.preference(), v.exchange()),
         RData::NAPTR(v) => format!(
             "{} {} \"{}\" \"{}\" \"{}\" {}",
             v.order(),
             v.preference(),
-            str::from_utf8(v.flags()).unwrap_or(""),
-            str::from_utf8(v.services()).unwrap_or(""),
-            str::from_utf8(v.regexp()).unwrap_or(""),
+            escape_character_string(v.flags()),
+            escape_character_string(v.services()),
+            escape_character_string(v.regexp()),
             v.replacement(),
         ),
         RData::NS(v) => v.to_utf8(),
@@ -125,20 +267,187 @@ pub fn record_to_data(record: &Record) -> String {
             v.minimum(),
         ),
         RData::SRV(v) => format!("{} {} {} {}", v.priority(), v.weight(), v.port(), v.target()),
-        //RData::SSHFP(v) => ,
-        //RData::TLSA(v) => ,
-        RData::TXT(v) => format!(
-            "\"{}\"",
-            v.txt_data()
-                .iter()
-                .flat_map(|t| str::from_utf8(t))
-                .collect::<Vec<&str>>()
-                .concat()
+        RData::SSHFP(v) => format!(
+            "{} {} {}",
+            u8::from(v.algorithm()),
+            u8::from(v.fingerprint_type()),
+            hex_lower(v.fingerprint()),
+        ),
+        RData::SVCB(v) => svcb_data(v),
+        RData::TLSA(v) => format!(
+            "{} {} {} {}",
+            u8::from(v.cert_usage()),
+            u8::from(v.selector()),
+            u8::from(v.matching()),
+            hex_upper(v.cert_data()),
         ),
-        _ => panic!("Unexpected result: {:?}", record),
+        // Each character-string is a separate quoted token like `dig` prints them, merging
+        // them would change the value of records that depend on the boundaries (SPF, DKIM).
+        RData::TXT(v) => v
+            .txt_data()
+            .iter()
+            .map(|t| format!("\"{}\"", escape_character_string(t)))
+            .collect::<Vec<String>>()
+            .join(" "),
+        // Anything else is represented generically instead of failing the entire response
+        v => generic_data(v),
     }
 }
 
+/// Format SVCB or HTTPS record data as `<priority> <target> <params...>` (RFC 9460)
+///
+/// Trust DNS has its own formatting for these records but it leaves trailing commas in lists
+/// of values and uses draft names for some keys so parameters are formatted here like `dig` does.
+fn svcb_data(svcb: &svcb::SVCB) -> String {
+    let mut out = format!("{} {}", svcb.svc_priority(), svcb.target_name());
+
+    for (key, value) in svcb.svc_params() {
+        let _ = write!(out, " {}", svcb_key(*key));
+        match value {
+            svcb::SvcParamValue::Mandatory(v) => {
+                let keys: Vec<String> = v.0.iter().map(|k| svcb_key(*k)).collect();
+                let _ = write!(out, "={}", keys.join(","));
+            }
+            svcb::SvcParamValue::Alpn(v) => {
+                let ids: Vec<String> = v.0.iter().map(|a| escape_character_string(a.as_bytes())).collect();
+                let _ = write!(out, "=\"{}\"", ids.join(","));
+            }
+            svcb::SvcParamValue::NoDefaultAlpn => {}
+            svcb::SvcParamValue::Port(v) => {
+                let _ = write!(out, "={}", v);
+            }
+            svcb::SvcParamValue::Ipv4Hint(v) => {
+                let addrs: Vec<String> = v.0.iter().map(|a| a.to_string()).collect();
+                let _ = write!(out, "={}", addrs.join(","));
+            }
+            svcb::SvcParamValue::Ipv6Hint(v) => {
+                let addrs: Vec<String> = v.0.iter().map(|a| a.to_string()).collect();
+                let _ = write!(out, "={}", addrs.join(","));
+            }
+            svcb::SvcParamValue::EchConfig(v) => {
+                let _ = write!(out, "=\"{}\"", base64::encode(&v.0));
+            }
+            svcb::SvcParamValue::Unknown(v) => {
+                // Trust DNS splits unknown values into character-strings when decoding them,
+                // encoding them again gets back the original bytes of the value.
+                let mut bytes = Vec::new();
+                if let Err(e) = v.emit(&mut BinEncoder::new(&mut bytes)) {
+                    tracing::warn!(message = "unable to encode SVCB parameter", key = u16::from(*key), error = %e);
+                    bytes.clear();
+                }
+
+                let _ = write!(out, "=\"{}\"", escape_character_string(&bytes));
+            }
+        }
+    }
+
+    out
+}
+
+/// Presentation name of a SVCB parameter key, using `key<N>` for any without a name
+fn svcb_key(key: svcb::SvcParamKey) -> String {
+    match key {
+        svcb::SvcParamKey::Mandatory => "mandatory".to_owned(),
+        svcb::SvcParamKey::Alpn => "alpn".to_owned(),
+        svcb::SvcParamKey::NoDefaultAlpn => "no-default-alpn".to_owned(),
+        svcb::SvcParamKey::Port => "port".to_owned(),
+        svcb::SvcParamKey::Ipv4Hint => "ipv4hint".to_owned(),
+        svcb::SvcParamKey::EchConfig => "ech".to_owned(),
+        svcb::SvcParamKey::Ipv6Hint => "ipv6hint".to_owned(),
+        k => format!("key{}", u16::from(k)),
+    }
+}
+
+/// Format the value of a CAA record escaped to go inside quotes, e.g. `letsencrypt.org; accounturi=...`
+fn caa_value(value: &caa::Value) -> String {
+    match value {
+        caa::Value::Issuer(name, params) => {
+            // An issuer without a name (just ";") means no CA is allowed to issue certificates
+            let mut out = match name {
+                Some(n) => n.to_utf8().trim_end_matches('.').to_owned(),
+                None => ";".to_owned(),
+            };
+
+            for (i, p) in params.iter().enumerate() {
+                if i > 0 || name.is_some() {
+                    out.push(';');
+                }
+                let _ = write!(out, " {}", p);
+            }
+
+            escape_character_string(out.as_bytes())
+        }
+        caa::Value::Url(url) => escape_character_string(url.as_str().as_bytes()),
+        caa::Value::Unknown(bytes) => escape_character_string(bytes),
+    }
+}
+
+/// Format record data using the generic format for unknown types (RFC 3597): `\# <length> <hex>`
+fn generic_data(rdata: &RData) -> String {
+    let mut bytes = Vec::new();
+    if let Err(e) = rdata.emit(&mut BinEncoder::new(&mut bytes)) {
+        tracing::warn!(message = "unable to encode record data", record_type = %rdata.to_record_type(), error = %e);
+        bytes.clear();
+    }
+
+    if bytes.is_empty() {
+        "\\# 0".to_owned()
+    } else {
+        format!("\\# {} {}", bytes.len(), hex_upper(&bytes))
+    }
+}
+
+/// Format bytes as uppercase hex without any separators, the way `dig` does for binary data
+fn hex_upper(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02X}", b);
+    }
+
+    out
+}
+
+/// Format bytes as lowercase hex without any separators, the way `dig` does for SSHFP fingerprints
+fn hex_lower(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+
+    out
+}
+
+/// Escape a DNS character-string so that it can be safely displayed inside double quotes.
+///
+/// Quotes and backslashes are escaped with a backslash while control characters and bytes
+/// that aren't valid UTF-8 are escaped as `\DDD` decimal values like `dig` does. This means
+/// binary data is represented without loss instead of being dropped or causing an error.
+fn escape_character_string(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '"' || c == '\\' {
+                out.push('\\');
+                out.push(c);
+            } else if c.is_control() {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(out, "\\{:03}", b);
+                }
+            } else {
+                out.push(c);
+            }
+        }
+
+        for b in chunk.invalid() {
+            let _ = write!(out, "\\{:03}", b);
+        }
+    }
+
+    out
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Serialize)]
 struct JsonQuestion {
     #[serde(rename = "name")]
@@ -215,6 +524,19 @@ pub struct JsonResponse {
 
     #[serde(rename = "Answer")]
     answers: Vec<JsonAnswer>,
+
+    #[serde(rename = "edns_client_subnet", skip_serializing_if = "Option::is_none")]
+    client_subnet: Option<String>,
+
+    #[serde(rename = "Comment", skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+
+    #[serde(rename = "raw", skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+
+    // NOTE: Padding must be the last field so that it's always preceded by a comma
+    #[serde(rename = "_padding", skip_serializing_if = "Option::is_none")]
+    padding: Option<String>,
 }
 
 impl JsonResponse {
@@ -238,25 +560,338 @@ impl JsonResponse {
             checking_disabled,
             questions,
             answers,
+            client_subnet: None,
+            comment: None,
+            raw: None,
+            padding: None,
         }
     }
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct ResponseEncoderWire;
+/// Default block size to pad wire format responses to a multiple of, in bytes
+pub const DEFAULT_PAD_BLOCK: usize = 128;
+
+/// Number of bytes added by an empty EDNS padding option (the option code and length)
+const EDNS_PADDING_OVERHEAD: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct ResponseEncoderWire {
+    pad_block: NonZeroUsize,
+    pad_all: bool,
+}
 
 impl ResponseEncoderWire {
     pub fn new() -> Self {
-        ResponseEncoderWire
+        ResponseEncoderWire {
+            pad_block: NonZeroUsize::new(DEFAULT_PAD_BLOCK).unwrap(),
+            pad_all: false,
+        }
     }
 
-    pub async fn encode(&self, res: DnsResponse) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
+    /// Pad responses to a multiple of this many bytes instead of `DEFAULT_PAD_BLOCK`
+    pub fn with_pad_block(mut self, block: NonZeroUsize) -> Self {
+        self.pad_block = block;
+        self
+    }
+
+    /// Pad all responses that use EDNS, not just responses to queries that include padding
+    pub fn with_pad_all(mut self, enabled: bool) -> Self {
+        self.pad_all = enabled;
+        self
+    }
+
+    /// Encode a response in the wire format, padding it with an EDNS padding option (RFC 8467)
+    /// if `padding` is set (because the query included padding) or padding is always enabled.
+    ///
+    /// Responses without an OPT record are never padded since the query didn't use EDNS.
+    pub async fn encode(&self, mut res: DnsResponse, padding: bool) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
         tracing::trace!(response = ?res);
 
         let meta = ResponseMetadata::from(&res);
+        if (padding || self.pad_all) && res.edns().is_some() {
+            self.pad(&mut res)?;
+        }
+
         let bytes = res.to_bytes()?;
 
         tracing::debug!(message = "encoded DNS result to wire format", num_bytes = bytes.len());
         Ok((meta, bytes))
     }
+
+    /// Replace any padding from the upstream server with enough to make the encoded response
+    /// a multiple of the block size
+    fn pad(&self, res: &mut DnsResponse) -> DonutResult<()> {
+        res.edns_mut().options_mut().remove(EdnsCode::Padding);
+
+        let unpadded = res.to_bytes()?.len() + EDNS_PADDING_OVERHEAD;
+        let padded = unpadded.div_ceil(self.pad_block.get()) * self.pad_block.get();
+        res.edns_mut().options_mut().insert(EdnsOption::Unknown(
+            u16::from(EdnsCode::Padding),
+            vec![0; padded - unpadded],
+        ));
+
+        Ok(())
+    }
+}
+
+impl Default for ResponseEncoderWire {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Format a message in a dig-like presentation format, one record per line
+pub fn format_message(mes: &Message) -> String {
+    let mut buf = String::new();
+    format_question(&mut buf, mes);
+    let _ = writeln!(buf);
+
+    if !mes.answers().is_empty() {
+        format_answer(&mut buf, mes);
+    } else {
+        format_authority(&mut buf, mes)
+    }
+
+    buf
+}
+
+fn format_question(buf: &mut String, mes: &Message) {
+    let _ = writeln!(buf, ";; QUESTION SECTION:");
+    for q in mes.queries() {
+        let _ = writeln!(
+            buf,
+            "; {}\t\t\t{}\t{}",
+            q.name().to_utf8(),
+            q.query_class(),
+            q.query_type()
+        );
+    }
+}
+
+fn format_authority(buf: &mut String, mes: &Message) {
+    let _ = writeln!(buf, ";; AUTHORITY SECTION:");
+    format_records(buf, mes.name_servers());
+}
+
+fn format_answer(buf: &mut String, mes: &Message) {
+    let _ = writeln!(buf, ";; ANSWER SECTION:");
+    format_records(buf, mes.answers());
+}
+
+fn format_records(buf: &mut String, records: &[Record]) {
+    for r in records {
+        let _ = writeln!(
+            buf,
+            "{}\t\t{}\t{}\t{}\t{}",
+            r.name().to_utf8(),
+            r.ttl(),
+            r.dns_class(),
+            r.record_type(),
+            record_to_data(r),
+        );
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ResponseEncoderText;
+
+impl ResponseEncoderText {
+    pub fn new() -> Self {
+        ResponseEncoderText
+    }
+
+    pub async fn encode(&self, res: DnsResponse) -> DonutResult<(ResponseMetadata, Vec<u8>)> {
+        tracing::trace!(response = ?res);
+
+        let meta = ResponseMetadata::from(&res);
+        let bytes = format_message(&res).into_bytes();
+
+        tracing::debug!(message = "encoded DNS result to text format", num_bytes = bytes.len());
+        Ok((meta, bytes))
+    }
+}
+
+/// Encode a synthetic response with each encoder to catch problems before serving any requests
+pub async fn self_test(
+    json_encoder: &ResponseEncoderJson,
+    wire_encoder: &ResponseEncoderWire,
+    text_encoder: &ResponseEncoderText,
+) -> DonutResult<()> {
+    let name = Name::from_ascii("example.com.")?;
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Response)
+        .add_query(Query::query(name.clone(), RecordType::A))
+        .add_answer(Record::from_rdata(name, 60, RData::A(Ipv4Addr::LOCALHOST)));
+    let response = DnsResponse::from(message);
+
+    let (_, json) = json_encoder.encode(response.clone(), true).await?;
+    let (_, wire) = wire_encoder.encode(response.clone(), false).await?;
+    let (_, text) = text_encoder.encode(response).await?;
+
+    tracing::info!(
+        message = "encoder self-test passed",
+        json_bytes = json.len(),
+        wire_bytes = wire.len(),
+        text_bytes = text.len(),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        record_to_data, self_test, synthesize_response, ResponseEncoderJson, ResponseEncoderText, ResponseEncoderWire,
+        DEFAULT_PAD_BLOCK,
+    };
+    use std::net::Ipv4Addr;
+    use std::num::NonZeroUsize;
+    use std::str::FromStr;
+    use trust_dns_client::op::{Message, Query, ResponseCode};
+    use trust_dns_client::rr::rdata::sshfp::{Algorithm, FingerprintType};
+    use trust_dns_client::rr::rdata::svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue};
+    use trust_dns_client::rr::rdata::tlsa::{CertUsage, Matching, Selector};
+    use trust_dns_client::rr::rdata::{CAA, NULL, SSHFP, SVCB, TLSA, TXT};
+    use trust_dns_client::rr::{Name, RData, Record, RecordType};
+
+    fn request() -> Message {
+        let mut req = Message::new();
+        req.add_query(Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A));
+        req
+    }
+
+    #[test]
+    fn test_synthesize_response_extended_code() {
+        let res = synthesize_response(&request(), ResponseCode::BADCOOKIE, Vec::new());
+
+        assert_eq!(ResponseCode::BADCOOKIE, res.response_code());
+        assert_eq!(23, u16::from(res.response_code()));
+        assert_eq!(Some(1), res.edns().map(|e| e.rcode_high()));
+    }
+
+    #[test]
+    fn test_synthesize_response_no_extended_code() {
+        let res = synthesize_response(&request(), ResponseCode::NXDomain, Vec::new());
+
+        assert_eq!(ResponseCode::NXDomain, res.response_code());
+        assert!(res.edns().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encode_json_generic_data() {
+        let name = Name::from_str("www.example.com.").unwrap();
+        let null = Record::from_rdata(name.clone(), 60, RData::NULL(NULL::with(vec![0x01, 0x02, 0xFF])));
+        let unknown = Record::from_rdata(
+            name,
+            60,
+            RData::Unknown {
+                code: 65280,
+                rdata: NULL::with(vec![0xAB, 0xCD]),
+            },
+        );
+        let res = synthesize_response(&request(), ResponseCode::NoError, vec![null, unknown]);
+
+        let (_, bytes) = ResponseEncoderJson::default().encode(res, false).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+
+        assert_eq!(10, body["Answer"][0]["type"]);
+        assert_eq!("\\# 3 0102FF", body["Answer"][0]["data"]);
+        assert_eq!(65280, body["Answer"][1]["type"]);
+        assert_eq!("\\# 2 ABCD", body["Answer"][1]["data"]);
+    }
+
+    #[tokio::test]
+    async fn test_encode_json_authentic_data() {
+        let mut res = synthesize_response(&request(), ResponseCode::NoError, Vec::new());
+        let (_, bytes) = ResponseEncoderJson::default().encode(res.clone(), false).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(false, body["AD"]);
+
+        res.set_authentic_data(true);
+        let (_, bytes) = ResponseEncoderJson::default().encode(res, false).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(true, body["AD"]);
+    }
+
+    #[tokio::test]
+    async fn test_self_test() {
+        let result = self_test(
+            &ResponseEncoderJson::new(true, NonZeroUsize::new(DEFAULT_PAD_BLOCK), Some("test".to_owned())),
+            &ResponseEncoderWire::new().with_pad_all(true),
+            &ResponseEncoderText::new(),
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    fn data(rdata: RData) -> String {
+        record_to_data(&Record::from_rdata(
+            Name::from_str("www.example.com.").unwrap(),
+            60,
+            rdata,
+        ))
+    }
+
+    #[test]
+    fn test_record_to_data_caa() {
+        let caa = CAA::new_issue(false, Some(Name::from_str("ca.example").unwrap()), Vec::new());
+        assert_eq!("0 issue \"ca.example\"", data(RData::CAA(caa)));
+    }
+
+    #[test]
+    fn test_record_to_data_tlsa() {
+        let tlsa = TLSA::new(
+            CertUsage::DomainIssued,
+            Selector::Spki,
+            Matching::Sha256,
+            vec![0xAB, 0xCD, 0x01],
+        );
+        assert_eq!("3 1 1 ABCD01", data(RData::TLSA(tlsa)));
+    }
+
+    #[test]
+    fn test_record_to_data_sshfp_ed25519() {
+        let sshfp = SSHFP::new(
+            Algorithm::Ed25519,
+            FingerprintType::SHA256,
+            vec![0xDE, 0xAD, 0xBE, 0xEF],
+        );
+        assert_eq!("4 2 deadbeef", data(RData::SSHFP(sshfp)));
+    }
+
+    #[test]
+    fn test_record_to_data_https() {
+        let https = SVCB::new(
+            1,
+            Name::root(),
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h2".to_owned(), "h3".to_owned()])),
+                ),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)])),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            "1 . alpn=\"h2,h3\" ipv4hint=192.0.2.1,192.0.2.2",
+            data(RData::HTTPS(https))
+        );
+    }
+
+    #[test]
+    fn test_record_to_data_txt_multiple_strings() {
+        let txt = TXT::new(vec!["v=spf1 include:example.com".to_owned(), "-all".to_owned()]);
+        assert_eq!("\"v=spf1 include:example.com\" \"-all\"", data(RData::TXT(txt)));
+    }
+
+    #[test]
+    fn test_record_to_data_txt_escaped() {
+        let txt = TXT::from_bytes(vec![&b"say \"hi\" \\o/"[..], &[0xFF, b'x', 0x07]]);
+        assert_eq!("\"say \\\"hi\\\" \\\\o/\" \"\\255x\\007\"", data(RData::TXT(txt)));
+    }
 }
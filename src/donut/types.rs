@@ -33,9 +33,16 @@ enum ErrorRepr {
 pub enum ErrorKind {
     Internal,
     Timeout,
+    /// The upstream server hasn't been connected to yet or an error was injected on purpose
+    /// by `http::Chaos`
+    Unavailable,
     InputInvalid,
+    /// The DNS message is too large, for both GET and POST requests
     InputBodyTooLong,
+    /// The encoded DNS message of a GET request is too long, before decoding it
     InputUriTooLong,
+    /// The client already has as many queries being handled as it's allowed
+    TooManyRequests,
 }
 
 #[derive(Debug)]